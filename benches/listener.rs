@@ -0,0 +1,101 @@
+use criterion::{ criterion_group, criterion_main, Criterion };
+use firerust::listener::SnapshotCache;
+use firerust::{ DataSnapshot, RealtimeReference };
+use serde_json::{ json, Map, Value };
+use std::hint::black_box;
+
+
+/// Builds a tree of roughly 10 MB, 40 000 users of about 250 bytes each
+fn tree() -> Value {
+    let users = (0..40_000u32)
+        .map(|i| (format!("user{:05}", i), json!({
+            "name": format!("User number {}", i),
+            "email": format!("user{}@example.com", i),
+            "bio": "x".repeat(150),
+            "age": i % 90,
+            "active": i.is_multiple_of(2),
+        })))
+        .collect::<Map<String, Value>>();
+
+    json!({ "users": users })
+}
+
+fn patch(i: usize) -> Value {
+    json!({ "age": i % 90, "active": i.is_multiple_of(3) })
+}
+
+
+fn streamed_patch(c: &mut Criterion) {
+    let value = tree();
+    assert!(value.to_string().len() > 10_000_000);
+
+    let mut group = c.benchmark_group("patch on a 10 MB tree");
+    group.sample_size(20);
+
+    // What every event cost before: update the tree, then clone it to hand it to the callback
+    group.bench_function("clone per event", |b| {
+        let mut snapshot = value.clone();
+        let mut i = 0;
+
+        b.iter(|| {
+            i += 1;
+            let pointer = snapshot.pointer_mut("/users/user00042").unwrap();
+            RealtimeReference::merge_value(pointer, patch(i)).unwrap();
+            black_box(snapshot.clone());
+        })
+    });
+
+    group.bench_function("shared cache", |b| {
+        let mut cache = SnapshotCache::new(value.clone());
+        let mut i = 0;
+
+        b.iter(|| {
+            i += 1;
+            let changes = cache.apply_patch("/users/user00042", patch(i));
+            black_box((changes, cache.shared()));
+        })
+    });
+
+    group.finish();
+}
+
+/// A slow callback still holds the snapshot of the previous event when the next one is applied
+fn slow_callback(c: &mut Criterion) {
+    let value = tree();
+
+    let mut group = c.benchmark_group("patch on a 10 MB tree while a callback is pending");
+    group.sample_size(20);
+
+    // The snapshot of the listened user keeps the whole tree alive, the next patch copies it
+    group.bench_function("snapshot sharing the tree", |b| {
+        let mut cache = SnapshotCache::new(value.clone());
+        let mut pending = None;
+        let mut i = 0;
+
+        b.iter(|| {
+            i += 1;
+            let changes = cache.apply_patch("/users/user00042", patch(i));
+            pending = Some(DataSnapshot::new(None, cache.shared()).child("users/user00042"));
+            black_box((changes, &pending));
+        })
+    });
+
+    group.bench_function("snapshot of the listened location", |b| {
+        let mut cache = SnapshotCache::new(value.clone());
+        let mut pending = None;
+        let mut i = 0;
+
+        b.iter(|| {
+            i += 1;
+            let changes = cache.apply_patch("/users/user00042", patch(i));
+            pending = Some(cache.snapshot(None, "users/user00042"));
+            black_box((changes, &pending));
+        })
+    });
+
+    group.finish();
+}
+
+
+criterion_group!(benches, streamed_patch, slow_callback);
+criterion_main!(benches);
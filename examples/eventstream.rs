@@ -9,15 +9,17 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let client = FirebaseClient::new(std::env::var("FIREBASE_URL")?)?;
     let reference = client.reference("/data");
     
-    reference.on_snapshot(| data: Value | {
+    let _values = reference.on_snapshot(| data: Value | {
         println!("Value: {:?}", data);
         Ok(())
     }, |err| eprintln!("Error: {}", err)).await?;
 
-    reference.on_snapshot(| data: Data | {
+    let data = reference.on_snapshot(| data: Data | {
         println!("Data: {:?}", data);
         Ok(())
-    }, |err| eprintln!("Error: {}", err)).await?.await.unwrap();
+    }, |err| eprintln!("Error: {}", err)).await?;
+
+    data.closed().await;
 
     Ok(())
 }
@@ -0,0 +1,657 @@
+//! # Example
+//!
+//! ```rust,no_run
+//! use firerust::{FirebaseClient, FirebaseError};
+//! use firerust::backup::{ BackupOptions, Backups };
+//! use std::time::Duration;
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), FirebaseError> {
+//!     let client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+//!     let backups = Backups::new("/var/backups/firebase");
+//!
+//!     let options = BackupOptions::new().path("/users").path("/config").keep_last(7);
+//!     let manifest = backups.create(&client, &options).await?;
+//!     backups.prune(&options)?;
+//!
+//!     // Restore a single user to another database
+//!     let staging = FirebaseClient::new("https://staging.firebaseio.com/")?;
+//!     backups.restore_subtree(&manifest, "/users/alice", &staging.reference("/")).await?;
+//!
+//!     Ok(())
+//! }
+//! ```
+
+
+use crate::import::{ Importer, ImportFormat, ImportOptions };
+use crate::{ FirebaseClient, FirebaseError, RealtimeReference };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use tokio::io::{ AsyncWrite, AsyncWriteExt };
+use crate::registry::{ normalize, relative };
+use flate2::{ Compression, write::GzEncoder };
+use serde::{ Deserialize, Serialize };
+use futures_util::{ Stream, StreamExt };
+use std::io::{ Read as _, Write as _ };
+use crate::connector::Method;
+use crate::persist::hex;
+use std::collections::BTreeSet;
+use std::path::{ Path, PathBuf };
+use flate2::read::GzDecoder;
+use sha2::{ Digest, Sha256 };
+use serde_json::Value;
+
+
+const MANIFEST: &str = "manifest.json";
+const CHUNK_SIZE: usize = 64 * 1024;
+
+
+/// What to back up and how many backups to keep
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BackupOptions {
+    paths: Vec<String>,
+    keep_last: Option<usize>,
+    max_age: Option<Duration>,
+}
+
+impl BackupOptions {
+
+    /// Create options backing up nothing and keeping every backup
+    pub fn new() -> BackupOptions {
+        BackupOptions::default()
+    }
+
+    /// Back up the given location, each of its children is downloaded separately
+    pub fn path(mut self, path: impl ToString) -> BackupOptions {
+        self.paths.push(normalize(&path.to_string()));
+        self
+    }
+
+    /// Keep only the given number of most recent backups
+    pub fn keep_last(mut self, backups: usize) -> BackupOptions {
+        self.keep_last = Some(backups.max(1));
+        self
+    }
+
+    /// Remove the backups older than the given age, the most recent backup is always kept
+    pub fn max_age(mut self, age: Duration) -> BackupOptions {
+        self.max_age = Some(age);
+        self
+    }
+}
+
+
+/// A file of a backup, holding the value of one location
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BackupEntry {
+    path: String,
+    file: String,
+    bytes: u64,
+    compressed: u64,
+    sha256: String,
+}
+
+impl BackupEntry {
+
+    /// Get the location of the value
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Get the size of the value as JSON
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Get the size of the compressed file
+    pub fn compressed(&self) -> u64 {
+        self.compressed
+    }
+
+    /// Get the SHA-256 of the compressed file, in hexadecimal
+    pub fn sha256(&self) -> &str {
+        &self.sha256
+    }
+}
+
+
+/// Description of a backup, saved next to its files
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    created: u64,
+    paths: Vec<String>,
+    entries: Vec<BackupEntry>,
+}
+
+impl Manifest {
+
+    /// Get the time the backup was started, in milliseconds since the Unix epoch
+    pub fn created(&self) -> u64 {
+        self.created
+    }
+
+    /// Get the locations backed up
+    pub fn paths(&self) -> &[String] {
+        &self.paths
+    }
+
+    /// Get the files of the backup
+    pub fn entries(&self) -> &[BackupEntry] {
+        &self.entries
+    }
+
+    /// Get the size of the backed up values as JSON
+    pub fn bytes(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.bytes).sum()
+    }
+}
+
+
+/// Timestamped, compressed backups kept in a directory, one subdirectory per backup
+#[derive(Clone, Debug)]
+pub struct Backups {
+    directory: PathBuf,
+}
+
+impl Backups {
+
+    /// Keep the backups in the given directory
+    pub fn new(directory: impl AsRef<Path>) -> Backups {
+        Backups {
+            directory: directory.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Back up the locations of the options
+    ///
+    /// The children of each location are listed with a shallow read and downloaded one by one,
+    /// so no value larger than a child is held in memory. A backup only appears once all its
+    /// files are written, the files of a failed or cancelled backup are removed.
+    ///
+    /// # Errors
+    /// Returns an error if a read fails or the files can not be written
+    pub async fn create(&self, client: &FirebaseClient, options: &BackupOptions) -> Result<Manifest, FirebaseError> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let (created, directory) = self.start(now).await?;
+        let mut temporary = Temporary(Some(directory.clone()));
+
+        let mut entries = Vec::new();
+        for path in &options.paths {
+            for location in crawl(client, path).await? {
+                let file = format!("{}.json.gz", entries.len());
+                let body = client.reference(&location).get_stream().await?;
+                let mut output = tokio::fs::File::create(directory.join(&file)).await.map_err(io_error)?;
+
+                entries.push(write_entry(&location, file, body, &mut output).await?);
+                output.sync_all().await.map_err(io_error)?;
+            }
+        }
+
+        let manifest = Manifest {
+            created,
+            paths: options.paths.clone(),
+            entries,
+        };
+
+        tokio::fs::write(directory.join(MANIFEST), serde_json::to_vec_pretty(&manifest)?).await.map_err(io_error)?;
+        tokio::fs::rename(&directory, self.directory.join(created.to_string())).await.map_err(io_error)?;
+        temporary.0 = None;
+
+        Ok(manifest)
+    }
+
+    /// Claim the directory of a backup started at the given time, taking the next millisecond while
+    /// another backup holds the name, returning the time the backup is named after
+    async fn start(&self, mut created: u64) -> Result<(u64, PathBuf), FirebaseError> {
+        tokio::fs::create_dir_all(&self.directory).await.map_err(io_error)?;
+
+        loop {
+            let directory = self.directory.join(format!("{}.tmp", created));
+
+            match tokio::fs::create_dir(&directory).await {
+                Ok(()) => match tokio::fs::try_exists(self.directory.join(created.to_string())).await.map_err(io_error)? {
+                    false => return Ok((created, directory)),
+                    true => tokio::fs::remove_dir(&directory).await.map_err(io_error)?,
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {},
+                Err(e) => return Err(io_error(e)),
+            }
+
+            created += 1;
+        }
+    }
+
+    /// Run [`Backups::create`] then [`Backups::prune`] at the given interval until the client is shut down
+    ///
+    /// Errors of a backup or of its pruning are reported to `on_error`, the next run happens anyway
+    pub fn schedule(&self, client: &FirebaseClient, options: BackupOptions, every: Duration, on_error: impl Fn(FirebaseError) + Send + 'static) -> tokio::task::JoinHandle<()> {
+        let (backups, client) = (self.clone(), client.clone());
+        let mut shutdown = client.shutdown.subscribe();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(every);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+            loop {
+                tokio::select! {
+                    _ = shutdown.wait_for(|shutdown| *shutdown) => return,
+                    _ = interval.tick() => {},
+                }
+
+                let result = tokio::select! {
+                    _ = shutdown.wait_for(|shutdown| *shutdown) => return,
+                    result = backups.create(&client, &options) => result,
+                };
+
+                if let Err(e) = result {
+                    on_error(e);
+                }
+
+                let (pruning, retention) = (backups.clone(), options.clone());
+                let pruned = tokio::task::spawn_blocking(move || pruning.prune(&retention)).await
+                    .map_err(|e| FirebaseError::new(e.to_string()));
+
+                if let Err(e) = pruned.and_then(|pruned| pruned) {
+                    on_error(e);
+                }
+            }
+        })
+    }
+
+    /// Get the completed backups, the most recent first
+    ///
+    /// A backup whose manifest can not be read is left out, and is never pruned.
+    ///
+    /// # Errors
+    /// Returns an error if the directory can not be read
+    pub fn list(&self) -> Result<Vec<Manifest>, FirebaseError> {
+        let directory = match std::fs::read_dir(&self.directory) {
+            Ok(directory) => directory,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(io_error(e)),
+        };
+
+        let mut manifests = Vec::new();
+        for backup in directory {
+            let manifest = backup.map_err(io_error)?.path().join(MANIFEST);
+
+            // Backups still being written have no manifest
+            let manifest = std::fs::read(manifest).ok().and_then(|bytes| serde_json::from_slice::<Manifest>(&bytes).ok());
+            if let Some(manifest) = manifest {
+                manifests.push(manifest);
+            }
+        }
+
+        manifests.sort_by_key(|manifest| std::cmp::Reverse(manifest.created));
+        Ok(manifests)
+    }
+
+    /// Remove the backups the retention of the options does not keep, returning them
+    ///
+    /// The files of backups started before the most recent one and never completed are removed too.
+    ///
+    /// # Errors
+    /// Returns an error if a backup can not be removed
+    pub fn prune(&self, options: &BackupOptions) -> Result<Vec<Manifest>, FirebaseError> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let manifests = self.list()?;
+        let mut removed = Vec::new();
+
+        // Backups run one after the other, an older backup still being written has failed
+        if let Some(latest) = manifests.first() {
+            for backup in std::fs::read_dir(&self.directory).map_err(io_error)? {
+                let backup = backup.map_err(io_error)?.path();
+                let started = backup.file_name().and_then(|name| name.to_str()?.strip_suffix(".tmp")?.parse::<u64>().ok());
+
+                if started.is_some_and(|started| started < latest.created) {
+                    std::fs::remove_dir_all(backup).map_err(io_error)?;
+                }
+            }
+        }
+
+        for (index, manifest) in manifests.into_iter().enumerate() {
+            let age = Duration::from_millis(now.saturating_sub(manifest.created));
+            let expired = options.max_age.is_some_and(|max_age| age > max_age);
+            let extra = options.keep_last.is_some_and(|keep_last| index >= keep_last);
+
+            if index > 0 && (expired || extra) {
+                std::fs::remove_dir_all(self.path(&manifest)).map_err(io_error)?;
+                removed.push(manifest);
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Write every value of the backup below the given reference, which can belong to another client
+    ///
+    /// Each backed up location is deleted below the reference before its values are written, so
+    /// the restored locations hold exactly what the backup holds.
+    ///
+    /// # Errors
+    /// Returns an error if a file is missing or corrupted, or if the writes fail
+    pub async fn restore(&self, manifest: &Manifest, target: &RealtimeReference<'_>) -> Result<(), FirebaseError> {
+        self.restore_subtree(manifest, "/", target).await
+    }
+
+    /// Write the values of the backup at or below `path` below the given reference
+    ///
+    /// The restored location is replaced: the backed up locations at or below `path`, or `path`
+    /// itself when it is inside one, are deleted below the reference before the values are
+    /// written. Nothing is deleted if the backup does not hold `path`.
+    ///
+    /// # Errors
+    /// Returns an error if the backup does not hold the location, if a file is missing or
+    /// corrupted, or if the writes fail
+    pub async fn restore_subtree(&self, manifest: &Manifest, path: &str, target: &RealtimeReference<'_>) -> Result<(), FirebaseError> {
+        let path = normalize(path);
+        let missing = || FirebaseError::new(format!("Backup {} does not hold {}", manifest.created, path));
+
+        // The location is inside the value of an entry, only that part is written
+        let inside = manifest.entries.iter()
+            .find_map(|entry| relative(&entry.path, &path).filter(|below| !below.is_empty()).map(|below| (entry, below)));
+
+        if let Some((entry, below)) = inside {
+            let file = self.path(manifest).join(&entry.file);
+            verify(&file, &entry.sha256).await?;
+
+            let value: Value = tokio::task::spawn_blocking(move || -> Result<Value, FirebaseError> {
+                let file = std::fs::File::open(file).map_err(io_error)?;
+                Ok(serde_json::from_reader(GzDecoder::new(std::io::BufReader::new(file)))?)
+            }).await.map_err(|e| FirebaseError::new(e.to_string()))??;
+
+            // Backed up values hold no null, a null value is a location the backup does not have
+            let key = entry.path.rsplit('/').next().unwrap_or_default();
+            return match crate::registry::descend(&value[key], below) {
+                Value::Null => Err(missing()),
+                value => target.child(&path).set(value).await,
+            };
+        }
+
+        let entries = manifest.entries.iter().filter(|entry| relative(&path, &entry.path).is_some()).collect::<Vec<_>>();
+
+        // A backed up location holding nothing is restored as empty, a location below it is unknown
+        if entries.is_empty() && !manifest.paths.iter().any(|root| relative(&path, root).is_some()) {
+            return Err(missing());
+        }
+
+        // Every file is checked before anything is deleted
+        for entry in &entries {
+            verify(&self.path(manifest).join(&entry.file), &entry.sha256).await?;
+        }
+
+        for location in replaced(&manifest.paths, &path) {
+            target.child(location).delete().await?;
+        }
+
+        for entry in entries {
+            let parent = entry.path.rsplit_once('/').map_or("", |(parent, _)| parent);
+            let importer = Importer::new(target.child(parent), ImportOptions::new());
+            import_entry(&importer, self.path(manifest).join(&entry.file)).await?;
+        }
+
+        Ok(())
+    }
+
+    fn path(&self, manifest: &Manifest) -> PathBuf {
+        self.directory.join(manifest.created.to_string())
+    }
+}
+
+
+/// Directory of a backup being written, removed with its files unless the backup completes
+struct Temporary(Option<PathBuf>);
+
+impl Drop for Temporary {
+    fn drop(&mut self) {
+        if let Some(directory) = self.0.take() {
+            let _ = std::fs::remove_dir_all(directory);
+        }
+    }
+}
+
+
+/// Get the locations deleted before restoring `path`, the backed up locations at or below it,
+/// or `path` itself when it is below one of them
+fn replaced<'p>(roots: &'p [String], path: &'p str) -> BTreeSet<&'p str> {
+    roots.iter()
+        .filter_map(|root| match relative(path, root) {
+            Some(_) => Some(root.as_str()),
+            None => relative(root, path).map(|_| path),
+        })
+        .collect()
+}
+
+/// List the locations to download for a backed up path, its children or the path itself for a leaf
+async fn crawl(client: &FirebaseClient, path: &str) -> Result<Vec<String>, FirebaseError> {
+    let response = client.connector.request(Method::Get, path, Some("?shallow=true"), None, client.api_key.as_deref()).await?;
+
+    if response.status().code() != 200 {
+        return Err(FirebaseError::new(format!("{} {}", response.status().code(), response.status().message())));
+    }
+
+    match serde_json::from_str(response.body())? {
+        Value::Object(children) => Ok(children.keys().map(|key| normalize(&format!("{}/{}", path, key))).collect()),
+        Value::Null => Ok(Vec::new()),
+        _ if path.is_empty() => Err(FirebaseError::new("Backup: the root of the database is not an object")),
+        _ => Ok(vec![path.to_string()]),
+    }
+}
+
+/// Compress a downloaded value into a file, as an object holding the value under its key
+async fn write_entry<S, W>(path: &str, file: String, mut body: S, output: &mut W) -> Result<BackupEntry, FirebaseError> where S: Stream<Item = Result<bytes::Bytes, FirebaseError>> + Unpin, W: AsyncWrite + Unpin {
+    let key = path.rsplit('/').next().unwrap_or_default();
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let mut hasher = Sha256::new();
+    let (mut bytes, mut compressed) = (0, 0);
+
+    encoder.write_all(format!("{{{}:", serde_json::to_string(key)?).as_bytes()).map_err(io_error)?;
+
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        bytes += chunk.len() as u64;
+
+        let ready;
+        (encoder, ready) = compress(encoder, chunk).await?;
+        hasher.update(&ready);
+        compressed += ready.len() as u64;
+        output.write_all(&ready).await.map_err(io_error)?;
+    }
+
+    let rest = tokio::task::spawn_blocking(move || encoder.write_all(b"}").and_then(|_| encoder.finish()).map_err(io_error)).await
+        .map_err(|e| FirebaseError::new(e.to_string()))??;
+    hasher.update(&rest);
+    compressed += rest.len() as u64;
+    output.write_all(&rest).await.map_err(io_error)?;
+    output.flush().await.map_err(io_error)?;
+
+    Ok(BackupEntry {
+        path: path.to_string(),
+        file,
+        bytes,
+        compressed,
+        sha256: hex(&hasher.finalize()),
+    })
+}
+
+/// Compress a chunk on a blocking thread, returning the encoder and the compressed bytes it has ready
+async fn compress(mut encoder: GzEncoder<Vec<u8>>, chunk: bytes::Bytes) -> Result<(GzEncoder<Vec<u8>>, Vec<u8>), FirebaseError> {
+    tokio::task::spawn_blocking(move || {
+        encoder.write_all(&chunk).map_err(io_error)?;
+        let ready = std::mem::take(encoder.get_mut());
+        Ok((encoder, ready))
+    }).await.map_err(|e| FirebaseError::new(e.to_string()))?
+}
+
+/// Check the file of an entry against the checksum of the manifest
+async fn verify(file: &Path, sha256: &str) -> Result<(), FirebaseError> {
+    let file = file.to_path_buf();
+
+    let digest = tokio::task::spawn_blocking(move || -> Result<String, FirebaseError> {
+        let mut input = std::fs::File::open(&file).map_err(io_error)?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; CHUNK_SIZE];
+
+        loop {
+            match input.read(&mut buffer).map_err(io_error)? {
+                0 => return Ok(hex(&hasher.finalize())),
+                read => hasher.update(&buffer[..read]),
+            }
+        }
+    }).await.map_err(|e| FirebaseError::new(e.to_string()))??;
+
+    match digest == sha256 {
+        true => Ok(()),
+        false => Err(FirebaseError::new("Backup: a file does not match its checksum")),
+    }
+}
+
+/// Decompress the file of an entry on a blocking thread and import it as it is decompressed
+async fn import_entry(importer: &Importer<'_>, file: PathBuf) -> Result<(), FirebaseError> {
+    let (reader, mut writer) = tokio::io::duplex(CHUNK_SIZE);
+    let runtime = tokio::runtime::Handle::current();
+
+    let decompress = tokio::task::spawn_blocking(move || -> Result<(), FirebaseError> {
+        let file = std::fs::File::open(file).map_err(io_error)?;
+        let mut decoder = GzDecoder::new(std::io::BufReader::new(file));
+        let mut buffer = vec![0; CHUNK_SIZE];
+
+        loop {
+            match decoder.read(&mut buffer).map_err(io_error)? {
+                0 => return Ok(()),
+                read => runtime.block_on(writer.write_all(&buffer[..read])).map_err(io_error)?,
+            }
+        }
+    });
+
+    let imported = importer.import(reader, ImportFormat::Json).await;
+    let decompressed = decompress.await.map_err(|e| FirebaseError::new(e.to_string()))?;
+
+    imported?;
+    decompressed
+}
+
+fn io_error(error: std::io::Error) -> FirebaseError {
+    FirebaseError::new(format!("Backup: {}", error))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persist::test_directory as directory;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_entry_round_trip() {
+        let directory = directory("backup-entry");
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let chunks = vec![Ok(bytes::Bytes::from("{\"age\":")), Ok(bytes::Bytes::from("30}"))];
+        let mut output = tokio::fs::File::create(directory.join("0.json.gz")).await.unwrap();
+        let entry = write_entry("users/alice", "0.json.gz".to_string(), futures_util::stream::iter(chunks), &mut output).await.unwrap();
+        drop(output);
+
+        assert_eq!((entry.path(), entry.bytes()), ("users/alice", 10));
+        verify(&directory.join("0.json.gz"), entry.sha256()).await.unwrap();
+        assert!(verify(&directory.join("0.json.gz"), &hex(&[0; 32])).await.is_err());
+
+        let mut json = String::new();
+        GzDecoder::new(std::fs::File::open(directory.join("0.json.gz")).unwrap()).read_to_string(&mut json).unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&json).unwrap(), json!({"alice": {"age": 30}}));
+
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn test_unfinished_backup_leaves_no_files() {
+        let directory = directory("backup-unfinished");
+        std::fs::create_dir_all(directory.join("1.tmp")).unwrap();
+        std::fs::write(directory.join("1.tmp").join("0.json.gz"), b"").unwrap();
+
+        drop(Temporary(Some(directory.join("1.tmp"))));
+        assert!(!directory.join("1.tmp").exists());
+
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+
+    #[tokio::test]
+    async fn test_backups_started_together_get_their_own_directory() {
+        let backups = Backups::new(directory("backup-start"));
+        std::fs::create_dir_all(backups.directory.join("5.tmp")).unwrap();
+        std::fs::create_dir_all(backups.directory.join("6")).unwrap();
+
+        let (created, started) = backups.start(5).await.unwrap();
+        assert_eq!((created, &started), (7, &backups.directory.join("7.tmp")));
+        assert!(started.is_dir());
+        assert!(!backups.directory.join("6.tmp").exists());
+
+        assert_eq!(backups.start(5).await.unwrap().0, 8);
+
+        let _ = std::fs::remove_dir_all(&backups.directory);
+    }
+
+    #[test]
+    fn test_replaced_locations() {
+        let roots = vec!["users".to_string(), "config".to_string()];
+
+        assert_eq!(replaced(&roots, ""), BTreeSet::from(["users", "config"]));
+        assert_eq!(replaced(&roots, "users"), BTreeSet::from(["users"]));
+        assert_eq!(replaced(&roots, "users/alice"), BTreeSet::from(["users/alice"]));
+        assert!(replaced(&roots, "posts").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_restore_missing_location() {
+        let backups = Backups::new(directory("backup-missing"));
+        let client = crate::FirebaseClient::new("https://firerust-test.firebaseio.com/").unwrap();
+        let mut manifest = Manifest { created: 1, paths: vec!["users".to_string()], entries: Vec::new() };
+        std::fs::create_dir_all(backups.path(&manifest)).unwrap();
+
+        let chunks = vec![Ok(bytes::Bytes::from("{\"age\":30}"))];
+        let mut output = tokio::fs::File::create(backups.path(&manifest).join("0.json.gz")).await.unwrap();
+        manifest.entries.push(write_entry("users/alice", "0.json.gz".to_string(), futures_util::stream::iter(chunks), &mut output).await.unwrap());
+        drop(output);
+
+        // Nothing is written, the backup does not know these locations
+        for path in ["users/alice/name", "users/carol", "posts"] {
+            let error = backups.restore_subtree(&manifest, path, &client.reference("/")).await.unwrap_err();
+            assert_eq!(error.to_string(), format!("Backup 1 does not hold {}", path));
+        }
+
+        let _ = std::fs::remove_dir_all(&backups.directory);
+    }
+
+    #[test]
+    fn test_retention() {
+        let backups = Backups::new(directory("backup-retention"));
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+
+        for created in [now - 10_000, now - 20_000, now - 30_000, now - 40_000] {
+            let manifest = Manifest { created, paths: vec!["users".to_string()], entries: Vec::new() };
+            std::fs::create_dir_all(backups.path(&manifest)).unwrap();
+            std::fs::write(backups.path(&manifest).join(MANIFEST), serde_json::to_vec(&manifest).unwrap()).unwrap();
+        }
+        std::fs::create_dir_all(backups.directory.join(format!("{}.tmp", now))).unwrap();
+        std::fs::create_dir_all(backups.directory.join(format!("{}.tmp", now - 15_000)).join("0.json.gz")).unwrap();
+
+        let removed = backups.prune(&BackupOptions::new().keep_last(3)).unwrap();
+        assert_eq!(removed.iter().map(|m| m.created()).collect::<Vec<_>>(), vec![now - 40_000]);
+
+        // Only the backup that may still be running keeps its files
+        assert!(backups.directory.join(format!("{}.tmp", now)).exists());
+        assert!(!backups.directory.join(format!("{}.tmp", now - 15_000)).exists());
+
+        let removed = backups.prune(&BackupOptions::new().max_age(Duration::from_secs(15))).unwrap();
+        assert_eq!(removed.len(), 2);
+
+        // A corrupted manifest leaves its backup out instead of failing the listing
+        std::fs::create_dir_all(backups.directory.join("1")).unwrap();
+        std::fs::write(backups.directory.join("1").join(MANIFEST), b"{\"created\":").unwrap();
+        assert_eq!(backups.list().unwrap().iter().map(|m| m.created()).collect::<Vec<_>>(), vec![now - 10_000]);
+
+        // The most recent backup is kept whatever its age
+        assert!(backups.prune(&BackupOptions::new().max_age(Duration::ZERO)).unwrap().is_empty());
+
+        let _ = std::fs::remove_dir_all(&backups.directory);
+    }
+}
@@ -0,0 +1,467 @@
+//! # Example
+//!
+//! ```rust,no_run
+//! use firerust::{FirebaseClient, FirebaseError, BufferedWriter};
+//! use firerust::buffer::BufferOptions;
+//! use serde_json::json;
+//! use std::time::Duration;
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), FirebaseError> {
+//!     let client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+//!     let writer = BufferedWriter::new(&client, BufferOptions::new().interval(Duration::from_millis(250)));
+//!
+//!     // Both updates are sent in the same PATCH, the second temperature replaces the first
+//!     let first = writer.update("/devices/1", json!({"temperature": 21, "humidity": 40}))?;
+//!     let second = writer.update("/devices/1", json!({"temperature": 22}))?;
+//!
+//!     first.await?;
+//!     second.await?;
+//!     Ok(())
+//! }
+//! ```
+
+
+use crate::{ tree, FirebaseClient, FirebaseError, MultiPathUpdate, PendingWrite, RealtimeReference };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use tokio::sync::{ oneshot, watch, Notify };
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use crate::update::split_path;
+use std::collections::BTreeMap;
+use std::sync::{ Arc, Mutex };
+use std::time::Duration;
+use serde_json::Value;
+use serde::Serialize;
+
+
+type Waiter = oneshot::Sender<Result<(), FirebaseError>>;
+type Sender = Box<dyn Fn(MultiPathUpdate) -> BoxFuture<'static, Result<(), FirebaseError>> + Send + Sync>;
+
+
+/// When a buffered writer sends its pending writes
+#[derive(Clone, Debug, PartialEq)]
+pub struct BufferOptions {
+    interval: Duration,
+    max_paths: usize,
+}
+
+impl Default for BufferOptions {
+    fn default() -> BufferOptions {
+        BufferOptions {
+            interval: Duration::from_millis(100),
+            max_paths: 500,
+        }
+    }
+}
+
+impl BufferOptions {
+
+    /// Send the pending writes every 100 milliseconds, or as soon as they touch 500 paths
+    pub fn new() -> BufferOptions {
+        BufferOptions::default()
+    }
+
+    /// Set the time between two sends
+    pub fn interval(mut self, interval: Duration) -> BufferOptions {
+        self.interval = interval;
+        self
+    }
+
+    /// Set the number of pending paths that triggers a send before the interval is over
+    pub fn max_paths(mut self, max_paths: usize) -> BufferOptions {
+        self.max_paths = max_paths.max(1);
+        self
+    }
+}
+
+
+/// Pending writes, merged per path
+#[derive(Default)]
+struct Pending {
+    /// Values to write, no path is below another one
+    paths: BTreeMap<String, Value>,
+    waiters: Vec<Waiter>,
+    /// Set once the task sending the writes stopped, no write is accepted after it
+    closed: bool,
+}
+
+impl Pending {
+
+    /// Merge a value into the pending value of the given path, see [`RealtimeReference::merge_value`](crate::RealtimeReference::merge_value)
+    ///
+    /// A `null` value deletes the path and whatever is pending below it
+    fn set(&mut self, path: String, value: Value) {
+        let ancestor = self.paths.keys()
+            .find(|key| path == **key || path.starts_with(&format!("{}/", key)))
+            .cloned();
+
+        if let Some(ancestor) = ancestor {
+            let below = &path[ancestor.len()..];
+            if let Some(current) = self.paths.get_mut(&ancestor) {
+                merge(current, below, value);
+            }
+            return;
+        }
+
+        // The pending writes below the path become part of its value
+        let prefix = format!("{}/", path);
+        let mut current = Value::Null;
+        let below = self.paths.keys().filter(|key| key.starts_with(&prefix)).cloned().collect::<Vec<_>>();
+        for key in below {
+            if let Some(pending) = self.paths.remove(&key) {
+                merge(&mut current, &key[path.len()..], pending);
+            }
+        }
+
+        merge(&mut current, "", value);
+        self.paths.insert(path, current);
+    }
+
+    /// Build the multi-path update of the pending writes
+    fn take(&mut self) -> (MultiPathUpdate, Vec<Waiter>) {
+        let update = std::mem::take(&mut self.paths).into_iter()
+            .fold(MultiPathUpdate::new(), |update, (path, value)| update.set(&path, value));
+
+        (update, std::mem::take(&mut self.waiters))
+    }
+}
+
+
+/// Shared between the writer and the task sending its writes
+struct Buffer {
+    send: Sender,
+    options: BufferOptions,
+    pending: Mutex<Pending>,
+    /// Keeps the sends in the order of the writes
+    sending: tokio::sync::Mutex<()>,
+    full: Notify,
+    next_id: AtomicU64,
+}
+
+impl Buffer {
+
+    fn pending(&self) -> std::sync::MutexGuard<'_, Pending> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Send the pending writes as a single `PATCH` and report the outcome to each of them
+    async fn flush(&self) -> Result<(), FirebaseError> {
+        let _sending = self.sending.lock().await;
+        let (update, waiters) = self.pending().take();
+
+        if waiters.is_empty() {
+            return Ok(());
+        }
+
+        let result = (self.send)(update).await;
+        for waiter in waiters {
+            let _ = waiter.send(result.clone());
+        }
+
+        result
+    }
+}
+
+
+/// Merges frequent updates and sends them together as a single multi-path `PATCH`
+///
+/// Updates to the same location are deep merged like [`RealtimeReference::merge_value`](crate::RealtimeReference::merge_value)
+/// does, so only the merged value of each location is sent. The writes are sent every interval of the options, or
+/// sooner when too many locations are pending, and the remaining writes are sent when the writer
+/// is dropped or the client is shut down.
+pub struct BufferedWriter {
+    buffer: Arc<Buffer>,
+    _closed: oneshot::Sender<()>,
+}
+
+impl std::fmt::Debug for BufferedWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BufferedWriter")
+            .field("options", &self.buffer.options)
+            .field("pending", &self.len())
+            .finish()
+    }
+}
+
+impl BufferedWriter {
+
+    /// Create a writer sending through the given client, must be called from a Tokio runtime
+    pub fn new(client: &FirebaseClient, options: BufferOptions) -> BufferedWriter {
+        let sender = client.clone();
+
+        BufferedWriter::start(options, client.shutdown.subscribe(), Box::new(move |update| {
+            let client = sender.clone();
+            async move { update.apply(&client.reference("/")).await }.boxed()
+        }))
+    }
+
+    fn start(options: BufferOptions, mut shutdown: watch::Receiver<bool>, send: Sender) -> BufferedWriter {
+        let buffer = Arc::new(Buffer {
+            send,
+            options,
+            pending: Mutex::new(Pending::default()),
+            sending: tokio::sync::Mutex::new(()),
+            full: Notify::new(),
+            next_id: AtomicU64::new(0),
+        });
+
+        let (closed, mut dropped) = oneshot::channel();
+        let sender = buffer.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(sender.options.interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+            loop {
+                let last = tokio::select! {
+                    _ = &mut dropped => true,
+                    _ = shutdown.wait_for(|shutdown| *shutdown) => true,
+                    _ = interval.tick() => false,
+                    _ = sender.full.notified() => false,
+                };
+
+                // The writes accepted before this point are part of the last send
+                if last {
+                    sender.pending().closed = true;
+                }
+
+                // Errors are reported to the writes
+                let _ = sender.flush().await;
+
+                if last {
+                    break;
+                }
+            }
+        });
+
+        BufferedWriter {
+            buffer,
+            _closed: closed,
+        }
+    }
+
+    /// Queue an update of the children of the given location, see [`RealtimeReference::update`](crate::RealtimeReference::update)
+    ///
+    /// The returned future resolves once the `PATCH` holding the update was applied or rejected
+    ///
+    /// # Errors
+    /// Returns an error if the data can not be serialized, is not an object, a path is invalid, or the
+    /// client was shut down
+    pub fn update<T>(&self, path: &str, data: T) -> Result<PendingWrite, FirebaseError> where T: Serialize {
+        let base = path.split('/').filter(|s| !s.is_empty()).collect::<Vec<_>>().join("/");
+
+        // The server only accepts an object as the body of a `PATCH`
+        let writes = match serde_json::to_value(data)? {
+            Value::Object(map) => map.into_iter()
+                .map(|(key, value)| Ok((join(&base, &key)?, value)))
+                .collect::<Result<Vec<_>, FirebaseError>>()?,
+            value => return Err(FirebaseError::new(format!("Update of {} is not an object: {}", path, value))),
+        };
+
+        let (sender, receiver) = oneshot::channel();
+        let id = self.buffer.next_id.fetch_add(1, Ordering::Relaxed);
+
+        let mut pending = self.buffer.pending();
+        if pending.closed {
+            return Err(FirebaseError::new("Buffered writer: the client was shut down"));
+        }
+
+        for (path, value) in writes {
+            pending.set(path, value);
+        }
+        pending.waiters.push(sender);
+        let full = pending.paths.len() >= self.buffer.options.max_paths;
+        drop(pending);
+
+        if full {
+            self.buffer.full.notify_one();
+        }
+
+        Ok(PendingWrite::new(id, receiver))
+    }
+
+    /// Get the number of locations waiting to be sent
+    pub fn len(&self) -> usize {
+        self.buffer.pending().paths.len()
+    }
+
+    /// Returns true if no write is waiting to be sent
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Send the pending writes now
+    ///
+    /// # Errors
+    /// Returns an error if the server rejects the writes
+    pub async fn flush(&self) -> Result<(), FirebaseError> {
+        self.buffer.flush().await
+    }
+}
+
+
+/// Merge a value into the part of `current` at the relative path `below`
+fn merge(current: &mut Value, below: &str, value: Value) {
+    if value.is_null() {
+        tree::set(current, below, Value::Null);
+        return;
+    }
+
+    let nested = below.rsplit('/').filter(|s| !s.is_empty())
+        .fold(value, |value, segment| Value::Object(serde_json::Map::from_iter([(segment.to_string(), value)])));
+
+    // Merging values never fails
+    let _ = RealtimeReference::merge_value(current, nested);
+}
+
+/// Join a key of an update to the location it updates, validating the resulting path
+fn join(base: &str, key: &str) -> Result<String, FirebaseError> {
+    let path = format!("{}/{}", base, key);
+    Ok(split_path(&path)?.join("/"))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_pending_merge() {
+        let mut pending = Pending::default();
+        pending.set("devices/1/temperature".to_string(), json!(21));
+        pending.set("devices/1/humidity".to_string(), json!(40));
+        pending.set("devices/1/temperature".to_string(), json!(22));
+        pending.set("devices/2".to_string(), json!({"temperature": 18}));
+        pending.set("devices/2/humidity".to_string(), json!(55));
+        pending.set("devices/2/temperature".to_string(), Value::Null);
+
+        let (update, waiters) = pending.take();
+        assert!(waiters.is_empty());
+        assert_eq!(update.build().unwrap(), ("devices".to_string(), json!({
+            "1/temperature": 22,
+            "1/humidity": 40,
+            "2": {"humidity": 55},
+        })));
+        assert!(pending.paths.is_empty());
+    }
+
+    #[test]
+    fn test_pending_ancestor_replaces_descendants() {
+        let mut pending = Pending::default();
+        pending.set("a/b/c".to_string(), json!(1));
+        pending.set("a/bc".to_string(), json!(2));
+        pending.set("a/b".to_string(), Value::Null);
+
+        assert_eq!(pending.paths, BTreeMap::from([
+            ("a/b".to_string(), Value::Null),
+            ("a/bc".to_string(), json!(2)),
+        ]));
+    }
+
+    #[test]
+    fn test_pending_deep_merge() {
+        let mut pending = Pending::default();
+        pending.set("users/alice/status".to_string(), json!({"a": 1, "c": {"x": 1}}));
+        pending.set("users/alice/status".to_string(), json!({"b": 2, "c": {"y": 2}}));
+        pending.set("users/alice/status/a".to_string(), Value::Null);
+        pending.set("users/bob/status/a".to_string(), json!(1));
+        pending.set("users/bob".to_string(), json!({"status": {"b": 2}}));
+
+        assert_eq!(pending.paths, BTreeMap::from([
+            ("users/alice/status".to_string(), json!({"b": 2, "c": {"x": 1, "y": 2}})),
+            ("users/bob".to_string(), json!({"status": {"a": 1, "b": 2}})),
+        ]));
+    }
+
+    /// Start a writer recording the updates it sends, failing them with the given error
+    fn recording(options: BufferOptions, error: Option<&str>) -> (BufferedWriter, Arc<Mutex<Vec<Value>>>, watch::Sender<bool>) {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let recorder = sent.clone();
+        let error = error.map(FirebaseError::new);
+        let (close, shutdown) = watch::channel(false);
+
+        let writer = BufferedWriter::start(options, shutdown, Box::new(move |update| {
+            recorder.lock().unwrap().push(update.build().unwrap().1);
+            let result = error.clone().map_or(Ok(()), Err);
+            async move { result }.boxed()
+        }));
+
+        (writer, sent, close)
+    }
+
+    #[tokio::test]
+    async fn test_update_rejects_non_objects() {
+        let (writer, sent, _close) = recording(BufferOptions::new(), None);
+
+        assert!(writer.update("/devices/1", json!(21)).is_err());
+        assert!(writer.update("/devices/1", Value::Null).is_err());
+        assert!(writer.is_empty());
+
+        writer.flush().await.unwrap();
+        assert!(sent.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_flush_on_max_paths() {
+        let (writer, sent, _close) = recording(BufferOptions::new().interval(Duration::from_secs(3600)).max_paths(2), None);
+
+        let first = writer.update("/devices/1", json!({"temperature": 21})).unwrap();
+        tokio::task::yield_now().await;
+        assert!(sent.lock().unwrap().is_empty());
+
+        let second = writer.update("/devices/2", json!({"temperature": 18})).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async { first.await.and(second.await) }).await.unwrap().unwrap();
+
+        assert_eq!(*sent.lock().unwrap(), vec![json!({"1/temperature": 21, "2/temperature": 18})]);
+    }
+
+    #[tokio::test]
+    async fn test_flush_on_interval() {
+        let (writer, sent, _close) = recording(BufferOptions::new().interval(Duration::from_millis(20)), None);
+
+        let first = writer.update("/devices/1", json!({"temperature": 21})).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), first).await.unwrap().unwrap();
+
+        let second = writer.update("/devices/1", json!({"temperature": 22})).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), second).await.unwrap().unwrap();
+
+        assert_eq!(*sent.lock().unwrap(), vec![json!({"temperature": 21}), json!({"temperature": 22})]);
+    }
+
+    #[tokio::test]
+    async fn test_errors_reach_every_waiter() {
+        let (writer, sent, _close) = recording(BufferOptions::new().interval(Duration::from_secs(3600)), Some("401 Unauthorized"));
+
+        let first = writer.update("/devices/1", json!({"temperature": 21})).unwrap();
+        let second = writer.update("/devices/2", json!({"temperature": 18})).unwrap();
+
+        assert_eq!(writer.flush().await.unwrap_err().to_string(), "401 Unauthorized");
+        assert_eq!(first.await.unwrap_err().to_string(), "401 Unauthorized");
+        assert_eq!(second.await.unwrap_err().to_string(), "401 Unauthorized");
+        assert_eq!(sent.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_update_after_shutdown() {
+        let (writer, sent, close) = recording(BufferOptions::new().interval(Duration::from_secs(3600)), None);
+
+        let first = writer.update("/devices/1", json!({"temperature": 21})).unwrap();
+        close.send(true).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), first).await.unwrap().unwrap();
+
+        let error = writer.update("/devices/1", json!({"temperature": 22})).unwrap_err();
+        assert_eq!(error.to_string(), "Buffered writer: the client was shut down");
+        assert!(writer.is_empty());
+        assert_eq!(*sent.lock().unwrap(), vec![json!({"temperature": 21})]);
+    }
+
+    #[test]
+    fn test_join_validates_paths() {
+        assert_eq!(join("devices/1", "status/online").unwrap(), "devices/1/status/online");
+        assert_eq!(join("", "devices").unwrap(), "devices");
+        assert!(join("devices", "a.b").is_err());
+        assert!(join("", "").is_err());
+    }
+}
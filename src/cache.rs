@@ -0,0 +1,448 @@
+//! # Example
+//!
+//! ```rust,no_run
+//! use firerust::{FirebaseClient, FirebaseError};
+//! use firerust::cache::ReadCacheOptions;
+//! use serde_json::Value;
+//! use std::time::Duration;
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), FirebaseError> {
+//!     let mut client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+//!     client.enable_read_cache(ReadCacheOptions::new().listen("/config").ttl(Duration::from_secs(30))).await?;
+//!
+//!     // Served from the listener on /config once its initial snapshot arrived
+//!     let flags = client.reference("/config/flags").get::<Value>().await?;
+//!
+//!     println!("{:?} {:?}", flags, client.cache_stats());
+//!     Ok(())
+//! }
+//! ```
+
+
+use std::sync::atomic::{ AtomicU64, Ordering };
+use crate::registry::{ normalize, relative, descend };
+use crate::listener::ListenerHandle;
+use crate::connector::Response;
+use crate::FirebaseError;
+use std::collections::HashMap;
+use tokio::time::{ Duration, Instant };
+use std::sync::Mutex;
+use serde_json::Value;
+
+
+/// Number of locations the ETag cache of a client keeps
+pub(crate) const ETAG_CAPACITY: usize = 1000;
+
+/// Number of locations the read cache of a client keeps unless configured otherwise
+pub(crate) const READ_CACHE_CAPACITY: usize = 1000;
+
+
+/// Configuration of the read cache of a client
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReadCacheOptions {
+    paths: Vec<String>,
+    ttl: Option<Duration>,
+    capacity: Option<usize>,
+}
+
+impl ReadCacheOptions {
+
+    /// Create options that only serve reads from running listeners
+    pub fn new() -> ReadCacheOptions {
+        ReadCacheOptions::default()
+    }
+
+    /// Keep a listener on the given path, reads at or below it are served from the listener
+    pub fn listen(mut self, path: impl ToString) -> ReadCacheOptions {
+        self.paths.push(path.to_string());
+        self
+    }
+
+    /// Keep the values read from paths without a listener for the given time
+    pub fn ttl(mut self, ttl: Duration) -> ReadCacheOptions {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Keep at most the given number of values read from paths without a listener, the least recently
+    /// read one is dropped to make room
+    pub fn capacity(mut self, capacity: usize) -> ReadCacheOptions {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    /// Get the paths kept fresh by a listener
+    pub fn paths(&self) -> &[String] {
+        &self.paths
+    }
+}
+
+
+/// Hit and miss counters of the read cache
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    hits: u64,
+    misses: u64,
+}
+
+impl CacheStats {
+
+    /// Get the number of reads served from the cache
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Get the number of reads sent to the server
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// Get the share of reads served from the cache, zero before the first read
+    pub fn hit_ratio(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.hits as f64 / total as f64,
+        }
+    }
+}
+
+
+/// Values read from paths without a listener, kept for a limited time
+///
+/// Holds at most `capacity` locations, the least recently read one is dropped to make room
+pub(crate) struct ReadCache {
+    ttl: Option<Duration>,
+    capacity: usize,
+    entries: Mutex<ReadEntries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    /// Listeners of the configured paths, stopped with the cache
+    _listeners: Vec<ListenerHandle>,
+}
+
+#[derive(Default)]
+struct ReadEntries {
+    /// Expiry, value and last use of each location
+    values: HashMap<String, (Instant, Value, u64)>,
+    clock: u64,
+}
+
+impl ReadCache {
+
+    /// Create a cache, the listeners are stopped when the cache is dropped
+    pub(crate) fn new(options: &ReadCacheOptions, listeners: Vec<ListenerHandle>) -> ReadCache {
+        ReadCache {
+            ttl: options.ttl,
+            capacity: options.capacity.unwrap_or(READ_CACHE_CAPACITY).max(1),
+            entries: Mutex::new(ReadEntries::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            _listeners: listeners,
+        }
+    }
+
+    fn entries(&self) -> std::sync::MutexGuard<'_, ReadEntries> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Get the counters of the cache
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Count a read served from the cache
+    pub(crate) fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a read sent to the server
+    pub(crate) fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Get the value at the given path from a fresh entry of the path or of one of its ancestors
+    pub(crate) fn lookup(&self, path: &str) -> Option<Value> {
+        let path = normalize(path);
+        let mut entries = self.entries();
+        let now = Instant::now();
+        entries.clock += 1;
+        let clock = entries.clock;
+
+        entries.values.iter_mut()
+            .filter(|(_, (expires, _, _))| *expires > now)
+            .find_map(|(key, (_, value, used))| relative(key, &path).map(|below| {
+                *used = clock;
+                descend(value, below)
+            }))
+    }
+
+    /// Keep a value read from the server until its time to live expires
+    pub(crate) fn store(&self, path: &str, value: Value) {
+        let ttl = match self.ttl {
+            Some(ttl) => ttl,
+            None => return,
+        };
+
+        let mut entries = self.entries();
+        let now = Instant::now();
+
+        let path = normalize(path);
+        entries.clock += 1;
+
+        entries.values.retain(|_, (expires, _, _)| *expires > now);
+        if !entries.values.contains_key(&path) && entries.values.len() >= self.capacity {
+            let oldest = entries.values.iter().min_by_key(|(_, (_, _, used))| *used).map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.values.remove(&oldest);
+            }
+        }
+
+        let clock = entries.clock;
+        entries.values.insert(path, (now + ttl, value, clock));
+    }
+
+    /// Drop the entries a write to the given path changes
+    pub(crate) fn invalidate(&self, path: &str) {
+        let path = normalize(path);
+
+        self.entries().values.retain(|key, _| relative(key, &path).is_none() && relative(&path, key).is_none());
+    }
+}
+
+
+/// Value read from the server along with its ETag
+#[derive(Clone, Debug, PartialEq)]
+pub struct Versioned<T> {
+    value: T,
+    etag: Option<String>,
+    changed: bool,
+}
+
+impl<T> Versioned<T> {
+
+    pub(crate) fn new(value: T, etag: Option<String>, changed: bool) -> Versioned<T> {
+        Versioned {
+            value,
+            etag,
+            changed,
+        }
+    }
+
+    /// Get the value
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+
+    /// Take the value
+    pub fn into_value(self) -> T {
+        self.value
+    }
+
+    /// Get the ETag of the value, `None` if the server did not send one
+    pub fn etag(&self) -> Option<&str> {
+        self.etag.as_deref()
+    }
+
+    /// Check if the value changed since the previous read of the location, always true for the first read
+    pub fn changed(&self) -> bool {
+        self.changed
+    }
+}
+
+
+/// Last value and ETag read from each location, unchanged values are served from here
+///
+/// Holds at most `capacity` locations, the least recently read one is dropped to make room
+pub(crate) struct EtagCache {
+    capacity: usize,
+    entries: Mutex<EtagEntries>,
+}
+
+#[derive(Default)]
+struct EtagEntries {
+    /// ETag, value and last use of each location
+    values: HashMap<String, (String, Value, u64)>,
+    clock: u64,
+}
+
+impl EtagCache {
+
+    pub(crate) fn new(capacity: usize) -> EtagCache {
+        EtagCache {
+            capacity: capacity.max(1),
+            entries: Mutex::new(EtagEntries::default()),
+        }
+    }
+
+    fn entries(&self) -> std::sync::MutexGuard<'_, EtagEntries> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Get the ETag and value last read from the given path
+    pub(crate) fn lookup(&self, path: &str) -> Option<(String, Value)> {
+        let mut entries = self.entries();
+        entries.clock += 1;
+        let clock = entries.clock;
+
+        entries.values.get_mut(&normalize(path)).map(|(etag, value, used)| {
+            *used = clock;
+            (etag.clone(), value.clone())
+        })
+    }
+
+    /// Keep the value read from the given path with its ETag
+    pub(crate) fn store(&self, path: &str, etag: String, value: Value) {
+        let mut entries = self.entries();
+        let path = normalize(path);
+        entries.clock += 1;
+
+        if !entries.values.contains_key(&path) && entries.values.len() >= self.capacity {
+            let oldest = entries.values.iter().min_by_key(|(_, (_, _, used))| *used).map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.values.remove(&oldest);
+            }
+        }
+
+        let clock = entries.clock;
+        entries.values.insert(path, (etag, value, clock));
+    }
+
+    /// Drop the entries a write to the given path changes
+    pub(crate) fn invalidate(&self, path: &str) {
+        let path = normalize(path);
+
+        self.entries().values.retain(|key, _| relative(key, &path).is_none() && relative(&path, key).is_none());
+    }
+}
+
+
+/// Get the value of a conditional read and whether it changed, from the response and the ETag and
+/// value read before
+///
+/// # Errors
+/// Returns an error if the server refused the read or the body is not valid JSON
+pub(crate) fn revalidate(response: &Response, previous: Option<(String, Value)>) -> Result<(Value, bool), FirebaseError> {
+    match (response.status().code(), previous) {
+        (304, Some((_, value))) => Ok((value, false)),
+        (200, Some((etag, value))) if response.etag() == Some(etag.as_str()) => Ok((value, false)),
+        (200, _) => Ok((serde_json::from_str::<Value>(response.body())?, true)),
+        (code, _) => Err(FirebaseError::new(format!("{} {}", code, response.status().message()))),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connector::Status;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_ttl_entries() {
+        let cache = ReadCache::new(&ReadCacheOptions::new().ttl(Duration::from_millis(50)), Vec::new());
+        cache.store("/users", json!({"alice": {"age": 30}}));
+
+        assert_eq!(cache.lookup("users/alice/age"), Some(json!(30)));
+        assert_eq!(cache.lookup("users/bob"), Some(Value::Null));
+        assert_eq!(cache.lookup("posts"), None);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(cache.lookup("users"), None);
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let cache = ReadCache::new(&ReadCacheOptions::new().ttl(Duration::from_secs(10)), Vec::new());
+        cache.store("users/alice", json!(1));
+        cache.store("users/bob", json!(2));
+        cache.store("posts", json!(3));
+
+        cache.invalidate("/users/alice/age");
+        cache.invalidate("/posts/1");
+        assert_eq!(cache.lookup("users/alice"), None);
+        assert_eq!(cache.lookup("users/bob"), Some(json!(2)));
+        assert_eq!(cache.lookup("posts"), None);
+
+        cache.invalidate("/");
+        assert_eq!(cache.lookup("users/bob"), None);
+    }
+
+    #[test]
+    fn test_read_cache_evicts_least_recently_read() {
+        let cache = ReadCache::new(&ReadCacheOptions::new().ttl(Duration::from_secs(10)).capacity(2), Vec::new());
+        cache.store("a", json!(1));
+        cache.store("b", json!(2));
+        cache.lookup("a/x");
+
+        cache.store("c", json!(3));
+        assert_eq!(cache.lookup("a"), Some(json!(1)));
+        assert_eq!(cache.lookup("b"), None);
+        assert_eq!(cache.lookup("c"), Some(json!(3)));
+
+        cache.store("c", json!(4));
+        assert_eq!(cache.lookup("a"), Some(json!(1)));
+        assert_eq!(cache.entries().values.len(), 2);
+    }
+
+    #[test]
+    fn test_stats() {
+        let cache = ReadCache::new(&ReadCacheOptions::new(), Vec::new());
+        assert_eq!(cache.stats().hit_ratio(), 0.0);
+
+        cache.hit();
+        cache.hit();
+        cache.hit();
+        cache.miss();
+        assert_eq!((cache.stats().hits(), cache.stats().misses(), cache.stats().hit_ratio()), (3, 1, 0.75));
+    }
+
+    #[test]
+    fn test_etag_cache() {
+        let cache = EtagCache::new(10);
+        assert_eq!(cache.lookup("users"), None);
+
+        cache.store("/users/", "a1".to_string(), json!({"alice": 1}));
+        cache.store("users", "b2".to_string(), json!({"alice": 2}));
+        assert_eq!(cache.lookup("users"), Some(("b2".to_string(), json!({"alice": 2}))));
+        assert_eq!(cache.lookup("users/alice"), None);
+
+        cache.store("posts", "c3".to_string(), json!(3));
+        cache.invalidate("/users/alice");
+        assert_eq!(cache.lookup("users"), None);
+        assert!(cache.lookup("posts").is_some());
+    }
+
+    #[test]
+    fn test_etag_cache_evicts_least_recently_read() {
+        let cache = EtagCache::new(2);
+        cache.store("a", "1".to_string(), json!(1));
+        cache.store("b", "2".to_string(), json!(2));
+        cache.lookup("a");
+
+        cache.store("c", "3".to_string(), json!(3));
+        assert!(cache.lookup("a").is_some());
+        assert_eq!(cache.lookup("b"), None);
+        assert!(cache.lookup("c").is_some());
+    }
+
+    #[test]
+    fn test_revalidate() {
+        let previous = || Some(("a1".to_string(), json!({"alice": 1})));
+        let ok = |body: &str| Response::new(body, Status::new(200, "OK"));
+
+        let not_modified = Response::new("", Status::new(304, "Not Modified"));
+        assert_eq!(revalidate(&not_modified, previous()).unwrap(), (json!({"alice": 1}), false));
+
+        // Some servers answer 200 with the same ETag instead of 304
+        assert_eq!(revalidate(&ok("{\"alice\": 1}").with_etag("a1"), previous()).unwrap(), (json!({"alice": 1}), false));
+        assert_eq!(revalidate(&ok("{\"alice\": 2}").with_etag("b2"), previous()).unwrap(), (json!({"alice": 2}), true));
+        assert_eq!(revalidate(&ok("1").with_etag("a1"), None).unwrap(), (json!(1), true));
+
+        assert!(revalidate(&not_modified, None).is_err());
+        assert!(revalidate(&Response::new("", Status::new(401, "Unauthorized")), previous()).is_err());
+    }
+}
@@ -11,15 +11,79 @@
 //! ```
 
 
-use reqwest::{Client, ClientBuilder, Response as ReqwestResponse};
+use reqwest::{Client, ClientBuilder, RequestBuilder, Response as ReqwestResponse};
+use futures_util::future::{ BoxFuture, Shared };
 use std::fmt::{ Display, Formatter };
+use std::collections::HashMap;
+use futures_util::FutureExt;
+use std::sync::{ Arc, Mutex };
+use std::time::Duration;
 use std::error::Error;
+use crate::registry::{ normalize, relative };
 
 /// A connector to a Firebase server.
 #[derive(Clone, Debug)]
 pub struct Connector {
     client: Client,
     base_url: String,
+    reads: Arc<InFlight>,
+}
+
+
+type SharedRead = Shared<BoxFuture<'static, Result<Response, Arc<ConnectorError>>>>;
+
+/// Identifies the reads that can share a request
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct ReadKey {
+    /// The location read, to find the reads a write changes
+    path: String,
+    url: String,
+    api_key: Option<String>,
+    /// `Some` for conditional reads, with the ETag they send if any
+    etag: Option<Option<String>>,
+}
+
+/// Reads waiting for the server
+#[derive(Default)]
+struct InFlight(Mutex<HashMap<ReadKey, SharedRead>>);
+
+impl std::fmt::Debug for InFlight {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "InFlight({})", self.reads().len())
+    }
+}
+
+impl InFlight {
+
+    fn reads(&self) -> std::sync::MutexGuard<'_, HashMap<ReadKey, SharedRead>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Join the read with the same key already waiting for the server, or start it
+    async fn join(&self, key: ReadKey, start: impl FnOnce() -> BoxFuture<'static, Result<Response, ConnectorError>>) -> Result<Response, ConnectorError> {
+        let read = self.reads().entry(key.clone())
+            .or_insert_with(|| start().map(|result| result.map_err(Arc::new)).boxed().shared())
+            .clone();
+
+        let result = read.clone().await;
+
+        // A later read must reach the server again, unless a new one already took the place
+        let mut reads = self.reads();
+        if reads.get(&key).is_some_and(|current| current.ptr_eq(&read)) {
+            reads.remove(&key);
+        }
+        drop(reads);
+
+        result.map_err(|e| Arc::try_unwrap(e).unwrap_or_else(ConnectorError::Shared))
+    }
+
+    /// Make the reads of locations a write changes reach the server again, the reads already
+    /// waiting still get their response
+    fn invalidate(&self, path: &str) {
+        let path = normalize(path);
+
+        self.reads().retain(|key, _| relative(&key.path, &path).is_none() && relative(&path, &key.path).is_none());
+    }
 }
 
 impl Connector {
@@ -37,14 +101,20 @@ impl Connector {
         let client = ClientBuilder::new()
             .tcp_nodelay(true)
             .build()
-            .map_err(|e| ConnectorError::Reqwest(e))?;
+            .map_err(ConnectorError::Reqwest)?;
 
         Ok(Connector {
             client,
-            base_url
+            base_url,
+            reads: Arc::new(InFlight::default()),
         })
     }
 
+    /// Get the scheme, host and port of the database
+    pub(crate) fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
     fn build_url(&self, path: &str, params: Option<&str>) -> String {
         let mut p = path;
         if p.starts_with('/') {
@@ -60,11 +130,22 @@ impl Connector {
     }
 
     /// Send data to the server
+    ///
+    /// Concurrent reads of the same url with the same credentials share one request and its response,
+    /// a read started after a write to the location does not join a read started before it
     pub async fn request(&self, method: Method, path: &str, params: Option<&str>, data: Option<&str>, api_key: Option<&str>) -> Result<Response, ConnectorError> {
         let url = self.build_url(path, params);
+
+        if !matches!(method, Method::Get) {
+            self.invalidate_reads(path);
+        }
         
         let mut builder = match method {
-            Method::Get => self.client.get(&url),
+            Method::Get => {
+                let key = ReadKey { path: normalize(path), url, api_key: api_key.map(|key| key.to_string()), etag: None };
+                let builder = self.get(&key.url, api_key);
+                return self.reads.join(key, || send(builder).boxed()).await;
+            },
             Method::Put => self.client.put(&url),
             Method::Post => self.client.post(&url),
             Method::Patch => self.client.patch(&url),
@@ -80,31 +161,83 @@ impl Connector {
                              .body(body_data.to_string());
         }
 
-        let res = builder.send().await?;
-        let status_code = res.status().as_u16();
-        let status_msg = res.status().canonical_reason().unwrap_or("Unknown").to_string();
-        let body = res.text().await?;
+        // Reads started while the write was on its way may not see it either
+        let response = send(builder).await;
+        self.invalidate_reads(path);
+        response
+    }
+
+    fn get(&self, url: &str, api_key: Option<&str>) -> RequestBuilder {
+        let builder = self.client.get(url);
+
+        match api_key {
+            Some(key) => builder.bearer_auth(key),
+            None => builder,
+        }
+    }
 
-        Ok(Response::new(body, Status::new(status_code, status_msg)))
+    /// Make the reads of locations a write to the given path changes reach the server again
+    pub(crate) fn invalidate_reads(&self, path: &str) {
+        self.reads.invalidate(path);
+    }
+
+    /// Read data from the server with its ETag, the body is empty if the data still matches `etag`
+    ///
+    /// Concurrent reads sending the same ETag share one request, like [`Connector::request`]
+    pub async fn conditional_get(&self, path: &str, etag: Option<&str>, api_key: Option<&str>) -> Result<Response, ConnectorError> {
+        let url = self.build_url(path, None);
+
+        let mut builder = self.get(&url, api_key).header("X-Firebase-ETag", "true");
+        if let Some(etag) = etag {
+            builder = builder.header("if-none-match", etag);
+        }
+
+        let key = ReadKey { path: normalize(path), url, api_key: api_key.map(|key| key.to_string()), etag: Some(etag.map(|etag| etag.to_string())) };
+        self.reads.join(key, || send_conditional(builder).boxed()).await
+    }
+
+    /// Read data from the server without waiting for the body, which can then be streamed
+    pub async fn download(&self, path: &str, params: Option<&str>, api_key: Option<&str>) -> Result<ReqwestResponse, ConnectorError> {
+        let url = self.build_url(path, params);
+
+        Ok(self.get(&url, api_key).send().await?)
     }
 
     /// Connect to the server with event stream
     pub async fn event_stream(&self, path: &str, params: Option<&str>, api_key: Option<&str>) -> Result<ReqwestResponse, ConnectorError> {
         let url = self.build_url(path, params);
-        
-        let mut builder = self.client.get(&url).header("Accept", "text/event-stream");
-
-        if let Some(key) = api_key {
-            builder = builder.bearer_auth(key);
-        }
 
-        let res = builder.send().await?;
-        Ok(res)
+        Ok(self.get(&url, api_key).header("Accept", "text/event-stream").send().await?)
     }
 }
 
 
+async fn send(builder: RequestBuilder) -> Result<Response, ConnectorError> {
+    let res = builder.send().await?;
+    let status_code = res.status().as_u16();
+    let status_msg = res.status().canonical_reason().unwrap_or("Unknown").to_string();
+    let body = res.text().await?;
+
+    Ok(Response::new(body, Status::new(status_code, status_msg)))
+}
+
+async fn send_conditional(builder: RequestBuilder) -> Result<Response, ConnectorError> {
+    let res = builder.send().await?;
+    let status_code = res.status().as_u16();
+    let status_msg = res.status().canonical_reason().unwrap_or("Unknown").to_string();
+    let etag = res.headers().get("etag").and_then(|etag| etag.to_str().ok()).map(|etag| etag.to_string());
+    let body = res.text().await?;
+
+    let response = Response::new(body, Status::new(status_code, status_msg));
+    Ok(match etag {
+        Some(etag) => response.with_etag(etag),
+        None => response,
+    })
+}
+
+
 /// Status response
+#[derive(Clone, Debug)]
 pub struct Status {
     code: u16,
     message: String
@@ -115,7 +248,7 @@ impl Status {
     /// Create a new status
     pub fn new(code: u16, message: impl ToString) -> Status {
         Status {
-            code: code,
+            code,
             message: message.to_string()
         }
     }
@@ -139,9 +272,11 @@ impl Display for Status {
 
 
 /// Database request response
+#[derive(Clone, Debug)]
 pub struct Response {
     body: String,
     status: Status,
+    etag: Option<String>,
 }
 
 impl Response {
@@ -150,10 +285,17 @@ impl Response {
     pub fn new(body: impl ToString, status: Status) -> Response {
         Response {
             body: body.to_string(),
-            status: status
+            status,
+            etag: None,
         }
     }
 
+    /// Set the ETag of the data of the response
+    pub fn with_etag(mut self, etag: impl ToString) -> Response {
+        self.etag = Some(etag.to_string());
+        self
+    }
+
     /// Get the response body
     pub fn body(&self) -> &str {
         &self.body
@@ -163,6 +305,11 @@ impl Response {
     pub fn status(&self) -> &Status {
         &self.status
     }
+
+    /// Get the ETag of the data, only sent for conditional reads
+    pub fn etag(&self) -> Option<&str> {
+        self.etag.as_deref()
+    }
 }
 
 
@@ -196,6 +343,7 @@ impl From<String> for EventType {
 pub struct EventStream {
     event: EventType,
     data: String,
+    id: String,
 }
 
 impl EventStream {
@@ -204,7 +352,8 @@ impl EventStream {
     pub fn new(event: impl ToString, data: impl ToString) -> EventStream {
         EventStream {
             data: data.to_string(),
-            event: EventType::from(event.to_string())
+            event: EventType::from(event.to_string()),
+            id: String::new(),
         }
     }
 
@@ -217,31 +366,176 @@ impl EventStream {
     pub fn data(&self) -> &str {
         &self.data
     }
+
+    /// Get the last event id at the time the event was dispatched
+    pub fn id(&self) -> &str {
+        &self.id
+    }
 }
 
 impl TryFrom<String> for EventStream {
     type Error = &'static str;
 
     fn try_from(data: String) -> Result<Self, Self::Error> {
-        let mut event_stream = data.lines().filter(|l| l.contains(":"));
+        let mut decoder = SseDecoder::new();
+        let mut events = decoder.feed(data.as_bytes());
+        events.extend(decoder.feed(b"\n\n"));
+
+        match events.into_iter().next() {
+            Some(event) => Ok(event),
+            None => Err("Invalid event stream")
+        }
+    }
+}
+
+
+const BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Incremental decoder for `text/event-stream` bodies, following the WHATWG specification
+///
+/// Bytes can be fed in chunks of any size, each byte is scanned once
+///
+/// # Example
+/// ```rust
+/// use firerust::connector::SseDecoder;
+///
+/// let mut decoder = SseDecoder::new();
+/// assert!(decoder.feed(b"event: put\r\ndata: {\"path\"").is_empty());
+///
+/// let events = decoder.feed(b": \"/\"}\r\n\r\n");
+/// assert_eq!(events[0].data(), "{\"path\": \"/\"}");
+/// ```
+#[derive(Debug, Default)]
+pub struct SseDecoder {
+    line: Vec<u8>,
+    event: String,
+    data: String,
+    has_data: bool,
+    last_event_id: String,
+    retry: Option<Duration>,
+    after_cr: bool,
+    started: bool,
+}
+
+impl SseDecoder {
+
+    /// Create a new decoder
+    pub fn new() -> SseDecoder {
+        SseDecoder::default()
+    }
 
-        let event = match event_stream.next() {
-            Some(event) => match event.strip_prefix("event: ") {
-                Some(event) => event.to_string(),
-                None => return Err("Invalid event")
+    /// Feed a chunk of the body and get the events completed by it
+    pub fn feed(&mut self, mut chunk: &[u8]) -> Vec<EventStream> {
+        let mut events = Vec::new();
+
+        // A single byte order mark may start the stream, hold the bytes until it is known
+        if !self.started {
+            let wanted = &BOM[self.line.len()..];
+            let common = wanted.len().min(chunk.len());
+
+            if chunk[..common] == wanted[..common] {
+                self.line.extend_from_slice(&chunk[..common]);
+                chunk = &chunk[common..];
+
+                if self.line.len() < BOM.len() {
+                    return events;
+                }
+                self.line.clear();
+            }
+            self.started = true;
+        }
+
+        // A CR at the end of a previous chunk already ended the line, skip the LF of a CRLF
+        if let Some(&first) = chunk.first() {
+            if self.after_cr && first == b'\n' {
+                chunk = &chunk[1..];
+            }
+            self.after_cr = false;
+        }
+
+        while let Some(pos) = chunk.iter().position(|&b| b == b'\n' || b == b'\r') {
+            self.line.extend_from_slice(&chunk[..pos]);
+
+            let line = std::mem::take(&mut self.line);
+            if let Some(event) = self.process_line(&line) {
+                events.push(event);
+            }
+
+            if chunk[pos] == b'\r' {
+                match chunk.get(pos + 1) {
+                    Some(b'\n') => { chunk = &chunk[pos + 2..]; continue; },
+                    Some(_) => {},
+                    None => self.after_cr = true,
+                }
+            }
+            chunk = &chunk[pos + 1..];
+        }
+
+        self.line.extend_from_slice(chunk);
+        events
+    }
+
+    /// Get the last event id received
+    pub fn last_event_id(&self) -> &str {
+        &self.last_event_id
+    }
+
+    /// Get the reconnection time requested by the server
+    pub fn retry(&self) -> Option<Duration> {
+        self.retry
+    }
+
+    fn process_line(&mut self, line: &[u8]) -> Option<EventStream> {
+        if line.is_empty() {
+            return self.dispatch();
+        }
+
+        if line[0] == b':' {
+            return None;
+        }
+
+        let (field, value) = match line.iter().position(|&b| b == b':') {
+            Some(pos) => {
+                let value = &line[pos + 1..];
+                (&line[..pos], value.strip_prefix(b" ").unwrap_or(value))
             },
-            None => return Err("Invalid event stream")
+            None => (line, &b""[..]),
         };
 
-        let data = match event_stream.next() {
-            Some(data) => match data.strip_prefix("data: ") {
-                Some(data) => data.to_string(),
-                None => return Err("Invalid data")
+        let value = String::from_utf8_lossy(value);
+
+        match field {
+            b"event" => self.event = value.into_owned(),
+            b"data" => {
+                if self.has_data {
+                    self.data.push('\n');
+                }
+                self.data.push_str(&value);
+                self.has_data = true;
             },
-            None => return Err("Invalid event stream")
-        };
+            b"id" if !value.contains('\0') => self.last_event_id = value.into_owned(),
+            b"retry" if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => {
+                self.retry = value.parse::<u64>().ok().map(Duration::from_millis);
+            },
+            _ => {}
+        }
 
-        Ok(EventStream::new(event, data))
+        None
+    }
+
+    fn dispatch(&mut self) -> Option<EventStream> {
+        let event = std::mem::take(&mut self.event);
+        let data = std::mem::take(&mut self.data);
+
+        if !std::mem::take(&mut self.has_data) {
+            return None;
+        }
+
+        Some(EventStream {
+            event: EventType::from(if event.is_empty() { "message".to_string() } else { event }),
+            data,
+            id: self.last_event_id.clone(),
+        })
     }
 }
 
@@ -274,6 +568,8 @@ impl Display for Method {
 pub enum ConnectorError {
     Reqwest(reqwest::Error),
     EventParse(String),
+    /// Error of a read shared with other callers
+    Shared(Arc<ConnectorError>),
 }
 
 impl Display for ConnectorError {
@@ -281,6 +577,7 @@ impl Display for ConnectorError {
         match self {
             ConnectorError::Reqwest(e) => write!(f, "Request error: {}", e),
             ConnectorError::EventParse(e) => write!(f, "Event parse error: {}", e),
+            ConnectorError::Shared(e) => write!(f, "{}", e),
         }
     }
 }
@@ -290,3 +587,137 @@ impl Error for ConnectorError {}
 impl From<reqwest::Error> for ConnectorError { fn from(e: reqwest::Error) -> Self { ConnectorError::Reqwest(e) } }
 impl From<std::string::FromUtf8Error> for ConnectorError { fn from(e: std::string::FromUtf8Error) -> Self { ConnectorError::EventParse(e.to_string()) } }
 impl From<&'static str> for ConnectorError { fn from(e: &'static str) -> Self { ConnectorError::EventParse(e.to_string()) } }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(chunks: &[&[u8]]) -> Vec<(String, String, String)> {
+        let mut decoder = SseDecoder::new();
+        chunks.iter()
+            .flat_map(|chunk| decoder.feed(chunk))
+            .map(|e| (format!("{:?}", e.event()), e.data().to_string(), e.id().to_string()))
+            .collect()
+    }
+
+    fn event(event: &str, data: &str, id: &str) -> (String, String, String) {
+        (event.to_string(), data.to_string(), id.to_string())
+    }
+
+    #[test]
+    fn test_sse_firebase_event() {
+        let events = decode(&[b"event: put\ndata: {\"path\":\"/\",\"data\":1}\n\n"]);
+        assert_eq!(events, vec![event("Put", "{\"path\":\"/\",\"data\":1}", "")]);
+    }
+
+    #[test]
+    fn test_sse_split_chunks() {
+        let body = b"event: patch\r\ndata: {\"a\":\r\ndata: 1}\r\n\r\nevent: keep-alive\r\ndata: null\r\n\r\n";
+        let expected = vec![event("Patch", "{\"a\":\n1}", ""), event("KeepAlive", "null", "")];
+
+        for size in 1..body.len() {
+            let chunks: Vec<&[u8]> = body.chunks(size).collect();
+            assert_eq!(decode(&chunks), expected, "chunk size {}", size);
+        }
+    }
+
+    #[test]
+    fn test_sse_line_endings() {
+        let events = decode(&[b"event:put\rdata:a\r\rdata:b\n\ndata:c\r\n\r\n"]);
+        assert_eq!(events, vec![event("Put", "a", ""), event("Unknown(\"message\")", "b", ""), event("Unknown(\"message\")", "c", "")]);
+    }
+
+    #[test]
+    fn test_sse_cr_split_from_lf() {
+        let events = decode(&[b"data: a\r", b"\ndata: b\r", b"\r", b"\n"]);
+        assert_eq!(events, vec![event("Unknown(\"message\")", "a\nb", "")]);
+
+        // An empty chunk between the CR and the LF does not end another line
+        let events = decode(&[b"data: a\r", b"", b"\ndata: b\r", b"\r", b"", b"\n"]);
+        assert_eq!(events, vec![event("Unknown(\"message\")", "a\nb", "")]);
+    }
+
+    #[test]
+    fn test_sse_fields() {
+        let mut decoder = SseDecoder::new();
+        let events = decoder.feed(b": comment\nid: 42\nretry: 1500\nretry: soon\nfoo: bar\ndata\ndata:  two spaces\n\n");
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].data(), "\n two spaces");
+        assert_eq!(events[0].id(), "42");
+        assert_eq!(decoder.last_event_id(), "42");
+        assert_eq!(decoder.retry(), Some(Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn test_sse_skips_events_without_data() {
+        let events = decode(&[b"event: put\n\nid: 1\n\n: ping\n\ndata: x\n\ndata: unterminated"]);
+        assert_eq!(events, vec![event("Unknown(\"message\")", "x", "1")]);
+    }
+
+    #[test]
+    fn test_sse_byte_order_mark() {
+        assert_eq!(decode(&[b"\xEF\xBB", b"\xBFdata: a\n\n"]), vec![event("Unknown(\"message\")", "a", "")]);
+        assert_eq!(decode(&[b"\xEF\xBB\xBF\xEF\xBB\xBFdata: a\n\n"]), vec![]);
+    }
+
+    #[test]
+    fn test_event_stream_try_from() {
+        let event = EventStream::try_from("event:cancel\ndata:\"permission denied\"".to_string()).unwrap();
+
+        assert!(matches!(event.event(), EventType::Cancel));
+        assert_eq!(event.data(), "\"permission denied\"");
+        assert!(EventStream::try_from(": only a comment".to_string()).is_err());
+    }
+
+    fn read_key(path: &str) -> ReadKey {
+        ReadKey { path: path.to_string(), url: format!("https://x.firebaseio.com:443/{}.json", path), api_key: None, etag: None }
+    }
+
+    /// Start a read answering after a short delay, counting the reads started
+    fn start(started: &std::sync::atomic::AtomicUsize) -> BoxFuture<'static, Result<Response, ConnectorError>> {
+        started.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+        async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok(Response::new("1", Status::new(200, "OK")))
+        }.boxed()
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_reads_share_one_request() {
+        let (reads, started) = (InFlight::default(), std::sync::atomic::AtomicUsize::new(0));
+
+        let responses = futures_util::future::join_all((0..5).map(|_| reads.join(read_key("users"), || start(&started)))).await;
+
+        assert_eq!(started.into_inner(), 1);
+        assert!(responses.iter().all(|response| response.as_ref().is_ok_and(|r| r.body() == "1")));
+        assert!(reads.reads().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_read_after_write_is_not_coalesced() {
+        let (reads, started) = (InFlight::default(), std::sync::atomic::AtomicUsize::new(0));
+
+        let after_write = async {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            reads.invalidate("/users/alice");
+            reads.join(read_key("users"), || start(&started)).await
+        };
+        let unrelated = async {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            reads.join(read_key("posts"), || start(&started)).await
+        };
+
+        let (before, after, _, _) = futures_util::join!(
+            reads.join(read_key("users"), || start(&started)),
+            after_write,
+            reads.join(read_key("posts"), || start(&started)),
+            unrelated,
+        );
+
+        assert!(before.is_ok() && after.is_ok());
+        assert_eq!(started.into_inner(), 3);
+    }
+}
@@ -0,0 +1,312 @@
+//! # Example
+//!
+//! ```rust,no_run
+//! use firerust::{FirebaseClient, FirebaseError};
+//! use futures_util::StreamExt;
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), FirebaseError> {
+//!     let client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+//!
+//!     // Only one user is held in memory at a time
+//!     let mut users = client.reference("/users").stream_children().await?;
+//!     while let Some(user) = users.next().await {
+//!         let (key, value) = user?;
+//!         println!("{}: {}", key, value);
+//!     }
+//!
+//!     Ok(())
+//! }
+//! ```
+
+
+use crate::FirebaseError;
+use serde_json::Value;
+
+
+/// Progress of a download
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Progress {
+    bytes: u64,
+    total: Option<u64>,
+}
+
+impl Progress {
+
+    pub(crate) fn new(bytes: u64, total: Option<u64>) -> Progress {
+        Progress {
+            bytes,
+            total,
+        }
+    }
+
+    /// Get the number of bytes received so far
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Get the size of the download, `None` if the server did not announce it
+    pub fn total(&self) -> Option<u64> {
+        self.total
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Start,
+    /// Inside a `null` document, with the number of bytes read
+    Null(usize),
+    /// Before a key of an object, true for the first one which may also close the object
+    Key(bool),
+    InKey,
+    Colon,
+    Value,
+    Done,
+}
+
+/// Incremental reader of the top-level children of a JSON document
+///
+/// Bytes are fed in chunks as they arrive, only the child being read is kept in memory. Children
+/// of an array are keyed by their index and `null` children are skipped, the way the server
+/// stores them.
+#[derive(Debug)]
+pub struct ChildReader {
+    state: State,
+    /// Index of the next child when the document is an array
+    index: Option<usize>,
+    key: String,
+    buffer: Vec<u8>,
+    depth: usize,
+    string: bool,
+    escape: bool,
+    offset: u64,
+}
+
+impl Default for ChildReader {
+    fn default() -> Self {
+        ChildReader::new()
+    }
+}
+
+impl ChildReader {
+
+    /// Create a reader expecting the start of a document
+    pub fn new() -> ChildReader {
+        ChildReader {
+            state: State::Start,
+            index: None,
+            key: String::new(),
+            buffer: Vec::new(),
+            depth: 0,
+            string: false,
+            escape: false,
+            offset: 0,
+        }
+    }
+
+    /// Feed a chunk of the document, returning the children it completes
+    ///
+    /// # Errors
+    /// Returns an error if the document is not a valid JSON object, array or `null`
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<Vec<(String, Value)>, FirebaseError> {
+        let mut children = Vec::new();
+
+        for &byte in chunk {
+            self.offset += 1;
+
+            match self.state {
+                State::Start => match byte {
+                    b'{' => self.state = State::Key(true),
+                    b'[' => {
+                        self.index = Some(0);
+                        self.state = State::Value;
+                    },
+                    b'n' => self.state = State::Null(1),
+                    byte if byte.is_ascii_whitespace() => {},
+                    _ => return Err(self.error("expected an object, an array or null")),
+                },
+                State::Null(read) => {
+                    if b"null"[read] != byte {
+                        return Err(self.error("expected null"));
+                    }
+                    self.state = if read == 3 { State::Done } else { State::Null(read + 1) };
+                },
+                State::Key(first) => match byte {
+                    b'"' => {
+                        self.buffer.push(byte);
+                        self.state = State::InKey;
+                    },
+                    b'}' if first => self.state = State::Done,
+                    byte if byte.is_ascii_whitespace() => {},
+                    _ => return Err(self.error("expected a key")),
+                },
+                State::InKey => {
+                    self.buffer.push(byte);
+
+                    if self.escape {
+                        self.escape = false;
+                    } else if byte == b'\\' {
+                        self.escape = true;
+                    } else if byte == b'"' {
+                        self.key = serde_json::from_slice(&self.buffer)?;
+                        self.buffer.clear();
+                        self.state = State::Colon;
+                    }
+                },
+                State::Colon => match byte {
+                    b':' => self.state = State::Value,
+                    byte if byte.is_ascii_whitespace() => {},
+                    _ => return Err(self.error("expected a colon")),
+                },
+                State::Value => {
+                    if let Some(child) = self.value(byte)? {
+                        children.push(child);
+                    }
+                },
+                State::Done => if !byte.is_ascii_whitespace() {
+                    return Err(self.error("unexpected data after the document"));
+                },
+            }
+        }
+
+        Ok(children)
+    }
+
+    /// Read a byte of a child value, returning the child once its value is complete
+    fn value(&mut self, byte: u8) -> Result<Option<(String, Value)>, FirebaseError> {
+        if self.string {
+            self.buffer.push(byte);
+
+            if self.escape {
+                self.escape = false;
+            } else if byte == b'\\' {
+                self.escape = true;
+            } else if byte == b'"' {
+                self.string = false;
+            }
+            return Ok(None);
+        }
+
+        let close = match self.index {
+            Some(_) => b']',
+            None => b'}',
+        };
+
+        match byte {
+            b'"' => self.string = true,
+            b'{' | b'[' => self.depth += 1,
+            b'}' | b']' if self.depth > 0 => self.depth -= 1,
+            b',' | b'}' | b']' if self.depth == 0 => {
+                if byte != b',' && byte != close {
+                    return Err(self.error("mismatched closing bracket"));
+                }
+
+                let empty = self.buffer.iter().all(u8::is_ascii_whitespace);
+                self.state = match (byte, self.index) {
+                    (b',', Some(_)) => State::Value,
+                    (b',', None) => State::Key(false),
+                    _ => State::Done,
+                };
+
+                // Only an empty array closes without a value
+                if empty {
+                    return match (byte, self.index) {
+                        (b']', Some(0)) => Ok(None),
+                        _ => Err(self.error("expected a value")),
+                    };
+                }
+
+                let value: Value = serde_json::from_slice(&self.buffer)?;
+                self.buffer.clear();
+
+                let key = match &mut self.index {
+                    Some(index) => {
+                        *index += 1;
+                        (*index - 1).to_string()
+                    },
+                    None => std::mem::take(&mut self.key),
+                };
+
+                return Ok(Some((key, value)).filter(|(_, value)| !value.is_null()));
+            },
+            _ => {},
+        }
+
+        self.buffer.push(byte);
+        Ok(None)
+    }
+
+    /// Check that the whole document was read
+    ///
+    /// # Errors
+    /// Returns an error if the document was cut short
+    pub fn finish(&self) -> Result<(), FirebaseError> {
+        match self.state {
+            State::Done => Ok(()),
+            _ => Err(self.error("unexpected end of the document")),
+        }
+    }
+
+    fn error(&self, message: &str) -> FirebaseError {
+        FirebaseError::new(format!("Invalid document at byte {}: {}", self.offset, message))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn read(document: &str, size: usize) -> Result<Vec<(String, Value)>, FirebaseError> {
+        let mut reader = ChildReader::new();
+        let mut children = Vec::new();
+
+        for chunk in document.as_bytes().chunks(size) {
+            children.extend(reader.feed(chunk)?);
+        }
+
+        reader.finish()?;
+        Ok(children)
+    }
+
+    #[test]
+    fn test_object_children() {
+        let document = r#" {"alice": {"tags": ["a", "]}"], "age": 30}, "b\"ob" : "x,y" , "carol":1.5e3, "dave": null} "#;
+
+        for size in 1..document.len() {
+            assert_eq!(read(document, size).unwrap(), vec![
+                ("alice".to_string(), json!({"tags": ["a", "]}"], "age": 30})),
+                ("b\"ob".to_string(), json!("x,y")),
+                ("carol".to_string(), json!(1.5e3)),
+            ], "chunk size {}", size);
+        }
+    }
+
+    #[test]
+    fn test_array_and_empty_documents() {
+        assert_eq!(read("[true, null, {\"a\": []}]", 3).unwrap(), vec![
+            ("0".to_string(), json!(true)),
+            ("2".to_string(), json!({"a": []})),
+        ]);
+        assert_eq!(read("null", 1).unwrap(), vec![]);
+        assert_eq!(read("{}", 1).unwrap(), vec![]);
+        assert_eq!(read("[ ]", 1).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_invalid_documents() {
+        assert!(read("{\"a\": 1", 2).is_err());
+        assert!(read("{\"a\": 1]", 2).is_err());
+        assert!(read("{\"a\": }", 2).is_err());
+        assert!(read("[1,]", 2).is_err());
+        assert!(read("[,1]", 2).is_err());
+        assert!(read("{,\"a\": 1}", 2).is_err());
+        assert!(read("{\"a\": 1,}", 2).is_err());
+        assert!(read("{\"a\": 1,,\"b\": 2}", 2).is_err());
+        assert!(read("{,}", 2).is_err());
+        assert!(read("42", 2).is_err());
+        assert!(read("{} {}", 2).is_err());
+    }
+}
@@ -0,0 +1,676 @@
+//! # Example
+//!
+//! ```rust,no_run
+//! use firerust::{FirebaseClient, FirebaseError};
+//! use firerust::import::{ Importer, ImportFormat, ImportOptions };
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     let client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+//!     let file = tokio::fs::File::open("users.json").await?;
+//!
+//!     // Run again with the same file and options to resume after a crash
+//!     let options = ImportOptions::new().concurrency(8).checkpoint("users.checkpoint");
+//!     let stats = Importer::new(client.reference("/users"), options).import(file, ImportFormat::Json).await?;
+//!
+//!     println!("{} batches sent, {} already done", stats.batches(), stats.skipped());
+//!     Ok(())
+//! }
+//! ```
+
+
+use tokio::io::{ AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader };
+use crate::{ FirebaseError, MultiPathUpdate, RealtimeReference };
+use futures_util::stream::{ FuturesUnordered, StreamExt };
+use std::collections::{ HashMap, HashSet, VecDeque };
+use futures_util::FutureExt;
+use sha2::{ Digest, Sha256 };
+use std::io::{ Read as _, Write as _ };
+use crate::offline::{ retryable, RETRY_DELAY, MAX_RETRY_DELAY };
+use crate::download::ChildReader;
+use crate::registry::normalize;
+use crate::persist::hex;
+use crate::connector::Method;
+use std::path::{ Path, PathBuf };
+use std::fs::{ File, OpenOptions };
+use serde_json::Value;
+
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+
+/// Layout of the imported data
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImportFormat {
+    /// A single JSON document, its children become the children of the reference
+    Json,
+    /// One JSON object per line, the children of every object become children of the reference
+    ///
+    /// A key can only appear on one line
+    Ndjson,
+}
+
+
+/// Limits of an import
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImportOptions {
+    max_payload: usize,
+    max_depth: usize,
+    concurrency: usize,
+    retries: u32,
+    checkpoint: Option<PathBuf>,
+}
+
+impl Default for ImportOptions {
+    fn default() -> ImportOptions {
+        ImportOptions {
+            max_payload: 1024 * 1024,
+            max_depth: 32,
+            concurrency: 4,
+            retries: 5,
+            checkpoint: None,
+        }
+    }
+}
+
+impl ImportOptions {
+
+    /// Send batches of at most 1 MiB, 4 at a time, retrying each one 5 times
+    pub fn new() -> ImportOptions {
+        ImportOptions::default()
+    }
+
+    /// Set the maximum size in bytes of the body of a batch
+    pub fn max_payload(mut self, bytes: usize) -> ImportOptions {
+        self.max_payload = bytes;
+        self
+    }
+
+    /// Set the maximum depth of the imported values, counted from the reference
+    pub fn max_depth(mut self, depth: usize) -> ImportOptions {
+        self.max_depth = depth;
+        self
+    }
+
+    /// Set the number of batches sent at the same time
+    pub fn concurrency(mut self, batches: usize) -> ImportOptions {
+        self.concurrency = batches.max(1);
+        self
+    }
+
+    /// Set the number of times a failed batch is sent again before the import stops
+    pub fn retries(mut self, retries: u32) -> ImportOptions {
+        self.retries = retries;
+        self
+    }
+
+    /// Record the batches sent in the given file, so an interrupted import skips them when run again
+    ///
+    /// Only batches holding the same writes are skipped, and a file written for another location,
+    /// other data or other limits is refused. The file is removed once the import completes.
+    pub fn checkpoint(mut self, path: impl AsRef<Path>) -> ImportOptions {
+        self.checkpoint = Some(path.as_ref().to_path_buf());
+        self
+    }
+}
+
+
+/// Outcome of an import
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ImportStats {
+    batches: u64,
+    skipped: u64,
+    children: u64,
+}
+
+impl ImportStats {
+
+    /// Get the number of batches sent
+    pub fn batches(&self) -> u64 {
+        self.batches
+    }
+
+    /// Get the number of batches skipped because the checkpoint records them as sent
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// Get the number of top-level children read
+    pub fn children(&self) -> u64 {
+        self.children
+    }
+}
+
+
+/// Writes of a single multi-path update
+#[derive(Debug)]
+struct Batch {
+    id: u64,
+    writes: Vec<(String, Value)>,
+}
+
+impl Batch {
+
+    /// Hash of the writes, so a checkpoint only skips a batch holding the same writes
+    fn digest(&self) -> String {
+        let mut hasher = Sha256::new();
+        for (path, value) in &self.writes {
+            hasher.update(path.as_bytes());
+            hasher.update(value.to_string().as_bytes());
+        }
+
+        hex(&hasher.finalize())
+    }
+}
+
+/// Groups writes into batches of bounded size, splitting values too large for a single batch
+struct Batcher {
+    max_payload: usize,
+    max_depth: usize,
+    writes: Vec<(String, Value)>,
+    size: usize,
+    next_id: u64,
+    ready: VecDeque<Batch>,
+}
+
+impl Batcher {
+
+    fn new(options: &ImportOptions) -> Batcher {
+        Batcher {
+            max_payload: options.max_payload,
+            max_depth: options.max_depth,
+            writes: Vec::new(),
+            size: 0,
+            next_id: 0,
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Add the write of a value, split into writes of its children if it does not fit in a batch
+    fn push(&mut self, path: String, value: Value) -> Result<(), FirebaseError> {
+        let depth = path.split('/').count() + depth(&value);
+        if depth > self.max_depth {
+            return Err(FirebaseError::new(format!("Import: {} is {} levels deep, the limit is {}", path, depth, self.max_depth)));
+        }
+
+        // The key, its quotes, the colon and the comma
+        let size = serde_json::to_string(&value)?.len() + path.len() + 4;
+
+        if size > self.max_payload {
+            let children: Vec<(String, Value)> = match value {
+                Value::Object(map) if !map.is_empty() => map.into_iter().collect(),
+                Value::Array(items) if !items.is_empty() => items.into_iter().enumerate().map(|(i, v)| (i.to_string(), v)).collect(),
+                _ => return Err(FirebaseError::new(format!("Import: {} is larger than a batch", path))),
+            };
+
+            for (key, child) in children {
+                self.push(format!("{}/{}", path, key), child)?;
+            }
+            return Ok(());
+        }
+
+        if self.size + size > self.max_payload {
+            self.flush();
+        }
+
+        self.size += size;
+        self.writes.push((path, value));
+        Ok(())
+    }
+
+    /// Close the current batch
+    fn flush(&mut self) {
+        if self.writes.is_empty() {
+            return;
+        }
+
+        self.ready.push_back(Batch {
+            id: self.next_id,
+            writes: std::mem::take(&mut self.writes),
+        });
+        self.next_id += 1;
+        self.size = 0;
+    }
+}
+
+
+/// Batches already sent by a previous run, appended to as batches complete
+///
+/// The first line is the fingerprint of the import, each other line the id and digest of a batch.
+struct Checkpoint {
+    path: Option<PathBuf>,
+    file: Option<File>,
+    done: HashMap<u64, String>,
+}
+
+impl Checkpoint {
+
+    fn open(path: Option<&Path>, fingerprint: &str) -> Result<Checkpoint, FirebaseError> {
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(Checkpoint { path: None, file: None, done: HashMap::new() }),
+        };
+
+        let mut file = OpenOptions::new().create(true).read(true).append(true).open(path).map_err(io_error)?;
+        let mut content = String::new();
+        file.read_to_string(&mut content).map_err(io_error)?;
+
+        // A line cut short by a crash is dropped, its batch is sent again
+        let complete = content.rfind('\n').map_or(0, |end| end + 1);
+        if complete < content.len() {
+            file.set_len(complete as u64).map_err(io_error)?;
+        }
+
+        let mut lines = content[..complete].lines();
+        match lines.next() {
+            Some(line) if line == fingerprint => {},
+            Some(_) => return Err(FirebaseError::new(format!("Import: the checkpoint {} was written for other data or options", path.display()))),
+            None => file.write_all(format!("{}\n", fingerprint).as_bytes()).and_then(|_| file.sync_data()).map_err(io_error)?,
+        }
+
+        let done = lines
+            .filter_map(|line| line.split_once(' '))
+            .filter_map(|(id, digest)| Some((id.parse().ok()?, digest.to_string())))
+            .collect();
+
+        Ok(Checkpoint {
+            path: Some(path.to_path_buf()),
+            file: Some(file),
+            done,
+        })
+    }
+
+    /// Returns true if a previous run sent the batch
+    fn sent(&self, batch: &Batch, digest: &str) -> bool {
+        self.done.get(&batch.id).is_some_and(|done| done == digest)
+    }
+
+    /// Append a sent batch, the file is written and synced off the executor
+    async fn record(&mut self, id: u64, digest: String) -> Result<(), FirebaseError> {
+        if let Some(mut file) = self.file.take() {
+            let line = format!("{} {}\n", id, digest);
+            let (file, written) = tokio::task::spawn_blocking(move || {
+                let written = file.write_all(line.as_bytes()).and_then(|_| file.sync_data());
+                (file, written)
+            }).await.map_err(|e| FirebaseError::new(format!("Import: {}", e)))?;
+
+            self.file = Some(file);
+            written.map_err(io_error)?;
+        }
+
+        self.done.insert(id, digest);
+        Ok(())
+    }
+
+    fn remove(self) -> Result<(), FirebaseError> {
+        drop(self.file);
+
+        match self.path {
+            Some(path) => std::fs::remove_file(path).map_err(io_error),
+            None => Ok(()),
+        }
+    }
+}
+
+
+/// Uploads large datasets below a reference as a series of bounded multi-path updates
+///
+/// Every batch sets the values it holds, so sending a batch again is harmless. Values too large
+/// for a batch are written child by child, which keeps the children of the existing value that
+/// the imported value does not have. Batches are sent straight to the server, even when offline
+/// mode is enabled, and only sent again when the server can not be reached or is overloaded.
+#[derive(Clone)]
+pub struct Importer<'a> {
+    reference: RealtimeReference<'a>,
+    options: ImportOptions,
+}
+
+impl<'a> Importer<'a> {
+
+    /// Create an importer writing below the given reference
+    pub fn new(reference: RealtimeReference<'a>, options: ImportOptions) -> Importer<'a> {
+        Importer {
+            reference,
+            options,
+        }
+    }
+
+    /// Read the data and send it in batches, returning once every batch was applied
+    ///
+    /// # Errors
+    /// Returns an error if the data is invalid, holds a top-level key twice or exceeds the limits
+    /// of the options, if the server rejects a batch or it still fails after its retries, or if
+    /// the checkpoint was written for other data or can not be written. The batches sent before
+    /// the error are kept in the checkpoint.
+    pub async fn import<R>(&self, mut reader: R, format: ImportFormat) -> Result<ImportStats, FirebaseError> where R: AsyncRead + Unpin {
+        let mut first = Vec::with_capacity(CHUNK_SIZE);
+        (&mut reader).take(CHUNK_SIZE as u64).read_to_end(&mut first).await.map_err(io_error)?;
+
+        let mut checkpoint = Checkpoint::open(self.options.checkpoint.as_deref(), &self.fingerprint(format, &first))?;
+        let mut batcher = Batcher::new(&self.options);
+        let mut stats = ImportStats::default();
+        let mut sending = FuturesUnordered::new();
+        let mut source = Source::new(AsyncReadExt::chain(std::io::Cursor::new(first), reader), format);
+        let mut end = false;
+
+        loop {
+            while sending.len() < self.options.concurrency {
+                let batch = match batcher.ready.pop_front() {
+                    Some(batch) => batch,
+                    None => break,
+                };
+
+                let digest = batch.digest();
+                if checkpoint.sent(&batch, &digest) {
+                    stats.skipped += 1;
+                    continue;
+                }
+
+                stats.batches += 1;
+                sending.push(self.send(batch).map(|sent| sent.map(|id| (id, digest))));
+            }
+
+            if end && batcher.ready.is_empty() && sending.is_empty() {
+                break;
+            }
+
+            // The next children are read while the batches are sent, once every batch has started
+            tokio::select! {
+                children = source.next(), if !end && batcher.ready.is_empty() => {
+                    match children? {
+                        Some(children) => for (key, value) in children {
+                            stats.children += 1;
+                            batcher.push(key, value)?;
+                        },
+                        None => {
+                            end = true;
+                            batcher.flush();
+                        },
+                    }
+                },
+                Some(sent) = sending.next() => {
+                    let (id, digest) = sent?;
+                    checkpoint.record(id, digest).await?;
+                },
+            }
+        }
+
+        checkpoint.remove()?;
+        Ok(stats)
+    }
+
+    /// Identify the import in its checkpoint by its destination, its limits and the start of its data
+    fn fingerprint(&self, format: ImportFormat, first: &[u8]) -> String {
+        format!(
+            "firerust-import {}/{} {:?} max_payload={} max_depth={} first_chunk={}",
+            self.reference.client.connector.base_url(), normalize(&self.reference.path), format,
+            self.options.max_payload, self.options.max_depth, hex(&Sha256::digest(first)),
+        )
+    }
+
+    /// Send a batch, retrying with a growing delay when the server can not be reached or is overloaded
+    async fn send(&self, batch: Batch) -> Result<u64, FirebaseError> {
+        let update = batch.writes.into_iter().fold(MultiPathUpdate::new(), |update, (path, value)| update.set(&path, value));
+        let (ancestor, body) = update.build().map_err(|e| failed(batch.id, e))?;
+        let body = body.to_string();
+
+        let reference = match ancestor.is_empty() {
+            true => self.reference.clone(),
+            false => self.reference.child(&ancestor),
+        };
+
+        let mut delay = RETRY_DELAY;
+        let mut attempt = 0;
+
+        loop {
+            let error = match reference.send_write(Method::Patch, Some(&body)).await {
+                Ok(response) => match response.status().code() {
+                    200 | 204 => return Ok(batch.id),
+                    code => {
+                        let error = FirebaseError::new(format!("{} {}", code, response.status().message()));
+                        if !retryable(code) {
+                            return Err(failed(batch.id, error));
+                        }
+                        error
+                    },
+                },
+                Err(e) => e,
+            };
+
+            if attempt >= self.options.retries {
+                return Err(failed(batch.id, error));
+            }
+
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+            attempt += 1;
+        }
+    }
+}
+
+
+/// Reads the top-level children of the data, a chunk or a line at a time
+struct Source<R> {
+    reader: BufReader<R>,
+    format: ImportFormat,
+    json: ChildReader,
+    line: Vec<u8>,
+    /// Keys read so far, a key read twice would be written by two batches in no particular order
+    keys: HashSet<String>,
+}
+
+impl<R> Source<R> where R: AsyncRead + Unpin {
+
+    fn new(reader: R, format: ImportFormat) -> Source<R> {
+        Source {
+            reader: BufReader::new(reader),
+            format,
+            json: ChildReader::new(),
+            line: Vec::new(),
+            keys: HashSet::new(),
+        }
+    }
+
+    /// Read the next children, `None` at the end of the data
+    ///
+    /// Cancelling the read loses no data, the next call carries on where it stopped
+    ///
+    /// # Errors
+    /// Returns an error if the data is invalid or holds a top-level key twice
+    async fn next(&mut self) -> Result<Option<Vec<(String, Value)>>, FirebaseError> {
+        let children = match self.read().await? {
+            Some(children) => children,
+            None => return Ok(None),
+        };
+
+        if let Some((key, _)) = children.iter().find(|(key, _)| !self.keys.insert(key.clone())) {
+            return Err(FirebaseError::new(format!("Import: the key {} appears more than once", key)));
+        }
+
+        Ok(Some(children))
+    }
+
+    async fn read(&mut self) -> Result<Option<Vec<(String, Value)>>, FirebaseError> {
+        match self.format {
+            ImportFormat::Json => {
+                let mut chunk = vec![0; CHUNK_SIZE];
+                let read = self.reader.read(&mut chunk).await.map_err(io_error)?;
+
+                if read == 0 {
+                    self.json.finish()?;
+                    return Ok(None);
+                }
+
+                self.json.feed(&chunk[..read]).map(Some)
+            },
+            ImportFormat::Ndjson => {
+                // A read cancelled halfway keeps its bytes in the line, the next read completes it
+                if self.reader.read_until(b'\n', &mut self.line).await.map_err(io_error)? == 0 && self.line.is_empty() {
+                    return Ok(None);
+                }
+
+                let line = std::mem::take(&mut self.line);
+                match serde_json::from_slice::<Value>(&line) {
+                    _ if line.iter().all(u8::is_ascii_whitespace) => Ok(Some(Vec::new())),
+                    Ok(Value::Object(map)) => Ok(Some(map.into_iter().filter(|(_, value)| !value.is_null()).collect())),
+                    Ok(_) => Err(FirebaseError::new("Import: every line must be a JSON object")),
+                    Err(e) => Err(e.into()),
+                }
+            },
+        }
+    }
+}
+
+
+fn failed(id: u64, error: FirebaseError) -> FirebaseError {
+    FirebaseError::new(format!("Import: batch {} failed: {}", id, error))
+}
+
+/// Get the number of nested levels of a value, zero for a primitive
+pub(crate) fn depth(value: &Value) -> usize {
+    match value {
+        Value::Object(map) => 1 + map.values().map(depth).max().unwrap_or(0),
+        Value::Array(items) => 1 + items.iter().map(depth).max().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+fn io_error(error: std::io::Error) -> FirebaseError {
+    FirebaseError::new(format!("Import: {}", error))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn batches(options: ImportOptions, children: Vec<(&str, Value)>) -> Result<Vec<Vec<(String, Value)>>, FirebaseError> {
+        let mut batcher = Batcher::new(&options);
+        for (key, value) in children {
+            batcher.push(key.to_string(), value)?;
+        }
+        batcher.flush();
+
+        Ok(batcher.ready.into_iter().map(|batch| batch.writes).collect())
+    }
+
+    #[test]
+    fn test_batches_are_bounded() {
+        let options = ImportOptions::new().max_payload(40);
+        let batches = batches(options, vec![
+            ("a", json!("0123456789")),
+            ("b", json!("0123456789")),
+            ("c", json!({"x": "0123456789", "y": "0123456789", "z": 1})),
+        ]).unwrap();
+
+        assert_eq!(batches, vec![
+            vec![("a".to_string(), json!("0123456789")), ("b".to_string(), json!("0123456789"))],
+            vec![("c/x".to_string(), json!("0123456789")), ("c/y".to_string(), json!("0123456789"))],
+            vec![("c/z".to_string(), json!(1))],
+        ]);
+    }
+
+    #[test]
+    fn test_limits() {
+        assert!(batches(ImportOptions::new().max_payload(10), vec![("a", json!("0123456789"))]).is_err());
+        assert!(batches(ImportOptions::new().max_depth(3), vec![("a", json!({"b": {"c": 1}}))]).is_ok());
+        assert!(batches(ImportOptions::new().max_depth(3), vec![("a", json!({"b": {"c": {"d": 1}}}))]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint_reopen() {
+        let path = std::env::temp_dir().join(format!("firerust-import-{}.checkpoint", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let batch = |id| Batch { id, writes: vec![("a".to_string(), json!(id))] };
+
+        let mut checkpoint = Checkpoint::open(Some(&path), "fingerprint").unwrap();
+        checkpoint.record(0, batch(0).digest()).await.unwrap();
+        checkpoint.record(2, batch(2).digest()).await.unwrap();
+        drop(checkpoint);
+
+        std::fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b"3 ab").unwrap();
+        let checkpoint = Checkpoint::open(Some(&path), "fingerprint").unwrap();
+        assert_eq!(checkpoint.done.keys().collect::<HashSet<_>>(), HashSet::from([&0, &2]));
+        assert!(checkpoint.sent(&batch(0), &batch(0).digest()));
+        assert!(!checkpoint.sent(&batch(1), &batch(1).digest()));
+
+        // The same id holding other writes is sent again
+        let other = Batch { id: 2, writes: vec![("b".to_string(), json!(2))] };
+        assert!(!checkpoint.sent(&other, &other.digest()));
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 3);
+        drop(checkpoint);
+
+        assert!(Checkpoint::open(Some(&path), "other fingerprint").is_err());
+
+        let checkpoint = Checkpoint::open(Some(&path), "fingerprint").unwrap();
+        checkpoint.remove().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_read_keeps_the_line() {
+        let (mut writer, reader) = tokio::io::duplex(64);
+        let mut source = Source::new(reader, ImportFormat::Ndjson);
+
+        tokio::io::AsyncWriteExt::write_all(&mut writer, b"{\"a\": ").await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(20), source.next()).await.is_err());
+
+        tokio::io::AsyncWriteExt::write_all(&mut writer, b"1}\n").await.unwrap();
+        assert_eq!(source.next().await.unwrap(), Some(vec![("a".to_string(), json!(1))]));
+
+        drop(writer);
+        assert_eq!(source.next().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_ndjson_source() {
+        let data: &[u8] = b"{\"a\": 1, \"b\": null}\n\n{\"c\": {\"d\": 2}}\n";
+        let mut source = Source::new(data, ImportFormat::Ndjson);
+        let mut children = Vec::new();
+
+        while let Some(read) = source.next().await.unwrap() {
+            children.extend(read);
+        }
+        assert_eq!(children, vec![("a".to_string(), json!(1)), ("c".to_string(), json!({"d": 2}))]);
+
+        let mut source = Source::new(&b"[1, 2]\n"[..], ImportFormat::Ndjson);
+        assert!(source.next().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_repeated_keys() {
+        let data: &[u8] = b"{\"a\": 1, \"b\": null}\n{\"b\": 2}\n{\"a\": 3}\n";
+        let mut source = Source::new(data, ImportFormat::Ndjson);
+
+        assert!(source.next().await.is_ok());
+        assert!(source.next().await.is_ok());
+        assert_eq!(source.next().await.unwrap_err().to_string(), "Import: the key a appears more than once");
+
+        let mut source = Source::new(&b"{\"a\": 1, \"a\": 2}"[..], ImportFormat::Json);
+        assert!(source.next().await.is_err());
+    }
+
+    #[test]
+    fn test_fingerprint_names_the_destination() {
+        let client = crate::FirebaseClient::new("https://firerust-test.firebaseio.com/").unwrap();
+        let other = crate::FirebaseClient::new("https://firerust-other.firebaseio.com/").unwrap();
+        let fingerprint = |reference| Importer::new(reference, ImportOptions::new()).fingerprint(ImportFormat::Json, b"{}");
+
+        assert_eq!(fingerprint(client.reference("/users")), fingerprint(client.reference("users/")));
+        assert_ne!(fingerprint(client.reference("/users")), fingerprint(client.reference("/posts")));
+        assert_ne!(fingerprint(client.reference("/users")), fingerprint(other.reference("/users")));
+    }
+
+    #[tokio::test]
+    async fn test_local_errors_are_not_retried() {
+        let client = crate::FirebaseClient::new("https://firerust-test.firebaseio.com/").unwrap();
+        let importer = Importer::new(client.reference("/"), ImportOptions::new().retries(5));
+        let batch = Batch { id: 7, writes: vec![("a".to_string(), json!(1)), ("a/b".to_string(), json!(2))] };
+
+        let error = tokio::time::timeout(Duration::from_millis(500), importer.send(batch)).await.unwrap().unwrap_err();
+        assert!(error.to_string().starts_with("Import: batch 7 failed"));
+    }
+}
@@ -29,11 +29,20 @@
 //! ```
 
 
-use connector::{ Connector, Method, EventStream , EventType };
+use connector::{ Connector, Method };
+use registry::{ ListenerRegistry, Subscriber, Prepare };
+use offline::OfflineQueue;
+use cache::{ ReadCache, ReadCacheOptions, CacheStats, EtagCache, ETAG_CAPACITY };
+use persist::{ SnapshotStore, PersistenceOptions };
+use download::{ ChildReader, Progress };
+use futures_util::{ Stream, StreamExt };
+use listener::{ ChangeSet, CallbackQueue, ListenerOptions, Overflow };
 use std::fmt::{ Display, Formatter };
 use serde::de::DeserializeOwned;
-use std::sync::{ Arc, Mutex };
-use tokio::task::JoinHandle;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
 use std::error::Error;
 use serde_json::Value;
 use serde::Serialize;
@@ -43,12 +52,73 @@ use url::Url;
 /// TLS Connector for Firebase client
 pub mod connector;
 
+/// Immutable snapshots of database locations
+pub mod snapshot;
+
+/// Ordered queries over database locations
+pub mod query;
+
+/// Firebase ordering rules for keys and values
+pub mod ordering;
+
+/// Atomic writes to several locations
+pub mod update;
+
+/// Firebase semantics for writes to local trees
+pub mod tree;
+
+/// Local state of stream listeners
+pub mod listener;
+
+/// Streams shared between listeners
+mod registry;
+
+/// Durable queue of writes made while offline
+pub mod offline;
+
+/// Local cache serving reads
+pub mod cache;
+
+/// Listener caches saved to disk
+pub mod persist;
+
+/// Coalescing of frequent writes
+pub mod buffer;
+
+/// Streaming reads of large locations
+pub mod download;
+
+/// Bulk upload of large datasets
+pub mod import;
+
+/// Compressed backups of locations and their restore
+pub mod backup;
+
+/// Size analysis of locations
+pub mod usage;
+
+pub use snapshot::{ DataSnapshot, ChildEvent };
+pub use query::{ Query, OrderBy };
+pub use ordering::FirebaseOrdering;
+pub use update::MultiPathUpdate;
+pub use buffer::BufferedWriter;
+pub use offline::PendingWrite;
+pub use cache::Versioned;
+pub use listener::{ ListenerHandle, ListenerStatus, LiveValue };
+
 
 /// Connects and authenticates client to Firebase
 #[derive(Clone)]
 pub struct FirebaseClient {
     connector: Connector,
     api_key: Option<String>,
+    shutdown: Arc<watch::Sender<bool>>,
+    registry: Arc<ListenerRegistry>,
+    idle_timeout: Duration,
+    offline: Option<Arc<OfflineQueue>>,
+    read_cache: Option<Arc<ReadCache>>,
+    persistence: Option<Arc<SnapshotStore>>,
+    etags: Option<Arc<EtagCache>>,
 }
 
 
@@ -57,6 +127,11 @@ impl std::fmt::Debug for FirebaseClient {
         f.debug_struct("FirebaseClient")
             .field("connector", &self.connector)
             .field("api_key", &self.api_key.as_ref().map(|_| "[REDACTED]"))
+            .field("idle_timeout", &self.idle_timeout)
+            .field("offline", &self.offline.is_some())
+            .field("read_cache", &self.read_cache.is_some())
+            .field("persistence", &self.persistence.is_some())
+            .field("etags", &self.etags.is_some())
             .finish()
     }
 }
@@ -92,15 +167,19 @@ impl FirebaseClient {
             None => return Err(FirebaseError::new("Invalid domain"))
         };
 
-        let port = match url.port_or_known_default() {
-            Some(port) => port,
-            None => 443
-        };
+        let port = url.port_or_known_default().unwrap_or(443);
 
 
         Ok(FirebaseClient {
             api_key: None,
-            connector: Connector::new(domain, port)?
+            connector: Connector::new(domain, port)?,
+            shutdown: Arc::new(watch::channel(false).0),
+            registry: Arc::new(ListenerRegistry::default()),
+            idle_timeout: Duration::from_secs(90),
+            offline: None,
+            read_cache: None,
+            persistence: None,
+            etags: None,
         })
     }
 
@@ -120,6 +199,203 @@ impl FirebaseClient {
         self.api_key = Some(api_key.to_string());
     }
 
+    /// Sets how long a stream may stay silent before it is considered dead and opened again
+    /// 
+    /// The server sends a keep-alive about every 30 seconds, the default is 90 seconds.
+    /// Applies to streams opened after the call.
+    /// 
+    /// # Example
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use firerust::FirebaseClient;
+    /// use std::time::Duration;
+    /// 
+    /// let mut client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+    /// client.idle_timeout(Duration::from_secs(45));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn idle_timeout(&mut self, timeout: Duration) {
+        self.idle_timeout = timeout;
+    }
+
+    /// Queues the writes of the client in a durable journal instead of sending them directly
+    /// 
+    /// Writes are synced to the journal, applied to the local cache of the listeners and sent in order,
+    /// waiting for connectivity to come back when the server can not be reached. Writes left in the
+    /// journal by a previous run are sent first. Call it after [`FirebaseClient::auth`], the writes
+    /// are sent with the credentials set at this point.
+    /// 
+    /// A write the server rejects fails its [`PendingWrite`], and the listeners it was applied to
+    /// reload their location from the server to drop it.
+    /// 
+    /// Once enabled, `set`, `update` and `delete` wait until the server has applied the write, use
+    /// [`RealtimeReference::queue_set`] and the other `queue_` methods to get a [`PendingWrite`] instead.
+    /// 
+    /// # Example
+    /// ```rust,no_run
+    /// # use firerust::{FirebaseClient, FirebaseError};
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), FirebaseError> {
+    ///     let mut client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+    ///     client.enable_offline("firerust.journal").await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    /// 
+    /// # Errors
+    /// Returns an error if the journal can not be opened
+    pub async fn enable_offline(&mut self, journal: impl AsRef<std::path::Path>) -> Result<(), FirebaseError> {
+        let journal = journal.as_ref().to_path_buf();
+        let queue = tokio::task::spawn_blocking(move || OfflineQueue::open(journal)).await
+            .map_err(|e| FirebaseError::new(format!("Offline journal: {}", e)))?;
+        let queue = Arc::new(queue?);
+
+        tokio::spawn(queue.clone().run(self.connector.clone(), self.api_key.clone(), self.registry.clone(), self.shutdown.subscribe()));
+        self.offline = Some(queue);
+
+        Ok(())
+    }
+
+    /// Get the number of queued writes the server has not applied yet, zero without offline mode
+    pub fn pending_writes(&self) -> usize {
+        self.offline.as_ref().map_or(0, |queue| queue.len())
+    }
+
+    /// Serves `get` from a local cache instead of the server when possible
+    /// 
+    /// Reads at or below a location with a connected listener, started by the cache for the
+    /// configured paths or by the application, are served from the listener. Other reads go to the
+    /// server and are kept for the time to live of the options, writes made through the client
+    /// drop the values they change.
+    /// 
+    /// # Example
+    /// ```rust,no_run
+    /// # use firerust::{FirebaseClient, FirebaseError};
+    /// use firerust::cache::ReadCacheOptions;
+    /// 
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), FirebaseError> {
+    ///     let mut client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+    ///     client.enable_read_cache(ReadCacheOptions::new().listen("/config")).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    /// 
+    /// # Errors
+    /// Returns an error if a listener on the configured paths could not be started
+    pub async fn enable_read_cache(&mut self, options: ReadCacheOptions) -> Result<(), FirebaseError> {
+        let mut listeners = Vec::with_capacity(options.paths().len());
+
+        for path in options.paths() {
+            listeners.push(self.reference(path).on_change(|_, _| Ok(()), |_| {}).await?);
+        }
+
+        self.read_cache = Some(Arc::new(ReadCache::new(&options, listeners)));
+        Ok(())
+    }
+
+    /// Get the hit and miss counters of the read cache, `None` without a read cache
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.read_cache.as_ref().map(|cache| cache.stats())
+    }
+
+    /// Sends reads with the ETag of the value read before, so unchanged values are not downloaded again
+    /// 
+    /// The last value and ETag read from up to 1000 locations are kept in memory, the least recently
+    /// read ones are dropped first and writes made through the client drop the values they change.
+    /// See [`RealtimeReference::get_versioned`] to know if a value changed since the previous read.
+    /// 
+    /// # Example
+    /// ```rust,no_run
+    /// # use firerust::{FirebaseClient, FirebaseError};
+    /// # use serde_json::Value;
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), FirebaseError> {
+    ///     let mut client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+    ///     client.enable_etag_cache();
+    /// 
+    ///     let config = client.reference("/config").get::<Value>().await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn enable_etag_cache(&mut self) {
+        self.etags = Some(Arc::new(EtagCache::new(ETAG_CAPACITY)));
+    }
+
+    /// Saves the caches of listeners to disk and serves them to new listeners before the server answers
+    /// 
+    /// A listener on a location saved by a previous run gets the saved value at once, even when the
+    /// server can not be reached, and reports [`ListenerStatus::Stale`] until the server sends its
+    /// first snapshot. The listener is only called again if that snapshot differs from the saved one.
+    /// Only listeners started after this call are saved.
+    /// 
+    /// # Example
+    /// ```rust,no_run
+    /// # use firerust::{FirebaseClient, FirebaseError};
+    /// use firerust::persist::PersistenceOptions;
+    /// 
+    /// # fn main() -> Result<(), FirebaseError> {
+    ///     let mut client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+    ///     client.enable_persistence(PersistenceOptions::new("snapshots"))?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    /// 
+    /// # Errors
+    /// Returns an error if the directory of the options can not be created
+    pub fn enable_persistence(&mut self, options: PersistenceOptions) -> Result<(), FirebaseError> {
+        self.persistence = Some(Arc::new(SnapshotStore::new(options)?));
+        Ok(())
+    }
+
+    /// Stops every listener started from this client or its clones and waits for them to finish
+    /// 
+    /// Updates already queued for a callback are still delivered before its listener finishes.
+    /// Listeners started after the shutdown stop immediately
+    /// 
+    /// # Example
+    /// ```rust,no_run
+    /// # use firerust::{FirebaseClient, FirebaseError};
+    /// # use serde_json::Value;
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), FirebaseError> {
+    ///     let client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+    ///     let listener = client.reference("/").on_snapshot(|_: Value| Ok(()), |_| {}).await?;
+    ///
+    ///     client.shutdown().await;
+    ///     assert!(listener.is_closed());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+        self.shutdown.closed().await;
+    }
+
+    /// Get the number of streams open for the listeners of this client and its clones
+    /// 
+    /// Listeners of the same location, or of a location below an already listened one, share a
+    /// stream, which is closed when its last listener stops
+    /// 
+    /// # Example
+    /// ```rust,no_run
+    /// # use firerust::{FirebaseClient, FirebaseError};
+    /// # use serde_json::Value;
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), FirebaseError> {
+    ///     let client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+    ///     let users = client.reference("/users").on_snapshot(|_: Value| Ok(()), |_| {}).await?;
+    ///     let alice = client.reference("/users/alice").on_snapshot(|_: Value| Ok(()), |_| {}).await?;
+    ///
+    ///     assert_eq!(client.open_streams(), 1);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn open_streams(&self) -> usize {
+        self.registry.len()
+    }
+
     /// Creates a new reference to the given path
     /// 
     /// # Example
@@ -139,30 +415,79 @@ impl FirebaseClient {
 
 
 /// A reference to a Firebase real-time database
+#[derive(Clone)]
 pub struct RealtimeReference<'a> {
     client: &'a FirebaseClient,
     path: String,
+    options: ListenerOptions,
 }
 
 impl<'a> RealtimeReference<'a> {
 
     async fn write_request(&self, method: Method, data: Option<&str>) -> Result<Option<String>, FirebaseError> {
+        if self.client.offline.is_some() && matches!(method, Method::Put | Method::Patch | Method::Delete) {
+            let data = data.map(serde_json::from_str).transpose()?;
+            self.queue_write(method, data).await?.await?;
+            return Ok(None);
+        }
+
+        let response = self.send_write(method, data).await?;
+
+        let code = response.status().code();
+        if code != 200 && code != 204 {
+            return Err(FirebaseError::new(format!("{} {}", code, response.status().message())));
+        }
+
+        Ok(Some(response.body().to_string()))
+    }
+
+    /// Send a write straight to the server, returning its response whatever its status
+    pub(crate) async fn send_write(&self, method: Method, data: Option<&str>) -> Result<connector::Response, FirebaseError> {
+        if let Some(cache) = &self.client.read_cache {
+            cache.invalidate(&self.path);
+        }
+
+        if let Some(etags) = &self.client.etags {
+            etags.invalidate(&self.path);
+        }
+
         let params = "?print=silent";
-        
-        let response = self.client.connector.request(
+
+        Ok(self.client.connector.request(
             method,
             &self.path,
             Some(params),
             data,
             self.client.api_key.as_deref()
-        ).await?;
+        ).await?)
+    }
 
-        let code = response.status().code();
-        if code != 200 && code != 204 {
-            return Err(FirebaseError::new(format!("{} {}", code, response.status().message())));
+    /// Append a write to the offline journal and apply it to the local cache of the listeners
+    async fn queue_write(&self, method: Method, data: Option<Value>) -> Result<PendingWrite, FirebaseError> {
+        let queue = match &self.client.offline {
+            Some(queue) => queue,
+            None => return Err(FirebaseError::new("Offline mode is not enabled")),
+        };
+
+        if let Some(cache) = &self.client.read_cache {
+            cache.invalidate(&self.path);
         }
 
-        Ok(Some(response.body().to_string()))
+        if let Some(etags) = &self.client.etags {
+            etags.invalidate(&self.path);
+        }
+        self.client.connector.invalidate_reads(&self.path);
+
+        let merge = matches!(method, Method::Patch);
+        let local = data.clone().unwrap_or(Value::Null);
+        let pending = queue.push(method, &self.path, data).await?;
+
+        // Server values are only known once the server applied the write
+        if !has_server_value(&local) {
+            self.client.registry.apply_local(&self.path, &local, merge);
+        }
+
+        Ok(pending)
     }
 
     /// Creates a new instance of RealtimeReference with the given path
@@ -170,9 +495,16 @@ impl<'a> RealtimeReference<'a> {
         RealtimeReference {
             client,
             path: path.to_string(),
+            options: ListenerOptions::default(),
         }
     }
 
+    /// Set how the callbacks of listeners started from this reference are run
+    pub fn with_listener_options(mut self, options: ListenerOptions) -> RealtimeReference<'a> {
+        self.options = options;
+        self
+    }
+
     /// Set reference from the child path
     /// 
     /// # Example
@@ -187,7 +519,23 @@ impl<'a> RealtimeReference<'a> {
     /// # }
     /// ```
     pub fn child(&self, path: &str) -> RealtimeReference<'a> {
-        RealtimeReference::new(self.client, format!("{}/{}", self.path, path))
+        RealtimeReference::new(self.client, format!("{}/{}", self.path, path)).with_listener_options(self.options.clone())
+    }
+
+    /// Get the last segment of the reference path, `None` for the root
+    /// 
+    /// # Example
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use firerust::FirebaseClient;
+    /// 
+    /// let client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+    /// assert_eq!(client.reference("/users/alice").key(), Some("alice"));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn key(&self) -> Option<&str> {
+        self.path.split('/').rfind(|s| !s.is_empty())
     }
 
     /// Get the value of the reference
@@ -207,6 +555,14 @@ impl<'a> RealtimeReference<'a> {
     /// # Errors
     /// Returns an error if the value is not a valid Response
     pub async fn get<T>(&self) -> Result<T, FirebaseError> where T: DeserializeOwned {
+        if let Some(cache) = &self.client.read_cache {
+            return Ok(T::deserialize(self.get_cached(cache).await?)?);
+        }
+
+        if self.client.etags.is_some() {
+            return Ok(T::deserialize(self.get_versioned::<Value>().await?.into_value())?);
+        }
+
         let response = self.client.connector.request(Method::Get, &self.path, None, None, self.client.api_key.as_deref()).await?;
 
         if response.status().code() != 200 {
@@ -216,6 +572,270 @@ impl<'a> RealtimeReference<'a> {
         Ok(serde_json::from_str(response.body())?)
     }
 
+    /// Get the value of the reference with its ETag
+    /// 
+    /// With [`FirebaseClient::enable_etag_cache`], the value is only downloaded if its ETag changed
+    /// since the previous read of the reference, and [`Versioned::changed`] tells if it did.
+    /// Otherwise every read is reported as changed.
+    /// 
+    /// # Example
+    /// ```rust,no_run
+    /// # use firerust::{FirebaseClient, FirebaseError};
+    /// # use serde_json::Value;
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), FirebaseError> {
+    ///     let mut client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+    ///     client.enable_etag_cache();
+    /// 
+    ///     let config = client.reference("/config").get_versioned::<Value>().await?;
+    ///     if config.changed() {
+    ///         println!("{:?}", config.value());
+    ///     }
+    /// # Ok(())
+    /// # }
+    /// ```
+    /// 
+    /// # Errors
+    /// Returns an error if the value is not a valid Response
+    pub async fn get_versioned<T>(&self) -> Result<Versioned<T>, FirebaseError> where T: DeserializeOwned {
+        let previous = self.client.etags.as_ref().and_then(|etags| etags.lookup(&self.path));
+        let etag = previous.as_ref().map(|(etag, _)| etag.as_str());
+
+        let response = self.client.connector.conditional_get(&self.path, etag, self.client.api_key.as_deref()).await?;
+
+        let (value, changed) = cache::revalidate(&response, previous)?;
+
+        if let (Some(etags), Some(etag), true) = (&self.client.etags, response.etag(), changed) {
+            etags.store(&self.path, etag.to_string(), value.clone());
+        }
+
+        Ok(Versioned::new(T::deserialize(value)?, response.etag().map(|etag| etag.to_string()), changed))
+    }
+
+    /// Read the value from the read cache, falling back to the server
+    async fn get_cached(&self, cache: &ReadCache) -> Result<Value, FirebaseError> {
+        let cached = self.client.registry.lookup(&self.path, self.client.api_key.as_deref()).or_else(|| cache.lookup(&self.path));
+
+        if let Some(value) = cached {
+            cache.hit();
+            return Ok(value);
+        }
+
+        cache.miss();
+        let value = match self.client.etags.is_some() {
+            true => self.get_versioned::<Value>().await?.into_value(),
+            false => {
+                let response = self.client.connector.request(Method::Get, &self.path, None, None, self.client.api_key.as_deref()).await?;
+
+                if response.status().code() != 200 {
+                    return Err(FirebaseError::new(format!("{} {}", response.status().code(), response.status().message())));
+                }
+
+                serde_json::from_str::<Value>(response.body())?
+            },
+        };
+
+        cache.store(&self.path, value.clone());
+        Ok(value)
+    }
+
+    /// Get the data snapshot of the reference
+    /// 
+    /// # Example
+    /// ```rust,no_run
+    /// # use firerust::{FirebaseClient, FirebaseError};
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), FirebaseError> {
+    ///     let client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+    ///     let snapshot = client.reference("/users").get_snapshot().await?;
+    ///     println!("{} users", snapshot.size());
+    /// # Ok(())
+    /// # }
+    /// ```
+    /// 
+    /// # Errors
+    /// Returns an error if the value is not a valid Response
+    pub async fn get_snapshot(&self) -> Result<DataSnapshot, FirebaseError> {
+        let value = self.get::<Value>().await?;
+        Ok(DataSnapshot::new(self.key().map(|k| k.to_string()), value))
+    }
+
+    /// Get the data snapshot of the reference in export format, preserving priorities
+    /// 
+    /// # Example
+    /// ```rust,no_run
+    /// # use firerust::{FirebaseClient, FirebaseError};
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), FirebaseError> {
+    ///     let client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+    ///     let snapshot = client.reference("/users/alice").get_export().await?;
+    ///     println!("{:?}", snapshot.priority());
+    /// # Ok(())
+    /// # }
+    /// ```
+    /// 
+    /// # Errors
+    /// Returns an error if the value is not a valid Response
+    pub async fn get_export(&self) -> Result<DataSnapshot, FirebaseError> {
+        let response = self.client.connector.request(Method::Get, &self.path, Some("?format=export"), None, self.client.api_key.as_deref()).await?;
+
+        if response.status().code() != 200 {
+            return Err(FirebaseError::new(format!("{} {}", response.status().code(), response.status().message())));
+        }
+
+        let value: Value = serde_json::from_str(response.body())?;
+        Ok(DataSnapshot::new(self.key().map(|k| k.to_string()), value))
+    }
+
+    /// Start reading the value of the reference, the body is left to stream
+    async fn download(&self) -> Result<reqwest::Response, FirebaseError> {
+        let response = self.client.connector.download(&self.path, None, self.client.api_key.as_deref()).await?;
+
+        match response.status() {
+            status if status.is_success() => Ok(response),
+            status => Err(FirebaseError::new(format!("{} {}", status.as_u16(), status.canonical_reason().unwrap_or("Unknown")))),
+        }
+    }
+
+    /// Get the value of the reference as a stream of bytes, without holding it in memory
+    /// 
+    /// # Example
+    /// ```rust,no_run
+    /// # use firerust::{FirebaseClient, FirebaseError};
+    /// use futures_util::StreamExt;
+    /// 
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), FirebaseError> {
+    ///     let client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+    ///     let mut bytes = client.reference("/logs").get_stream().await?;
+    ///     while let Some(chunk) = bytes.next().await {
+    ///         println!("{} bytes", chunk?.len());
+    ///     }
+    /// # Ok(())
+    /// # }
+    /// ```
+    /// 
+    /// # Errors
+    /// Returns an error if the server can not be reached or rejects the read
+    pub async fn get_stream(&self) -> Result<impl Stream<Item = Result<bytes::Bytes, FirebaseError>> + Send + 'static, FirebaseError> {
+        let response = self.download().await?;
+
+        Ok(response.bytes_stream().map(|chunk| chunk.map_err(|e| FirebaseError::new(e.to_string()))))
+    }
+
+    /// Write the value of the reference to `writer` as it arrives, returning the number of bytes written
+    /// 
+    /// `on_progress` is called after every chunk written
+    /// 
+    /// # Example
+    /// ```rust,no_run
+    /// # use firerust::{FirebaseClient, FirebaseError};
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    ///     let client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+    ///     let mut file = tokio::fs::File::create("logs.json").await?;
+    /// 
+    ///     client.reference("/logs").download_to(&mut file, |progress| {
+    ///         println!("{} of {:?} bytes", progress.bytes(), progress.total());
+    ///     }).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    /// 
+    /// # Errors
+    /// Returns an error if the read fails or the writer can not be written
+    pub async fn download_to<W>(&self, writer: &mut W, mut on_progress: impl FnMut(Progress)) -> Result<u64, FirebaseError> where W: tokio::io::AsyncWrite + Unpin {
+        use tokio::io::AsyncWriteExt;
+
+        let response = self.download().await?;
+
+        let total = response.content_length();
+        let mut body = response.bytes_stream();
+        let mut written = 0;
+
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(|e| FirebaseError::new(e.to_string()))?;
+            writer.write_all(&chunk).await.map_err(|e| FirebaseError::new(format!("Download: {}", e)))?;
+
+            written += chunk.len() as u64;
+            on_progress(Progress::new(written, total));
+        }
+
+        writer.flush().await.map_err(|e| FirebaseError::new(format!("Download: {}", e)))?;
+        Ok(written)
+    }
+
+    /// Get the children of the reference one by one as they arrive, see [`ChildReader`]
+    /// 
+    /// # Errors
+    /// Returns an error if the server can not be reached or rejects the read, errors while reading
+    /// are yielded by the stream
+    pub async fn stream_children(&self) -> Result<impl Stream<Item = Result<(String, Value), FirebaseError>> + Send + Unpin + 'static, FirebaseError> {
+        let body = Box::pin(self.get_stream().await?);
+        let state = (body, ChildReader::new(), std::collections::VecDeque::new(), false);
+
+        Ok(Box::pin(futures_util::stream::unfold(state, |(mut body, mut reader, mut children, mut done)| async move {
+            loop {
+                if let Some(child) = children.pop_front() {
+                    return Some((Ok(child), (body, reader, children, done)));
+                }
+
+                if done {
+                    return None;
+                }
+
+                let result = match body.next().await {
+                    Some(Ok(chunk)) => reader.feed(&chunk).map(|read| children.extend(read)),
+                    Some(Err(e)) => Err(e),
+                    None => {
+                        done = true;
+                        reader.finish()
+                    },
+                };
+
+                if let Err(e) = result {
+                    return Some((Err(e), (body, reader, children, true)));
+                }
+            }
+        })))
+    }
+
+    /// Order the children of the reference by key
+    pub fn order_by_key(&self) -> Query<'a> {
+        Query::new(self.clone(), OrderBy::Key)
+    }
+
+    /// Order the children of the reference by value
+    pub fn order_by_value(&self) -> Query<'a> {
+        Query::new(self.clone(), OrderBy::Value)
+    }
+
+    /// Order the children of the reference by priority
+    pub fn order_by_priority(&self) -> Query<'a> {
+        Query::new(self.clone(), OrderBy::Priority)
+    }
+
+    /// Order the children of the reference by the value at the given child path
+    /// 
+    /// # Example
+    /// ```rust,no_run
+    /// # use firerust::{FirebaseClient, FirebaseError};
+    /// # use serde_json::Value;
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), FirebaseError> {
+    ///     let client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+    ///     let adults = client.reference("/users")
+    ///         .order_by_child("age")
+    ///         .start_at(18)
+    ///         .get::<Value>()
+    ///         .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn order_by_child(&self, path: &str) -> Query<'a> {
+        Query::new(self.clone(), OrderBy::Child(path.to_string()))
+    }
+
     /// Set the value of the reference
     /// 
     /// # Example
@@ -236,6 +856,66 @@ impl<'a> RealtimeReference<'a> {
         Ok(())
     }
 
+    /// Set the value of the reference together with its priority
+    /// 
+    /// # Example
+    /// ```rust,no_run
+    /// # use firerust::{FirebaseClient, FirebaseError};
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), FirebaseError> {
+    ///     let client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+    ///     client.reference("/users/alice").set_with_priority(serde_json::json!({
+    ///        "name": "Alice",
+    ///     }), 10).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    /// 
+    /// # Errors
+    /// Returns an error if the priority is not a number, a string or null
+    pub async fn set_with_priority<T, P>(&self, data: T, priority: P) -> Result<(), FirebaseError> where T: Serialize, P: Serialize {
+        let priority = RealtimeReference::priority_value(priority)?;
+
+        let data = match serde_json::to_value(&data)? {
+            Value::Object(mut map) => {
+                map.insert(".priority".to_string(), priority);
+                Value::Object(map)
+            },
+            value => serde_json::json!({ ".value": value, ".priority": priority }),
+        };
+
+        self.write_request(Method::Put, Some(&data.to_string())).await?;
+        Ok(())
+    }
+
+    /// Set the priority of the reference without changing its value
+    /// 
+    /// # Example
+    /// ```rust,no_run
+    /// # use firerust::{FirebaseClient, FirebaseError};
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), FirebaseError> {
+    ///     let client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+    ///     client.reference("/users/alice").set_priority("a").await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    /// 
+    /// # Errors
+    /// Returns an error if the priority is not a number, a string or null
+    pub async fn set_priority<P>(&self, priority: P) -> Result<(), FirebaseError> where P: Serialize {
+        let priority = RealtimeReference::priority_value(priority)?;
+        self.child(".priority").write_request(Method::Put, Some(&priority.to_string())).await?;
+        Ok(())
+    }
+
+    fn priority_value<P>(priority: P) -> Result<Value, FirebaseError> where P: Serialize {
+        match serde_json::to_value(priority)? {
+            priority @ (Value::Null | Value::Number(_) | Value::String(_)) => Ok(priority),
+            _ => Err(FirebaseError::new("Priority must be a number, a string or null")),
+        }
+    }
+
     /// Set a unique child value of the reference
     /// 
     /// # Example
@@ -299,8 +979,44 @@ impl<'a> RealtimeReference<'a> {
         Ok(())
     }
 
+    /// Queue setting the value of the reference, see [`FirebaseClient::enable_offline`]
+    /// 
+    /// Returns once the write is in the journal, the returned future resolves when the server applied it
+    /// 
+    /// # Example
+    /// ```rust,no_run
+    /// # use firerust::{FirebaseClient, FirebaseError};
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), FirebaseError> {
+    ///     let mut client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+    ///     client.enable_offline("firerust.journal").await?;
+    ///
+    ///     let write = client.reference("/message").queue_set("Hello, world!").await?;
+    ///     write.await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    /// 
+    /// # Errors
+    /// Returns an error if offline mode is not enabled or the write could not be journaled
+    pub async fn queue_set<T>(&self, data: T) -> Result<PendingWrite, FirebaseError> where T: Serialize {
+        self.queue_write(Method::Put, Some(serde_json::to_value(data)?)).await
+    }
+
+    /// Queue updating the children of the reference, see [`RealtimeReference::queue_set`]
+    pub async fn queue_update<T>(&self, data: T) -> Result<PendingWrite, FirebaseError> where T: Serialize {
+        self.queue_write(Method::Patch, Some(serde_json::to_value(data)?)).await
+    }
+
+    /// Queue deleting the value of the reference, see [`RealtimeReference::queue_set`]
+    pub async fn queue_delete(&self) -> Result<PendingWrite, FirebaseError> {
+        self.queue_write(Method::Delete, None).await
+    }
+
     /// Get the value of the reference as a stream
     /// 
+    /// The listener runs until the returned handle is dropped or cancelled
+    /// 
     /// # Example
     /// ```rust,no_run
     /// # use firerust::{FirebaseClient, FirebaseError};
@@ -308,137 +1024,270 @@ impl<'a> RealtimeReference<'a> {
     /// # #[tokio::main]
     /// # async fn main() -> Result<(), FirebaseError> {
     ///     let client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
-    ///     client.reference("/").on_snapshot(|snapshot: Value| {
+    ///     let listener = client.reference("/").on_snapshot(|snapshot: Value| {
     ///         assert_eq!(snapshot["message"].as_str(), Some("Hello, world!"));
     ///         Ok(())
     ///     }, |_| {}).await?;
+    ///
+    ///     listener.closed().await;
     /// # Ok(())
     /// # }
     /// ```
-    pub async fn on_snapshot<T, F, E>(&self, callback: F, on_error: E) -> Result<JoinHandle<()>, FirebaseError> where 
+    pub async fn on_snapshot<T, F, E>(&self, callback: F, on_error: E) -> Result<ListenerHandle, FirebaseError> where 
         T: Send + 'static,
         F: Send + Sync + 'static,
         E: Send + Sync + 'static,
         T: Serialize + DeserializeOwned,
         F: Fn(T) -> Result<(), FirebaseError>,
-        E: Fn(FirebaseError) -> ()
+        E: Fn(FirebaseError)
+    {
+        self.on_snapshot_async(move |data| std::future::ready(callback(data)), on_error).await
+    }
+
+    /// Get the value of the reference as a stream, with an async callback
+    /// 
+    /// # Example
+    /// ```rust,no_run
+    /// # use firerust::{FirebaseClient, FirebaseError};
+    /// # use serde_json::Value;
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), FirebaseError> {
+    ///     let client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+    ///     let mirror = client.clone();
+    ///
+    ///     let listener = client.reference("/source").on_snapshot_async(move |snapshot: Value| {
+    ///         let mirror = mirror.clone();
+    ///         async move { mirror.reference("/mirror").set(snapshot).await }
+    ///     }, |_| {}).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn on_snapshot_async<T, F, Fut, E>(&self, callback: F, on_error: E) -> Result<ListenerHandle, FirebaseError> where 
+        T: Send + 'static,
+        F: Send + Sync + 'static,
+        E: Send + Sync + 'static,
+        T: Serialize + DeserializeOwned,
+        F: Fn(T) -> Fut,
+        Fut: Future<Output = Result<(), FirebaseError>> + Send + 'static,
+        E: Fn(FirebaseError)
+    {
+        self.listen(None, None, |snapshot, _| Some(snapshot), |_, newer| newer, move |snapshot: DataSnapshot| {
+            let callback = T::deserialize(snapshot.value()).map(&callback);
+
+            async move {
+                match callback {
+                    Ok(callback) => callback.await,
+                    Err(e) => Err(e.into()),
+                }
+            }
+        }, on_error).await
+    }
+
+    /// Keep the latest value of the reference, waiting for the initial snapshot
+    /// 
+    /// Updates are coalesced, only the latest value is kept
+    /// 
+    /// # Example
+    /// ```rust,no_run
+    /// # use firerust::{FirebaseClient, FirebaseError};
+    /// # use serde_json::Value;
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), FirebaseError> {
+    ///     let client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+    ///     let mut config = client.reference("/config").watch::<Value>().await?;
+    ///
+    ///     loop {
+    ///         println!("{:?}", *config.borrow());
+    ///         config.changed().await?;
+    ///     }
+    /// # }
+    /// ```
+    /// 
+    /// # Errors
+    /// Returns an error if the listener could not be started or the initial value could not be deserialized
+    pub async fn watch<T>(&self) -> Result<LiveValue<T>, FirebaseError> where 
+        T: DeserializeOwned + Send + Sync + 'static
     {
-        let res = self.client.connector.event_stream(&self.path, None, self.client.api_key.as_deref()).await?;
+        let (initial, receiver) = tokio::sync::oneshot::channel();
+        let mut initial = Some(initial);
+        let mut sender: Option<watch::Sender<T>> = None;
+        let error = Arc::new(std::sync::Mutex::new(None));
+
+        let reference = self.clone().with_listener_options(ListenerOptions::new().capacity(1).overflow(Overflow::CoalesceLatest));
+        let listener = reference.listen(None, None, |snapshot, _| Some(snapshot), |_, newer| newer, move |snapshot: DataSnapshot| {
+            let result = match T::deserialize(snapshot.value()) {
+                Ok(data) => match &sender {
+                    Some(sender) => { sender.send_replace(data); Ok(()) },
+                    None => {
+                        let (tx, rx) = watch::channel(data);
+                        sender = Some(tx);
+                        if let Some(initial) = initial.take() {
+                            let _ = initial.send(Ok(rx));
+                        }
+                        Ok(())
+                    },
+                },
+                Err(e) => match initial.take() {
+                    Some(initial) => { let _ = initial.send(Err(e.into())); Ok(()) },
+                    None => Err(e.into()),
+                },
+            };
+
+            std::future::ready(result)
+        }, {
+            let error = error.clone();
+            move |e| *error.lock().unwrap_or_else(|e| e.into_inner()) = Some(e)
+        }).await?;
 
-        if res.status().as_u16() != 200 {
-            return Err(FirebaseError::new(format!("{} {}", res.status().as_u16(), res.status().canonical_reason().unwrap_or("Unknown"))));
+        match receiver.await {
+            Ok(Ok(receiver)) => Ok(LiveValue::new(receiver, error, listener)),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(FirebaseError::new("Listener closed before the initial snapshot")),
         }
+    }
+
+    /// Get the data snapshot of the reference as a stream
+    /// 
+    /// # Example
+    /// ```rust,no_run
+    /// # use firerust::{FirebaseClient, FirebaseError};
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), FirebaseError> {
+    ///     let client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+    ///     let listener = client.reference("/users").on_data_snapshot(|snapshot| {
+    ///         for user in snapshot.children() {
+    ///             println!("{:?}", user.key());
+    ///         }
+    ///         Ok(())
+    ///     }, |_| {}).await?;
+    ///
+    ///     listener.closed().await;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn on_data_snapshot<F, E>(&self, callback: F, on_error: E) -> Result<ListenerHandle, FirebaseError> where 
+        F: Send + Sync + 'static,
+        E: Send + Sync + 'static,
+        F: Fn(DataSnapshot) -> Result<(), FirebaseError>,
+        E: Fn(FirebaseError)
+    {
+        self.on_data_snapshot_async(move |snapshot| std::future::ready(callback(snapshot)), on_error).await
+    }
+
+    /// Get the data snapshot of the reference as a stream, with an async callback
+    pub async fn on_data_snapshot_async<F, Fut, E>(&self, callback: F, on_error: E) -> Result<ListenerHandle, FirebaseError> where 
+        F: Send + Sync + 'static,
+        E: Send + Sync + 'static,
+        F: Fn(DataSnapshot) -> Fut,
+        Fut: Future<Output = Result<(), FirebaseError>> + Send + 'static,
+        E: Fn(FirebaseError)
+    {
+        self.listen(None, None, |snapshot, _| Some(snapshot), |_, newer| newer, callback, on_error).await
+    }
+
+    /// Get the data snapshot of the reference as a stream, together with the paths changed by each event
+    /// 
+    /// The snapshot shares the tree of the listener, keeping it alive after the callback returns
+    /// makes the next event copy the tree before applying changes
+    /// 
+    /// # Example
+    /// ```rust,no_run
+    /// # use firerust::{FirebaseClient, FirebaseError};
+    /// # use serde_json::Value;
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), FirebaseError> {
+    ///     let client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+    ///     let listener = client.reference("/users").on_change(|changes, snapshot| {
+    ///         for path in changes.paths() {
+    ///             println!("{} => {:?}", path, snapshot.child(path).val::<Value>()?);
+    ///         }
+    ///         Ok(())
+    ///     }, |_| {}).await?;
+    ///
+    ///     listener.closed().await;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn on_change<F, E>(&self, callback: F, on_error: E) -> Result<ListenerHandle, FirebaseError> where 
+        F: Send + Sync + 'static,
+        E: Send + Sync + 'static,
+        F: Fn(&ChangeSet, DataSnapshot) -> Result<(), FirebaseError>,
+        E: Fn(FirebaseError)
+    {
+        self.listen(None, None, |snapshot, changes| Some((changes.clone(), snapshot)), |older, newer| {
+            (older.0.merge(newer.0), newer.1)
+        }, move |(changes, snapshot)| {
+            std::future::ready(callback(&changes, snapshot))
+        }, on_error).await
+    }
 
-        Ok(tokio::spawn(async move {
-            use futures_util::StreamExt;
-            let mut stream = res.bytes_stream();
-            let mut buffer = Vec::new();
-            let mut snap_arc: Option<Arc<Mutex<Value>>> = None;
-            
-            while let Some(chunk_res) = stream.next().await {
-                let chunk = match chunk_res {
-                    Ok(c) => c,
-                    Err(e) => {
-                        on_error(FirebaseError::new(e.to_string()));
+    /// Start a listener, `produce` turns each change of the location into an update for the callback
+    /// task, `merge` combines updates when the queue coalesces and `deliver` runs the callback
+    /// 
+    /// Listeners of the same location or of its descendants share a single stream, the handle
+    /// reports the outcome of the callback on the initial snapshot
+    async fn listen<P, H, D, Fut, E>(&self, params: Option<&str>, prepare: Option<Prepare>, mut produce: H, merge: fn(P, P) -> P, mut deliver: D, on_error: E) -> Result<ListenerHandle, FirebaseError> where 
+        P: Send + 'static,
+        H: Send + 'static,
+        D: Send + 'static,
+        E: Send + Sync + 'static,
+        H: FnMut(DataSnapshot, &ChangeSet) -> Option<P>,
+        D: FnMut(P) -> Fut,
+        Fut: Future<Output = Result<(), FirebaseError>> + Send + 'static,
+        E: Fn(FirebaseError)
+    {
+        // Dropping the initial update would leave `initialized` waiting forever
+        let queue = Arc::new(CallbackQueue::new(self.options.clone(), move |older: (P, bool), newer: (P, bool)| {
+            (merge(older.0, newer.0), older.1 || newer.1)
+        }).pin(|(_, initial)| *initial));
+        let on_error = Arc::new(on_error);
+
+        let subscriber = Subscriber::new(Box::new({
+            let queue = queue.clone();
+            move |snapshot, changes| {
+                let (queue, update) = (queue.clone(), (produce(snapshot, changes)?, changes.is_initial()));
+                Some(Box::pin(async move { queue.push(update).await }))
+            }
+        }), Box::new({
+            let queue = queue.clone();
+            move || queue.close()
+        }), on_error.clone());
+
+        let subscription = self.client.registry.subscribe(self.client, &self.path, params, prepare, subscriber).await?;
+        let (handle, mut cancel, ready) = ListenerHandle::new(subscription.status());
+        let mut shutdown = self.client.shutdown.subscribe();
+
+        tokio::spawn(async move {
+            let mut closing = false;
+
+            loop {
+                let (update, initial) = tokio::select! {
+                    _ = cancel.wait_for(|cancelled| *cancelled) => break,
+                    // The queued updates are still delivered, the shutdown waits for them
+                    _ = shutdown.wait_for(|shutdown| *shutdown), if !closing => {
+                        closing = true;
+                        queue.close();
                         continue;
-                    }
+                    },
+                    update = queue.pop() => match update {
+                        Some(update) => update,
+                        None => break,
+                    },
                 };
 
-                buffer.extend_from_slice(&chunk);
-
-                while let Some(pos) = buffer.windows(2).position(|w| w == b"\n\n") {
-                    let event_data = buffer[..pos].to_vec();
-                    buffer.drain(..pos + 2);
-
-                    let event_stream_str = match String::from_utf8(event_data) {
-                        Ok(s) => s,
-                        Err(e) => { on_error(FirebaseError::new(e.to_string())); continue; },
-                    };
-                    
-                    let event_stream = match EventStream::try_from(event_stream_str) {
-                        Ok(es) => es,
-                        Err(e) => { on_error(FirebaseError::new(e.to_string())); continue; },
-                    };
-
-                    let data = match serde_json::from_str::<Value>(event_stream.data()) {
-                        Ok(data) => data,
-                        Err(e) => { on_error(FirebaseError::new(e.to_string())); continue; }
-                    };
-
-                    let path = match data["path"].as_str() {
-                        Some(path) => match path {
-                            "/" => "",
-                            _ => path
-                        },
-                        None => continue
-                    };
-
-                    let snapshot = match data.get("data") {
-                        Some(s) => s.clone(),
-                        None => continue
-                    };
-
-                    match event_stream.event() {
-                        EventType::Put => {
-                            if snap_arc.is_none() {
-                                snap_arc = Some(Arc::new(Mutex::new(snapshot.clone())));
-                                if let Ok(data) = serde_json::from_value::<T>(snapshot.clone()) {
-                                    if let Err(e) = callback(data) { on_error(e); }
-                                }
-                                continue;
-                            }
-
-                            let snap = snap_arc.as_ref().unwrap();
-                            let mut snap_lock = match snap.lock() {
-                                Ok(l) => l,
-                                Err(e) => { on_error(FirebaseError::new(e.to_string())); continue; }
-                            };
-
-                            let pointer = match snap_lock.pointer_mut(&path) {
-                                Some(pointer) => pointer,
-                                None => continue
-                            };
-
-                            *pointer = snapshot;
-
-                            let data = match serde_json::from_value::<T>(snap_lock.clone()) {
-                                Ok(data) => data,
-                                Err(e) => { on_error(FirebaseError::new(e.to_string())); continue; },
-                            };
-
-                            if let Err(e) = callback(data) { on_error(e); }
-                        },
-                        EventType::Patch => {
-                            if snap_arc.is_none() { continue; }
-                            let snap = snap_arc.as_ref().unwrap();
-                            
-                            let mut snap_lock = match snap.lock() {
-                                Ok(l) => l,
-                                Err(e) => { on_error(FirebaseError::new(e.to_string())); continue; }
-                            };
-
-                            let pointer = match snap_lock.pointer_mut(&path) {
-                                Some(pointer) => pointer,
-                                None => continue
-                            };
-
-                            let _ = RealtimeReference::merge_value(pointer, snapshot);
-
-                            let data = match serde_json::from_value::<T>(snap_lock.clone()) {
-                                Ok(data) => data,
-                                Err(e) => { on_error(FirebaseError::new(e.to_string())); continue; }
-                            };
-
-                            if let Err(e) = callback(data) { on_error(e); }
-                        },                
-                        EventType::Cancel => return,
-                        EventType::AuthRevoked => return,
-                        EventType::KeepAlive => continue,
-                        EventType::Unknown(_) => continue,
-                    };
+                let result = listener::run_callback(&mut deliver, update).await;
+
+                if initial && ready.borrow().is_none() {
+                    ready.send_replace(Some(result.clone()));
                 }
+
+                if let Err(e) = result { on_error(e); }
             }
-        }))
+
+            drop(subscription);
+        });
+
+        Ok(handle)
     }
 
     #[doc(hidden)]
@@ -463,8 +1312,18 @@ impl<'a> RealtimeReference<'a> {
 }
 
 
+/// Returns true if the value holds a server value such as `{".sv": "timestamp"}`
+fn has_server_value(value: &Value) -> bool {
+    match value {
+        Value::Object(map) => map.contains_key(".sv") || map.values().any(has_server_value),
+        Value::Array(items) => items.iter().any(has_server_value),
+        _ => false,
+    }
+}
+
+
 /// Firebase client error
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct FirebaseError {
     message: String
 }
@@ -494,6 +1353,7 @@ impl Display for FirebaseError {
 mod tests {
     use super::*;
     use serde_json::json;
+    use std::sync::Mutex;
 
     #[test]
     fn test_merge_value_put() {
@@ -512,4 +1372,30 @@ mod tests {
         RealtimeReference::<'static>::merge_value(&mut a, b).unwrap();
         assert_eq!(a, json!({"foo": "bar"}));
     }
+
+    #[tokio::test]
+    async fn test_shutdown_waits_for_queued_callbacks() {
+        let client = FirebaseClient::new("https://firerust-test.firebaseio.com/").unwrap();
+        client.registry.open_local("counter", json!(0));
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let _listener = client.reference("/counter").on_snapshot_async({
+            let seen = seen.clone();
+            move |value: u32| {
+                let seen = seen.clone();
+                async move {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    seen.lock().unwrap().push(value);
+                    Ok(())
+                }
+            }
+        }, |_| {}).await.unwrap();
+
+        for value in 1..=3 {
+            client.registry.apply_local("counter", &json!(value), false);
+        }
+
+        client.shutdown().await;
+        assert_eq!(*seen.lock().unwrap(), vec![0, 1, 2, 3]);
+    }
 }
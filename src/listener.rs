@@ -0,0 +1,703 @@
+//! # Example
+//!
+//! ```rust
+//! use firerust::listener::SnapshotCache;
+//! use serde_json::json;
+//!
+//! let mut cache = SnapshotCache::new(json!({"users": {"alice": {"age": 30}}}));
+//! let changes = cache.apply_patch("/users/alice", json!({"age": 31}));
+//!
+//! assert_eq!(changes.paths(), &["users/alice/age".to_string()]);
+//! assert_eq!(cache.value()["users"]["alice"]["age"], 31);
+//! ```
+
+
+use tokio::sync::{ watch, Notify };
+use futures_util::FutureExt;
+use std::panic::AssertUnwindSafe;
+use std::future::Future;
+use std::collections::VecDeque;
+use std::sync::{ Arc, Mutex };
+use serde_json::Value;
+use crate::{ tree, DataSnapshot, FirebaseError };
+
+
+/// The local copy of a listened location, updated in place by stream events
+///
+/// The tree is shared with the snapshots of the whole location through an `Arc`, events are applied
+/// without copying unless a snapshot from a previous event is still alive. Snapshots of a location
+/// below it get their own copy of that location, so they never hold the whole tree.
+#[derive(Clone, Debug)]
+pub struct SnapshotCache {
+    root: Arc<Value>,
+}
+
+impl SnapshotCache {
+
+    /// Create a new cache holding the given value
+    pub fn new(value: Value) -> SnapshotCache {
+        SnapshotCache {
+            root: Arc::new(tree::normalize(value))
+        }
+    }
+
+    /// Get the cached value
+    pub fn value(&self) -> &Value {
+        &self.root
+    }
+
+    /// Get a shared handle to the cached value
+    pub fn shared(&self) -> Arc<Value> {
+        self.root.clone()
+    }
+
+    /// Get a snapshot of the location at the given path, `key` being the key of the cached location
+    ///
+    /// Only the snapshot of the whole cache shares its tree, the snapshot of a location below it
+    /// copies that location
+    pub fn snapshot(&self, key: Option<&str>, path: &str) -> DataSnapshot {
+        let root = DataSnapshot::new(key.map(|k| k.to_string()), self.shared());
+        if trim(path).is_empty() {
+            return root;
+        }
+
+        let child = root.child(path);
+        DataSnapshot::new(child.key().map(|k| k.to_string()), child.value().clone())
+    }
+
+    /// Replace the value at the given path, see [`tree::set`]
+    pub fn apply_put(&mut self, path: &str, data: Value) -> ChangeSet {
+        tree::set(Arc::make_mut(&mut self.root), path, data);
+        ChangeSet::new(vec![trim(path).to_string()])
+    }
+
+    /// Replace each child of `data` at the given path, see [`tree::update`]
+    pub fn apply_patch(&mut self, path: &str, data: Value) -> ChangeSet {
+        let paths = match &data {
+            Value::Object(map) => map.keys().map(|k| join(trim(path), trim(k))).collect(),
+            _ => vec![trim(path).to_string()],
+        };
+
+        tree::update(Arc::make_mut(&mut self.root), path, data);
+        ChangeSet::new(paths)
+    }
+}
+
+
+/// Handle to a running listener
+///
+/// The listener is stopped when the handle is dropped or [`ListenerHandle::cancel`] is called,
+/// a callback that is running is always allowed to finish
+#[derive(Debug)]
+pub struct ListenerHandle {
+    cancel: watch::Sender<bool>,
+    initial: watch::Receiver<Option<Result<(), FirebaseError>>>,
+    status: watch::Receiver<ListenerStatus>,
+}
+
+
+/// Connection status of a listener
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListenerStatus {
+    /// The stream is connected and events or keep-alives arrive in time
+    Live,
+    /// The stream was lost or stayed idle for too long and is being opened again
+    Reconnecting,
+    /// The listener serves the cache saved by a previous run until the server sends its first snapshot
+    Stale,
+    /// The listener has stopped
+    Closed,
+}
+
+/// Reports the outcome of the initial callback of a listener to its handle
+pub(crate) type InitialSender = watch::Sender<Option<Result<(), FirebaseError>>>;
+
+impl ListenerHandle {
+
+    /// Create a handle watching the given connection status, together with the receiver the listener
+    /// task watches for cancellation and the sender it reports the initial callback with
+    pub(crate) fn new(status: watch::Receiver<ListenerStatus>) -> (ListenerHandle, watch::Receiver<bool>, InitialSender) {
+        let (cancel, receiver) = watch::channel(false);
+        let (initial_sender, initial) = watch::channel(None);
+        (ListenerHandle { cancel, initial, status }, receiver, initial_sender)
+    }
+
+    /// Get the connection status of the listener
+    pub fn status(&self) -> ListenerStatus {
+        match self.is_closed() {
+            true => ListenerStatus::Closed,
+            false => *self.status.borrow(),
+        }
+    }
+
+    /// Wait until the callback has run on the initial snapshot
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// # use firerust::{FirebaseClient, FirebaseError};
+    /// # use serde_json::Value;
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), FirebaseError> {
+    ///     let client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+    ///     let listener = client.reference("/config").on_snapshot(|config: Value| {
+    ///         println!("{:?}", config);
+    ///         Ok(())
+    ///     }, |_| {}).await?;
+    ///
+    ///     listener.initialized().await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    /// Returns the error of the initial callback, including a value that could not be deserialized,
+    /// or an error if the listener stopped before the initial snapshot
+    pub async fn initialized(&self) -> Result<(), FirebaseError> {
+        let mut initial = self.initial.clone();
+
+        let result = match initial.wait_for(Option::is_some).await {
+            Ok(result) => result.clone().unwrap_or(Ok(())),
+            Err(_) => Err(FirebaseError::new("Listener closed before the initial snapshot")),
+        };
+        result
+    }
+
+    /// Stop the listener
+    pub fn cancel(&self) {
+        self.cancel.send_replace(true);
+    }
+
+    /// Returns true if the listener has stopped
+    pub fn is_closed(&self) -> bool {
+        self.cancel.is_closed()
+    }
+
+    /// Wait until the listener has stopped, because it was cancelled, the client was shut down
+    /// or the server ended the stream
+    pub async fn closed(&self) {
+        self.cancel.closed().await
+    }
+}
+
+impl Drop for ListenerHandle {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+
+/// The latest value of a location, kept up to date by a listener
+///
+/// The listener stops when the live value is dropped
+#[derive(Debug)]
+pub struct LiveValue<T> {
+    receiver: watch::Receiver<T>,
+    error: Arc<Mutex<Option<FirebaseError>>>,
+    listener: ListenerHandle,
+}
+
+impl<T> LiveValue<T> {
+
+    pub(crate) fn new(receiver: watch::Receiver<T>, error: Arc<Mutex<Option<FirebaseError>>>, listener: ListenerHandle) -> LiveValue<T> {
+        LiveValue {
+            receiver,
+            error,
+            listener,
+        }
+    }
+
+    /// Get the latest value, the value is locked until the returned reference is dropped
+    pub fn borrow(&self) -> watch::Ref<'_, T> {
+        self.receiver.borrow()
+    }
+
+    /// Wait until the value changes
+    ///
+    /// # Errors
+    /// Returns an error if the listener has stopped
+    pub async fn changed(&mut self) -> Result<(), FirebaseError> {
+        self.receiver.changed().await.map_err(|_| FirebaseError::new("Listener closed"))
+    }
+
+    /// Get the last error of the listener, a value that could not be deserialized leaves the
+    /// previous one in place
+    pub fn last_error(&self) -> Option<FirebaseError> {
+        self.error.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Get the handle of the listener keeping the value up to date
+    pub fn listener(&self) -> &ListenerHandle {
+        &self.listener
+    }
+}
+
+
+/// What a listener does when its callback queue is full
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Overflow {
+    /// Wait for room in the queue, the updates arriving meanwhile are merged into a single update
+    /// holding the latest value, without holding back the other listeners of the stream
+    #[default]
+    Block,
+    /// Drop the oldest pending update
+    DropOldest,
+    /// Merge every pending update into the latest one
+    CoalesceLatest,
+}
+
+/// How listener callbacks are run
+///
+/// Callbacks run on their own task, fed by a bounded queue, so a slow callback does not stall
+/// reading the stream and a panicking callback is reported through the error callback
+///
+/// # Example
+/// ```rust,no_run
+/// # use firerust::{FirebaseClient, FirebaseError};
+/// use firerust::listener::{ ListenerOptions, Overflow };
+/// use serde_json::Value;
+///
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), FirebaseError> {
+///     let client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+///     let options = ListenerOptions::new().capacity(1).overflow(Overflow::CoalesceLatest);
+///
+///     let listener = client.reference("/config")
+///         .with_listener_options(options)
+///         .on_snapshot(|config: Value| { println!("{:?}", config); Ok(()) }, |_| {})
+///         .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct ListenerOptions {
+    capacity: usize,
+    overflow: Overflow,
+}
+
+impl Default for ListenerOptions {
+    fn default() -> ListenerOptions {
+        ListenerOptions {
+            capacity: 64,
+            overflow: Overflow::Block,
+        }
+    }
+}
+
+impl ListenerOptions {
+
+    /// Create the default options, a queue of 64 updates that blocks when full
+    pub fn new() -> ListenerOptions {
+        ListenerOptions::default()
+    }
+
+    /// Set the number of updates that can wait for the callback
+    pub fn capacity(mut self, capacity: usize) -> ListenerOptions {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// Set what happens when the queue is full
+    pub fn overflow(mut self, overflow: Overflow) -> ListenerOptions {
+        self.overflow = overflow;
+        self
+    }
+}
+
+
+/// Bounded queue between the task reading a stream and the task running its callback
+pub(crate) struct CallbackQueue<T> {
+    state: Mutex<(VecDeque<T>, bool)>,
+    options: ListenerOptions,
+    merge: Box<dyn Fn(T, T) -> T + Send + Sync>,
+    pinned: Box<dyn Fn(&T) -> bool + Send + Sync>,
+    readable: Notify,
+    writable: Notify,
+}
+
+impl<T> CallbackQueue<T> {
+
+    /// Create a queue, `merge` combines an older and a newer update when coalescing
+    pub(crate) fn new(options: ListenerOptions, merge: impl Fn(T, T) -> T + Send + Sync + 'static) -> CallbackQueue<T> {
+        CallbackQueue {
+            state: Mutex::new((VecDeque::new(), false)),
+            options,
+            merge: Box::new(merge),
+            pinned: Box::new(|_| false),
+            readable: Notify::new(),
+            writable: Notify::new(),
+        }
+    }
+
+    /// Never drop the updates `pinned` returns true for, such as the initial update
+    ///
+    /// When only pinned updates are queued, dropping the oldest lets the queue hold one more update
+    pub(crate) fn pin(mut self, pinned: impl Fn(&T) -> bool + Send + Sync + 'static) -> CallbackQueue<T> {
+        self.pinned = Box::new(pinned);
+        self
+    }
+
+    pub(crate) async fn push(&self, item: T) {
+        loop {
+            let writable = self.writable.notified();
+            {
+                let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+                let (items, closed) = &mut *state;
+
+                if *closed {
+                    return;
+                }
+
+                if items.len() < self.options.capacity {
+                    items.push_back(item);
+                    self.readable.notify_one();
+                    return;
+                }
+
+                match self.options.overflow {
+                    Overflow::Block => {},
+                    Overflow::DropOldest => {
+                        if let Some(oldest) = items.iter().position(|item| !(self.pinned)(item)) {
+                            items.remove(oldest);
+                        }
+                        items.push_back(item);
+                        return;
+                    },
+                    Overflow::CoalesceLatest => {
+                        let merged = items.drain(..).chain(std::iter::once(item)).reduce(|older, newer| (self.merge)(older, newer));
+                        items.extend(merged);
+                        self.readable.notify_one();
+                        return;
+                    },
+                }
+            }
+            writable.await;
+        }
+    }
+
+    pub(crate) async fn pop(&self) -> Option<T> {
+        loop {
+            let readable = self.readable.notified();
+            {
+                let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+                if let Some(item) = state.0.pop_front() {
+                    self.writable.notify_one();
+                    return Some(item);
+                }
+
+                if state.1 {
+                    return None;
+                }
+            }
+            readable.await;
+        }
+    }
+
+    /// Let the callback task finish once the pending updates are delivered, later updates are dropped
+    pub(crate) fn close(&self) {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).1 = true;
+        self.readable.notify_one();
+        self.writable.notify_waiters();
+    }
+}
+
+
+/// Run a listener callback, reporting its panics as errors
+pub(crate) async fn run_callback<P, D, Fut>(deliver: &mut D, update: P) -> Result<(), FirebaseError> where
+    D: FnMut(P) -> Fut,
+    Fut: Future<Output = Result<(), FirebaseError>>
+{
+    let result = match std::panic::catch_unwind(AssertUnwindSafe(|| deliver(update))) {
+        Ok(callback) => AssertUnwindSafe(callback).catch_unwind().await,
+        Err(panic) => Err(panic),
+    };
+
+    result.unwrap_or_else(|panic| {
+        let message = panic.downcast_ref::<&str>().map(|s| s.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+
+        Err(FirebaseError::new(format!("Listener callback panicked: {}", message)))
+    })
+}
+
+
+/// The locations changed by a stream event, relative to the listened location
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChangeSet {
+    paths: Vec<String>,
+    initial: bool,
+}
+
+impl ChangeSet {
+
+    /// Merge the changes of a later event into this change set
+    pub fn merge(mut self, other: ChangeSet) -> ChangeSet {
+        for path in other.paths {
+            if !self.paths.contains(&path) {
+                self.paths.push(path);
+            }
+        }
+        self.initial |= other.initial;
+        self
+    }
+
+    /// Create a new change set over the given relative paths
+    pub fn new(paths: Vec<String>) -> ChangeSet {
+        ChangeSet {
+            paths,
+            initial: false,
+        }
+    }
+
+    /// Create the change set of the initial snapshot, which changes the whole location
+    pub fn initial() -> ChangeSet {
+        ChangeSet {
+            paths: vec![String::new()],
+            initial: true,
+        }
+    }
+
+    /// Returns true if this is the initial snapshot of the listener
+    pub fn is_initial(&self) -> bool {
+        self.initial
+    }
+
+    /// Returns true if the event did not change anything
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    /// Get the changed paths, the empty path is the listened location itself
+    pub fn paths(&self) -> &[String] {
+        &self.paths
+    }
+
+    /// Get the changes seen from the location at the given relative path
+    ///
+    /// A change of the location or of one of its ancestors changes the whole location
+    pub(crate) fn relative_to(&self, path: &str) -> ChangeSet {
+        let path = trim(path);
+        let paths = match self.paths.iter().any(|changed| is_within(path, changed)) {
+            true => vec![String::new()],
+            false => self.paths.iter()
+                .filter(|changed| is_within(changed, path))
+                .map(|changed| trim(&changed[path.len()..]).to_string())
+                .collect(),
+        };
+
+        ChangeSet {
+            paths,
+            initial: self.initial,
+        }
+    }
+
+    /// Returns true if the value at the given relative path may have changed
+    pub fn affects(&self, path: &str) -> bool {
+        let path = trim(path);
+
+        self.paths.iter().any(|changed| is_within(changed, path) || is_within(path, changed))
+    }
+}
+
+
+fn trim(path: &str) -> &str {
+    path.trim_matches('/')
+}
+
+fn join(parent: &str, key: &str) -> String {
+    match parent.is_empty() {
+        true => key.to_string(),
+        false => format!("{}/{}", parent, key),
+    }
+}
+
+/// Returns true if `path` is `ancestor` or one of its descendants
+fn is_within(path: &str, ancestor: &str) -> bool {
+    ancestor.is_empty() || path == ancestor || (path.starts_with(ancestor) && path.as_bytes().get(ancestor.len()) == Some(&b'/'))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_apply_in_place() {
+        let mut cache = SnapshotCache::new(json!({"a": {"b": 1}, "c": 2}));
+        let before = cache.value() as *const Value;
+
+        cache.apply_put("/a/b", json!(3));
+        assert_eq!(cache.value() as *const Value, before);
+        assert_eq!(cache.value(), &json!({"a": {"b": 3}, "c": 2}));
+    }
+
+    #[test]
+    fn test_apply_copy_on_write() {
+        let mut cache = SnapshotCache::new(json!({"a": 1}));
+        let held = cache.shared();
+
+        cache.apply_patch("/", json!({"b": 2}));
+        assert_eq!(*held, json!({"a": 1}));
+        assert_eq!(cache.value(), &json!({"a": 1, "b": 2}));
+    }
+
+    #[test]
+    fn test_snapshot_below_root_copies_location() {
+        let mut cache = SnapshotCache::new(json!({"users": {"alice": {"age": 30}}}));
+
+        let alice = cache.snapshot(Some("root"), "/users/alice");
+        assert_eq!(alice.key(), Some("alice"));
+        assert_eq!(Arc::strong_count(&cache.shared()), 2);
+
+        let root = cache.snapshot(Some("root"), "");
+        assert_eq!(root.key(), Some("root"));
+        assert_eq!(Arc::strong_count(&cache.shared()), 3);
+
+        drop(root);
+        let before = cache.value() as *const Value;
+        cache.apply_put("/users/alice/age", json!(31));
+        assert_eq!(cache.value() as *const Value, before);
+        assert_eq!(alice.val::<Value>().unwrap(), json!({"age": 30}));
+    }
+
+    #[test]
+    fn test_apply_missing_path() {
+        let mut cache = SnapshotCache::new(json!({"a": 1}));
+
+        let changes = cache.apply_patch("/b/c", json!({"d": 2, "e/f": 3}));
+        assert_eq!(changes.paths(), &["b/c/d".to_string(), "b/c/e/f".to_string()]);
+        assert_eq!(cache.value(), &json!({"a": 1, "b": {"c": {"d": 2, "e": {"f": 3}}}}));
+
+        cache.apply_put("/a/x", json!(true));
+        assert_eq!(cache.value()["a"], json!({"x": true}));
+    }
+
+    #[tokio::test]
+    async fn test_listener_handle() {
+        let (handle, mut cancel, _initial) = ListenerHandle::new(watch::channel(ListenerStatus::Live).1);
+        let task = tokio::spawn(async move { let _ = cancel.wait_for(|c| *c).await; });
+
+        assert!(!handle.is_closed());
+        handle.cancel();
+        handle.closed().await;
+        assert!(handle.is_closed());
+        task.await.unwrap();
+
+        let (handle, mut cancel, _initial) = ListenerHandle::new(watch::channel(ListenerStatus::Live).1);
+        drop(handle);
+        assert!(cancel.wait_for(|c| *c).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_listener_status() {
+        let (status, receiver) = watch::channel(ListenerStatus::Live);
+        let (handle, cancel, _initial) = ListenerHandle::new(receiver);
+        assert_eq!(handle.status(), ListenerStatus::Live);
+
+        status.send_replace(ListenerStatus::Reconnecting);
+        assert_eq!(handle.status(), ListenerStatus::Reconnecting);
+
+        drop(cancel);
+        assert_eq!(handle.status(), ListenerStatus::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_listener_initialized() {
+        let (handle, _cancel, initial) = ListenerHandle::new(watch::channel(ListenerStatus::Live).1);
+        initial.send_replace(Some(Err(FirebaseError::new("invalid type"))));
+        assert_eq!(handle.initialized().await.unwrap_err().to_string(), "invalid type");
+
+        let (handle, _cancel, initial) = ListenerHandle::new(watch::channel(ListenerStatus::Live).1);
+        drop(initial);
+        assert!(handle.initialized().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_queue_overflow() {
+        let queue = CallbackQueue::new(ListenerOptions::new().capacity(2).overflow(Overflow::DropOldest), |_, b| b);
+        for i in 0..5 { queue.push(i).await; }
+        queue.close();
+        assert_eq!((queue.pop().await, queue.pop().await, queue.pop().await), (Some(3), Some(4), None));
+
+        // The initial update stays, the next oldest is dropped
+        let queue = CallbackQueue::new(ListenerOptions::new().capacity(2).overflow(Overflow::DropOldest), |_, b| b).pin(|i| *i == 0);
+        for i in 0..5 { queue.push(i).await; }
+        queue.close();
+        assert_eq!((queue.pop().await, queue.pop().await, queue.pop().await), (Some(0), Some(4), None));
+
+        let queue = CallbackQueue::new(ListenerOptions::new().capacity(1).overflow(Overflow::DropOldest), |_, b| b).pin(|i| *i == 0);
+        for i in 0..3 { queue.push(i).await; }
+        queue.close();
+        assert_eq!((queue.pop().await, queue.pop().await, queue.pop().await), (Some(0), Some(2), None));
+
+        let queue = CallbackQueue::new(ListenerOptions::new().capacity(2).overflow(Overflow::CoalesceLatest), |a, b| a + b);
+        for i in 0..5 { queue.push(i).await; }
+        queue.close();
+        assert_eq!((queue.pop().await, queue.pop().await), (Some(10), None));
+    }
+
+    #[tokio::test]
+    async fn test_queue_blocks() {
+        let queue = Arc::new(CallbackQueue::new(ListenerOptions::new().capacity(1), |_, b| b));
+        queue.push(1).await;
+
+        let writer = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.push(2).await; queue.close(); }
+        });
+
+        tokio::task::yield_now().await;
+        assert!(!writer.is_finished());
+        assert_eq!(queue.pop().await, Some(1));
+        assert_eq!(queue.pop().await, Some(2));
+        assert_eq!(queue.pop().await, None);
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_callback_catches_panics() {
+        let mut sync_panic = |_: u32| -> std::future::Ready<Result<(), FirebaseError>> { panic!("sync") };
+        let mut async_panic = |_: u32| async { panic!("async") };
+
+        assert_eq!(run_callback(&mut sync_panic, 1).await.unwrap_err().to_string(), "Listener callback panicked: sync");
+        assert_eq!(run_callback(&mut async_panic, 1).await.unwrap_err().to_string(), "Listener callback panicked: async");
+    }
+
+    #[tokio::test]
+    async fn test_live_value() {
+        let (sender, receiver) = watch::channel(1);
+        let (listener, _cancel, _initial) = ListenerHandle::new(watch::channel(ListenerStatus::Live).1);
+        let mut value = LiveValue::new(receiver, Arc::default(), listener);
+
+        sender.send_replace(2);
+        assert!(value.changed().await.is_ok());
+        assert_eq!(*value.borrow(), 2);
+
+        drop(sender);
+        assert!(value.changed().await.is_err());
+    }
+
+    #[test]
+    fn test_change_set_relative_to() {
+        let changes = ChangeSet::new(vec!["users/alice/age".to_string(), "users/bob".to_string()]);
+
+        assert_eq!(changes.relative_to("users").paths(), &["alice/age".to_string(), "bob".to_string()]);
+        assert_eq!(changes.relative_to("/users/alice/").paths(), &["age".to_string()]);
+        assert_eq!(changes.relative_to("users/bob/age").paths(), &[String::new()]);
+        assert!(changes.relative_to("posts").is_empty());
+        assert!(ChangeSet::initial().relative_to("users").is_initial());
+    }
+
+    #[test]
+    fn test_change_set_affects() {
+        let changes = ChangeSet::new(vec!["users/alice/age".to_string()]);
+
+        assert!(changes.affects("users"));
+        assert!(changes.affects("/users/alice/age/"));
+        assert!(!changes.affects("users/al"));
+        assert!(!changes.affects("users/bob"));
+        assert!(ChangeSet::initial().affects("anything"));
+    }
+}
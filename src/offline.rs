@@ -0,0 +1,435 @@
+//! # Example
+//!
+//! ```rust,no_run
+//! use firerust::{FirebaseClient, FirebaseError};
+//! use serde_json::json;
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), FirebaseError> {
+//!     let mut client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+//!     client.enable_offline("firerust.journal").await?;
+//!
+//!     // Durable once queued, resolves when the server has applied it
+//!     let write = client.reference("/devices/1/status").queue_set(json!("online")).await?;
+//!     write.await?;
+//!
+//!     Ok(())
+//! }
+//! ```
+
+
+use std::collections::{ HashMap, VecDeque };
+use serde::{ Deserialize, Serialize };
+use std::io::{ BufRead, BufReader, Read as _, Seek as _, SeekFrom, Write as _ };
+use tokio::sync::{ oneshot, watch, Notify };
+use crate::connector::{ Connector, Method };
+use crate::registry::ListenerRegistry;
+use std::path::{ Path, PathBuf };
+use std::fs::{ File, OpenOptions };
+use std::task::{ Context, Poll };
+use std::sync::{ Arc, Mutex };
+use std::time::Duration;
+use crate::FirebaseError;
+use std::future::Future;
+use serde_json::Value;
+use std::pin::Pin;
+
+
+/// First delay before a write is sent again, doubled after each failure up to `MAX_RETRY_DELAY`
+pub(crate) const RETRY_DELAY: Duration = Duration::from_secs(1);
+pub(crate) const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+
+/// Completion of a queued write, resolves once the server has applied or rejected the write
+#[derive(Debug)]
+pub struct PendingWrite {
+    id: u64,
+    receiver: oneshot::Receiver<Result<(), FirebaseError>>,
+}
+
+impl PendingWrite {
+
+    pub(crate) fn new(id: u64, receiver: oneshot::Receiver<Result<(), FirebaseError>>) -> PendingWrite {
+        PendingWrite {
+            id,
+            receiver,
+        }
+    }
+
+    /// Get the position of the write in the journal or buffer it was queued to
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Future for PendingWrite {
+    type Output = Result<(), FirebaseError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.receiver).poll(cx).map(|result| {
+            result.unwrap_or_else(|_| Err(FirebaseError::new("Queue closed before the write was sent")))
+        })
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum WriteMethod {
+    Put,
+    Patch,
+    Delete,
+}
+
+impl From<WriteMethod> for Method {
+    fn from(method: WriteMethod) -> Method {
+        match method {
+            WriteMethod::Put => Method::Put,
+            WriteMethod::Patch => Method::Patch,
+            WriteMethod::Delete => Method::Delete,
+        }
+    }
+}
+
+/// A write waiting to be sent
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct QueuedWrite {
+    id: u64,
+    method: WriteMethod,
+    path: String,
+    data: Option<Value>,
+}
+
+/// A line of the journal
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase", tag = "op")]
+enum Record {
+    Write(QueuedWrite),
+    Ack { id: u64 },
+}
+
+
+/// Writes waiting for the server, backed by an append-only journal on disk
+///
+/// Every write is synced to the journal before it is acknowledged to the caller, so writes survive
+/// a restart and are replayed in order. The journal is only written from blocking threads, the
+/// lock of the file is never held on the async executor.
+pub(crate) struct OfflineQueue {
+    /// Held for the whole of an append, so the journal keeps the order of the ids
+    file: Mutex<File>,
+    state: Mutex<QueueState>,
+    available: Notify,
+}
+
+struct QueueState {
+    pending: VecDeque<QueuedWrite>,
+    waiters: HashMap<u64, oneshot::Sender<Result<(), FirebaseError>>>,
+    next_id: u64,
+}
+
+impl OfflineQueue {
+
+    /// Open the journal, keeping the writes that were not sent before
+    ///
+    /// # Errors
+    /// Returns an error if the journal can not be read or written
+    pub(crate) fn open(path: impl AsRef<Path>) -> Result<OfflineQueue, FirebaseError> {
+        let path = path.as_ref();
+        let mut pending = Vec::new();
+        let mut next_id = 0;
+
+        if path.exists() {
+            let file = File::open(path).map_err(io_error)?;
+
+            // A line cut short by a crash is the last one and was never acknowledged, it is skipped
+            for line in BufReader::new(file).lines() {
+                match serde_json::from_str::<Record>(&line.map_err(io_error)?) {
+                    Ok(Record::Write(write)) => {
+                        next_id = next_id.max(write.id + 1);
+                        pending.push(write);
+                    },
+                    Ok(Record::Ack { id }) => pending.retain(|write| write.id != id),
+                    Err(_) => continue,
+                }
+            }
+        }
+
+        let file = compact(path, &pending)?;
+
+        Ok(OfflineQueue {
+            file: Mutex::new(file),
+            state: Mutex::new(QueueState {
+                pending: pending.into(),
+                waiters: HashMap::new(),
+                next_id,
+            }),
+            available: Notify::new(),
+        })
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Get the number of writes waiting for the server
+    pub(crate) fn len(&self) -> usize {
+        self.state().pending.len()
+    }
+
+    fn file(&self) -> std::sync::MutexGuard<'_, File> {
+        self.file.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Append a write to the journal
+    ///
+    /// # Errors
+    /// Returns an error if the method is not a write or the journal can not be written
+    pub(crate) async fn push(self: &Arc<Self>, method: Method, path: &str, data: Option<Value>) -> Result<PendingWrite, FirebaseError> {
+        let method = match method {
+            Method::Put => WriteMethod::Put,
+            Method::Patch => WriteMethod::Patch,
+            Method::Delete => WriteMethod::Delete,
+            method => return Err(FirebaseError::new(format!("{} can not be queued offline", method))),
+        };
+
+        let (queue, path) = (self.clone(), path.to_string());
+        let pending = tokio::task::spawn_blocking(move || {
+            let mut file = queue.file();
+
+            let mut state = queue.state();
+            let write = QueuedWrite {
+                id: state.next_id,
+                method,
+                path,
+                data,
+            };
+            state.next_id += 1;
+            drop(state);
+
+            append(&mut file, &Record::Write(write.clone()))?;
+
+            let (sender, receiver) = oneshot::channel();
+            let mut state = queue.state();
+            state.waiters.insert(write.id, sender);
+            state.pending.push_back(write.clone());
+
+            Ok::<_, FirebaseError>(PendingWrite::new(write.id, receiver))
+        }).await.map_err(|e| FirebaseError::new(format!("Offline journal: {}", e)))??;
+
+        self.available.notify_one();
+        Ok(pending)
+    }
+
+    /// Record the outcome of the oldest write and hand it to its caller
+    async fn complete(self: &Arc<Self>, id: u64, result: Result<(), FirebaseError>) {
+        let queue = self.clone();
+
+        let _ = tokio::task::spawn_blocking(move || {
+            let mut file = queue.file();
+
+            // The write is replayed on the next start if the acknowledgement is lost
+            let _ = append(&mut file, &Record::Ack { id });
+
+            let mut state = queue.state();
+            state.pending.retain(|write| write.id != id);
+            let (empty, waiter) = (state.pending.is_empty(), state.waiters.remove(&id));
+            drop(state);
+
+            // No write can be appended while the file is held, an empty queue has nothing to replay
+            if empty {
+                let _ = file.set_len(0).and_then(|_| file.sync_data());
+            }
+
+            if let Some(waiter) = waiter {
+                let _ = waiter.send(result);
+            }
+        }).await;
+    }
+
+    /// Send the queued writes in order, waiting for connectivity when the server can not be reached
+    ///
+    /// Writes the server rejects are reported to their caller and dropped, and the listeners they
+    /// were applied to reload the location from the server. The other writes are retried.
+    pub(crate) async fn run(self: Arc<Self>, connector: Connector, api_key: Option<String>, registry: Arc<ListenerRegistry>, mut shutdown: watch::Receiver<bool>) {
+        let mut delay = RETRY_DELAY;
+
+        loop {
+            let available = self.available.notified();
+            let write = self.state().pending.front().cloned();
+
+            let write = match write {
+                Some(write) => write,
+                None => {
+                    tokio::select! {
+                        _ = shutdown.wait_for(|shutdown| *shutdown) => return,
+                        _ = available => continue,
+                    }
+                },
+            };
+
+            let data = write.data.as_ref().map(|data| data.to_string());
+            let result = tokio::select! {
+                _ = shutdown.wait_for(|shutdown| *shutdown) => return,
+                result = connector.request(write.method.into(), &write.path, Some("?print=silent"), data.as_deref(), api_key.as_deref()) => result,
+            };
+
+            let retry = match result {
+                Ok(response) => match response.status().code() {
+                    200 | 204 => {
+                        self.complete(write.id, Ok(())).await;
+                        false
+                    },
+                    code if retryable(code) => true,
+                    code => {
+                        registry.resync(&write.path);
+                        self.complete(write.id, Err(FirebaseError::new(format!("{} {}", code, response.status().message())))).await;
+                        false
+                    },
+                },
+                Err(_) => true,
+            };
+
+            if !retry {
+                delay = RETRY_DELAY;
+                continue;
+            }
+
+            tokio::select! {
+                _ = shutdown.wait_for(|shutdown| *shutdown) => return,
+                _ = tokio::time::sleep(delay) => {},
+            }
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+    }
+}
+
+
+/// Returns true if a write the server answered with the given status may succeed when sent again
+pub(crate) fn retryable(code: u16) -> bool {
+    matches!(code, 408 | 429 | 500..)
+}
+
+/// Rewrite the journal with only the pending writes, then reopen it for appending
+fn compact(path: &Path, pending: &[QueuedWrite]) -> Result<File, FirebaseError> {
+    let mut temporary = PathBuf::from(path);
+    temporary.as_mut_os_string().push(".tmp");
+
+    let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&temporary).map_err(io_error)?;
+    for write in pending {
+        append(&mut file, &Record::Write(write.clone()))?;
+    }
+    std::fs::rename(&temporary, path).map_err(io_error)?;
+
+    OpenOptions::new().read(true).append(true).open(path).map_err(io_error)
+}
+
+fn append(file: &mut File, record: &Record) -> Result<(), FirebaseError> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+
+    // A record cut short by a failed append is ended first, so it does not corrupt this one
+    let mut last = [b'\n'];
+    if file.metadata().map_err(io_error)?.len() > 0 {
+        file.seek(SeekFrom::End(-1)).and_then(|_| file.read_exact(&mut last)).map_err(io_error)?;
+    }
+    if last[0] != b'\n' {
+        line.insert(0, b'\n');
+    }
+
+    file.write_all(&line).and_then(|_| file.sync_data()).map_err(io_error)
+}
+
+fn io_error(error: std::io::Error) -> FirebaseError {
+    FirebaseError::new(format!("Offline journal: {}", error))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_retryable() {
+        assert!(retryable(500));
+        assert!(retryable(503));
+        assert!(retryable(429));
+        assert!(retryable(408));
+        assert!(!retryable(400));
+        assert!(!retryable(401));
+        assert!(!retryable(403));
+    }
+
+    fn journal(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("firerust-{}-{}.journal", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn test_journal_survives_reopen() {
+        let path = journal("reopen");
+
+        let queue = Arc::new(OfflineQueue::open(&path).unwrap());
+        queue.push(Method::Put, "a", Some(json!(1))).await.unwrap();
+        queue.push(Method::Patch, "b", Some(json!({"c": 2}))).await.unwrap();
+        queue.push(Method::Delete, "d", None).await.unwrap();
+        queue.complete(0, Ok(())).await;
+        drop(queue);
+
+        let queue = Arc::new(OfflineQueue::open(&path).unwrap());
+        let pending: Vec<_> = queue.state().pending.iter().map(|w| (w.id, w.path.clone())).collect();
+        assert_eq!(pending, vec![(1, "b".to_string()), (2, "d".to_string())]);
+
+        assert_eq!(queue.push(Method::Put, "e", None).await.unwrap().id(), 3);
+        assert!(queue.push(Method::Get, "e", None).await.is_err());
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_journal_skips_torn_line() {
+        let path = journal("torn");
+        std::fs::write(&path, "{\"op\":\"write\",\"id\":0,\"method\":\"put\",\"path\":\"a\",\"data\":1}\n{\"op\":\"wri").unwrap();
+
+        let queue = OfflineQueue::open(&path).unwrap();
+        assert_eq!(queue.len(), 1);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_append_after_torn_record() {
+        let path = journal("torn-append");
+        let queue = Arc::new(OfflineQueue::open(&path).unwrap());
+        queue.push(Method::Put, "a", Some(json!(1))).await.unwrap();
+
+        // An append that failed halfway
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(b"{\"op\":\"wri").unwrap();
+
+        queue.push(Method::Put, "b", Some(json!(2))).await.unwrap();
+        drop(queue);
+
+        let queue = OfflineQueue::open(&path).unwrap();
+        let pending: Vec<_> = queue.state().pending.iter().map(|w| w.path.clone()).collect();
+        assert_eq!(pending, vec!["a".to_string(), "b".to_string()]);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_pending_write_completes() {
+        let path = journal("complete");
+        let queue = Arc::new(OfflineQueue::open(&path).unwrap());
+
+        let write = queue.push(Method::Put, "a", Some(json!(1))).await.unwrap();
+        queue.complete(write.id(), Err(FirebaseError::new("401 Unauthorized"))).await;
+
+        assert_eq!(write.await.unwrap_err().to_string(), "401 Unauthorized");
+        assert_eq!(queue.len(), 0);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
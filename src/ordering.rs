@@ -0,0 +1,224 @@
+//! # Example
+//!
+//! ```rust
+//! use firerust::{FirebaseOrdering, OrderBy};
+//! use serde_json::json;
+//!
+//! let mut children = vec![("b", json!(2)), ("a", json!("x")), ("c", json!(null))];
+//! let ordering = FirebaseOrdering::new(OrderBy::Value);
+//! children.sort_by(|a, b| ordering.compare((a.0, &a.1), (b.0, &b.1)));
+//!
+//! assert_eq!(children.iter().map(|c| c.0).collect::<Vec<_>>(), vec!["c", "b", "a"]);
+//! ```
+
+
+use crate::query::OrderBy;
+use std::cmp::Ordering;
+use serde_json::Value;
+
+
+/// Comparator implementing the Firebase ordering rules for keys, values and priorities
+///
+/// Values accept raw data as well as data in export format, `.value` and `.priority` are handled
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FirebaseOrdering {
+    order_by: OrderBy,
+}
+
+impl FirebaseOrdering {
+
+    /// Create a new comparator for the children of a location ordered by `order_by`
+    pub fn new(order_by: OrderBy) -> FirebaseOrdering {
+        FirebaseOrdering {
+            order_by
+        }
+    }
+
+    /// Get the ordering of the comparator
+    pub fn order_by(&self) -> &OrderBy {
+        &self.order_by
+    }
+
+    /// Compare two children of a location, given as key and raw value
+    ///
+    /// Children that are equal under the ordering are tie-broken by key
+    pub fn compare(&self, a: (&str, &Value), b: (&str, &Value)) -> Ordering {
+        self.compare_index(a.1, b.1).then_with(|| FirebaseOrdering::compare_keys(a.0, b.0))
+    }
+
+    /// Compare the values the children are ordered by, without the key tie-break
+    pub fn compare_index(&self, a: &Value, b: &Value) -> Ordering {
+        match &self.order_by {
+            OrderBy::Key => Ordering::Equal,
+            OrderBy::Value => FirebaseOrdering::compare_values(a, b),
+            OrderBy::Priority => FirebaseOrdering::compare_priorities(priority(a), priority(b)),
+            OrderBy::Child(path) => FirebaseOrdering::compare_values(child(a, path), child(b, path)),
+        }
+    }
+
+    /// Get the value a child is ordered by, `None` when ordering by key
+    pub fn index_value<'v>(&self, value: &'v Value) -> Option<&'v Value> {
+        match &self.order_by {
+            OrderBy::Key => None,
+            OrderBy::Value => Some(leaf(value)),
+            OrderBy::Priority => Some(priority(value)),
+            OrderBy::Child(path) => Some(child(value, path)),
+        }
+    }
+
+    /// Compare two keys
+    ///
+    /// Keys that are 32-bit integers come first in numeric order, then the other keys in lexicographic order
+    pub fn compare_keys(a: &str, b: &str) -> Ordering {
+        if a == b {
+            return Ordering::Equal;
+        }
+
+        match (parse_key(a), parse_key(b)) {
+            (Some(x), Some(y)) => x.cmp(&y).then_with(|| a.len().cmp(&b.len())),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => a.encode_utf16().cmp(b.encode_utf16()),
+        }
+    }
+
+    /// Compare two values
+    ///
+    /// `null` < `false` < `true` < numbers < strings < objects, objects are all equal to each other
+    pub fn compare_values(a: &Value, b: &Value) -> Ordering {
+        let (a, b) = (leaf(a), leaf(b));
+
+        match (a, b) {
+            (Value::Number(x), Value::Number(y)) => compare_numbers(x, y),
+            (Value::String(x), Value::String(y)) => x.encode_utf16().cmp(y.encode_utf16()),
+            _ => rank(a).cmp(&rank(b)),
+        }
+    }
+
+    /// Compare two priorities
+    ///
+    /// No priority first, then numbers, then strings
+    pub fn compare_priorities(a: &Value, b: &Value) -> Ordering {
+        let (a, b) = (priority_rank(a), priority_rank(b));
+
+        match (a, b) {
+            ((1, Value::Number(x)), (1, Value::Number(y))) => compare_numbers(x, y),
+            ((2, Value::String(x)), (2, Value::String(y))) => x.encode_utf16().cmp(y.encode_utf16()),
+            _ => a.0.cmp(&b.0),
+        }
+    }
+}
+
+
+/// Parse a key the way Firebase detects integer keys, `-?0*\d{1,10}` within the 32-bit range
+fn parse_key(key: &str) -> Option<i32> {
+    let digits = key.strip_prefix('-').unwrap_or(key);
+    let significant = digits.trim_start_matches('0');
+
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) || significant.len() > 10 {
+        return None;
+    }
+
+    key.parse::<i64>().ok().and_then(|n| i32::try_from(n).ok())
+}
+
+fn compare_numbers(a: &serde_json::Number, b: &serde_json::Number) -> Ordering {
+    match (a.as_i64(), b.as_i64()) {
+        (Some(x), Some(y)) => x.cmp(&y),
+        _ => a.as_f64().unwrap_or(0.0).total_cmp(&b.as_f64().unwrap_or(0.0)),
+    }
+}
+
+fn rank(value: &Value) -> u8 {
+    match value {
+        Value::Null => 0,
+        Value::Bool(false) => 1,
+        Value::Bool(true) => 2,
+        Value::Number(_) => 3,
+        Value::String(_) => 4,
+        Value::Array(_) | Value::Object(_) => 5,
+    }
+}
+
+fn priority_rank(value: &Value) -> (u8, &Value) {
+    match value {
+        Value::Number(_) => (1, value),
+        Value::String(_) => (2, value),
+        _ => (0, value),
+    }
+}
+
+/// Unwrap a value in export format, objects holding only metadata are null
+fn leaf(value: &Value) -> &Value {
+    match value {
+        Value::Object(map) => match map.get(".value") {
+            Some(inner) => inner,
+            None if map.keys().all(|k| k == ".priority") => &Value::Null,
+            None => value,
+        },
+        _ => value,
+    }
+}
+
+fn priority(value: &Value) -> &Value {
+    match value {
+        Value::Object(map) => map.get(".priority").unwrap_or(&Value::Null),
+        _ => &Value::Null,
+    }
+}
+
+fn child<'v>(value: &'v Value, path: &str) -> &'v Value {
+    let mut current = value;
+
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        current = match leaf(current) {
+            Value::Object(map) => map.get(segment).unwrap_or(&Value::Null),
+            Value::Array(items) => segment.parse::<usize>().ok().and_then(|i| items.get(i)).unwrap_or(&Value::Null),
+            _ => &Value::Null,
+        };
+    }
+
+    current
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_compare_keys() {
+        let mut keys = vec!["b", "a", "10", "2", "-5", "01", "1", "2147483648", "+3"];
+        keys.sort_by(|a, b| FirebaseOrdering::compare_keys(a, b));
+
+        assert_eq!(keys, vec!["-5", "1", "01", "2", "10", "+3", "2147483648", "a", "b"]);
+    }
+
+    #[test]
+    fn test_compare_values() {
+        let mut values = vec![json!({"a": 1}), json!("b"), json!(2.5), json!(true), json!(null), json!(false), json!(-1), json!("a")];
+        values.sort_by(FirebaseOrdering::compare_values);
+
+        assert_eq!(values, vec![json!(null), json!(false), json!(true), json!(-1), json!(2.5), json!("a"), json!("b"), json!({"a": 1})]);
+    }
+
+    #[test]
+    fn test_compare_ties_by_key() {
+        let ordering = FirebaseOrdering::new(OrderBy::Child("age".to_string()));
+        let (a, b) = (json!({"age": 3}), json!({"age": 3}));
+
+        assert_eq!(ordering.compare(("b", &a), ("a", &b)), Ordering::Greater);
+        assert_eq!(ordering.compare(("2", &a), ("10", &b)), Ordering::Less);
+    }
+
+    #[test]
+    fn test_compare_export_values() {
+        let ordering = FirebaseOrdering::new(OrderBy::Priority);
+        let (a, b, c) = (json!({".value": 1, ".priority": "x"}), json!({".value": 1, ".priority": 5}), json!(1));
+
+        assert_eq!(ordering.compare(("a", &a), ("b", &b)), Ordering::Greater);
+        assert_eq!(ordering.compare(("c", &c), ("b", &b)), Ordering::Less);
+        assert_eq!(FirebaseOrdering::compare_values(&a, &json!(1)), Ordering::Equal);
+    }
+}
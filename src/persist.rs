@@ -0,0 +1,288 @@
+//! # Example
+//!
+//! ```rust,no_run
+//! use firerust::{FirebaseClient, FirebaseError, ListenerStatus};
+//! use firerust::persist::PersistenceOptions;
+//! use serde_json::Value;
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), FirebaseError> {
+//!     let mut client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+//!     client.enable_persistence(PersistenceOptions::new("/var/lib/app/snapshots"))?;
+//!
+//!     // The callback first sees the value saved by the previous run, then the server's
+//!     let listener = client.reference("/config").on_snapshot(|config: Value| {
+//!         println!("{:?}", config);
+//!         Ok(())
+//!     }, |_| {}).await?;
+//!
+//!     listener.initialized().await?;
+//!     if listener.status() == ListenerStatus::Stale {
+//!         println!("Running on the saved configuration");
+//!     }
+//!
+//!     Ok(())
+//! }
+//! ```
+
+
+use std::path::{ Path, PathBuf };
+use sha2::{ Digest, Sha256 };
+use crate::FirebaseError;
+use std::time::Duration;
+use serde_json::Value;
+
+
+const MAGIC: &[u8; 4] = b"FRSC";
+const VERSION: u8 = 1;
+const PLAIN: u8 = 0;
+#[cfg(feature = "encryption")]
+const ENCRYPTED: u8 = 1;
+const HEADER: usize = MAGIC.len() + 2 + 32;
+
+
+/// Where and how the caches of listeners are saved
+#[derive(Clone)]
+pub struct PersistenceOptions {
+    directory: PathBuf,
+    interval: Duration,
+    #[cfg(feature = "encryption")]
+    key: Option<[u8; 32]>,
+}
+
+impl std::fmt::Debug for PersistenceOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut debug = f.debug_struct("PersistenceOptions");
+        debug.field("directory", &self.directory).field("interval", &self.interval);
+
+        #[cfg(feature = "encryption")]
+        debug.field("key", &self.key.as_ref().map(|_| "[REDACTED]"));
+
+        debug.finish()
+    }
+}
+
+impl PersistenceOptions {
+
+    /// Save the caches in the given directory, at most every 5 seconds per listened location
+    pub fn new(directory: impl AsRef<Path>) -> PersistenceOptions {
+        PersistenceOptions {
+            directory: directory.as_ref().to_path_buf(),
+            interval: Duration::from_secs(5),
+            #[cfg(feature = "encryption")]
+            key: None,
+        }
+    }
+
+    /// Set the minimum time between two saves of the cache of a location
+    pub fn interval(mut self, interval: Duration) -> PersistenceOptions {
+        self.interval = interval;
+        self
+    }
+
+    /// Encrypt the saved caches with ChaCha20-Poly1305 under the given key
+    ///
+    /// Caches that were saved without encryption or with another key are ignored
+    #[cfg(feature = "encryption")]
+    pub fn encryption_key(mut self, key: [u8; 32]) -> PersistenceOptions {
+        self.key = Some(key);
+        self
+    }
+}
+
+
+/// Saved caches of listened locations, one file per location
+///
+/// Each file starts with a SHA-256 of its content, a file that was cut short or corrupted is ignored
+#[derive(Debug)]
+pub(crate) struct SnapshotStore {
+    options: PersistenceOptions,
+}
+
+impl SnapshotStore {
+
+    /// Create the store, creating its directory if needed
+    ///
+    /// # Errors
+    /// Returns an error if the directory can not be created
+    pub(crate) fn new(options: PersistenceOptions) -> Result<SnapshotStore, FirebaseError> {
+        std::fs::create_dir_all(&options.directory).map_err(|e| FirebaseError::new(format!("Snapshot store: {}", e)))?;
+
+        Ok(SnapshotStore {
+            options
+        })
+    }
+
+    /// Get the minimum time between two saves of the same location
+    pub(crate) fn interval(&self) -> Duration {
+        self.options.interval
+    }
+
+    fn file(&self, name: &str) -> PathBuf {
+        self.options.directory.join(format!("{}.snapshot", hex(&Sha256::digest(name.as_bytes()))))
+    }
+
+    /// Load the saved value of the location with the given name, `None` if there is no valid save
+    pub(crate) fn load(&self, name: &str) -> Option<Value> {
+        let bytes = std::fs::read(self.file(name)).ok()?;
+        serde_json::from_slice(&self.decode(&bytes)?).ok()
+    }
+
+    /// Save the value of the location with the given name, replacing the previous save atomically
+    ///
+    /// # Errors
+    /// Returns an error if the file can not be written
+    pub(crate) fn save(&self, name: &str, value: &Value) -> Result<(), FirebaseError> {
+        let bytes = self.encode(serde_json::to_vec(value)?)?;
+        let file = self.file(name);
+        let temporary = file.with_extension("tmp");
+
+        std::fs::write(&temporary, bytes)
+            .and_then(|_| std::fs::rename(&temporary, &file))
+            .map_err(|e| FirebaseError::new(format!("Snapshot store: {}", e)))
+    }
+
+    fn encode(&self, body: Vec<u8>) -> Result<Vec<u8>, FirebaseError> {
+        let (flag, payload) = self.seal(body)?;
+
+        let mut bytes = Vec::with_capacity(HEADER + payload.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.push(flag);
+        bytes.extend_from_slice(&Sha256::digest(&payload));
+        bytes.extend_from_slice(&payload);
+
+        Ok(bytes)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Option<Vec<u8>> {
+        if bytes.len() < HEADER || &bytes[..4] != MAGIC || bytes[4] != VERSION {
+            return None;
+        }
+
+        let (flag, digest, payload) = (bytes[5], &bytes[6..HEADER], &bytes[HEADER..]);
+        if Sha256::digest(payload).as_slice() != digest {
+            return None;
+        }
+
+        self.open(flag, payload)
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    fn seal(&self, body: Vec<u8>) -> Result<(u8, Vec<u8>), FirebaseError> {
+        Ok((PLAIN, body))
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    fn open(&self, flag: u8, payload: &[u8]) -> Option<Vec<u8>> {
+        match flag {
+            PLAIN => Some(payload.to_vec()),
+            _ => None,
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    fn seal(&self, body: Vec<u8>) -> Result<(u8, Vec<u8>), FirebaseError> {
+        use chacha20poly1305::aead::{ Aead, AeadCore, KeyInit, OsRng };
+        use chacha20poly1305::ChaCha20Poly1305;
+
+        let key = match &self.options.key {
+            Some(key) => key,
+            None => return Ok((PLAIN, body)),
+        };
+
+        let cipher = ChaCha20Poly1305::new(key.into());
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher.encrypt(&nonce, body.as_slice()).map_err(|_| FirebaseError::new("Snapshot store: encryption failed"))?;
+
+        Ok((ENCRYPTED, [nonce.as_slice(), &ciphertext].concat()))
+    }
+
+    #[cfg(feature = "encryption")]
+    fn open(&self, flag: u8, payload: &[u8]) -> Option<Vec<u8>> {
+        use chacha20poly1305::aead::{ Aead, KeyInit };
+        use chacha20poly1305::{ ChaCha20Poly1305, Nonce };
+
+        match (flag, &self.options.key) {
+            (PLAIN, None) => Some(payload.to_vec()),
+            (ENCRYPTED, Some(key)) if payload.len() > 12 => {
+                let (nonce, ciphertext) = payload.split_at(12);
+                ChaCha20Poly1305::new(key.into()).decrypt(Nonce::from_slice(nonce), ciphertext).ok()
+            },
+            _ => None,
+        }
+    }
+}
+
+
+/// Get an empty directory for the files of a test, unique to the test and the process
+#[cfg(test)]
+pub(crate) fn test_directory(name: &str) -> PathBuf {
+    let directory = std::env::temp_dir().join(format!("firerust-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&directory);
+    directory
+}
+
+
+/// Get the lowercase hexadecimal form of a digest
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn store(name: &str) -> SnapshotStore {
+        SnapshotStore::new(PersistenceOptions::new(test_directory(name))).unwrap()
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let store = store("snapshots");
+        assert_eq!(store.load("users"), None);
+
+        store.save("users", &json!({"alice": {"age": 30}})).unwrap();
+        assert_eq!(store.load("users"), Some(json!({"alice": {"age": 30}})));
+        assert_eq!(store.load("posts"), None);
+
+        let _ = std::fs::remove_dir_all(&store.options.directory);
+    }
+
+    #[test]
+    fn test_corrupted_file_is_ignored() {
+        let store = store("corrupted");
+        store.save("users", &json!({"alice": 1})).unwrap();
+
+        let file = store.file("users");
+        let mut bytes = std::fs::read(&file).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        std::fs::write(&file, &bytes).unwrap();
+        assert_eq!(store.load("users"), None);
+
+        std::fs::write(&file, &bytes[..HEADER - 1]).unwrap();
+        assert_eq!(store.load("users"), None);
+
+        let _ = std::fs::remove_dir_all(&store.options.directory);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_encrypted_store() {
+        let plain = store("encrypted");
+        let directory = plain.options.directory.clone();
+        let encrypted = SnapshotStore::new(PersistenceOptions::new(&directory).encryption_key([7; 32])).unwrap();
+
+        encrypted.save("users", &json!({"alice": 1})).unwrap();
+        assert_eq!(encrypted.load("users"), Some(json!({"alice": 1})));
+        assert_eq!(plain.load("users"), None);
+        assert!(!String::from_utf8_lossy(&std::fs::read(encrypted.file("users")).unwrap()).contains("alice"));
+
+        let other = SnapshotStore::new(PersistenceOptions::new(&directory).encryption_key([8; 32])).unwrap();
+        assert_eq!(other.load("users"), None);
+
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+}
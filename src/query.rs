@@ -0,0 +1,666 @@
+//! # Example
+//!
+//! ```rust,no_run
+//! use firerust::{FirebaseClient, FirebaseError};
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), FirebaseError> {
+//!     let client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+//!     let snapshot = client.reference("/scores")
+//!         .order_by_value()
+//!         .limit_to_last(3)
+//!         .get_snapshot()
+//!         .await?;
+//!
+//!     for score in snapshot.children() {
+//!         println!("{:?} => {}", score.key(), score.val::<u64>()?);
+//!     }
+//!
+//!     Ok(())
+//! }
+//! ```
+
+
+use crate::{ ChildEvent, DataSnapshot, FirebaseError, FirebaseOrdering, RealtimeReference };
+use futures_util::stream::{ self, BoxStream, Stream, StreamExt };
+use crate::listener::{ SnapshotCache, ListenerHandle };
+use url::form_urlencoded::byte_serialize;
+use serde::de::DeserializeOwned;
+use std::task::{ Context, Poll };
+use crate::connector::Method;
+use serde_json::{ Map, Value };
+use std::pin::Pin;
+use std::sync::Arc;
+
+
+/// How the children of a location are ordered
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum OrderBy {
+    #[default]
+    Key,
+    Value,
+    Priority,
+    Child(String),
+}
+
+impl OrderBy {
+
+    /// Get the value of `orderBy` sent to the server
+    pub fn param(&self) -> String {
+        match self {
+            OrderBy::Key => "$key".to_string(),
+            OrderBy::Value => "$value".to_string(),
+            OrderBy::Priority => "$priority".to_string(),
+            OrderBy::Child(path) => path.trim_matches('/').to_string(),
+        }
+    }
+}
+
+
+/// A query over the children of a database location
+#[derive(Clone)]
+pub struct Query<'a> {
+    reference: RealtimeReference<'a>,
+    order_by: OrderBy,
+    start_at: Option<Value>,
+    end_at: Option<Value>,
+    equal_to: Option<Value>,
+    limit_to_first: Option<u32>,
+    limit_to_last: Option<u32>,
+}
+
+impl<'a> Query<'a> {
+
+    /// Creates a new query over the children of the reference
+    pub fn new(reference: RealtimeReference<'a>, order_by: OrderBy) -> Query<'a> {
+        Query {
+            reference,
+            order_by,
+            start_at: None,
+            end_at: None,
+            equal_to: None,
+            limit_to_first: None,
+            limit_to_last: None,
+        }
+    }
+
+    /// Get the reference the query runs on
+    pub fn reference(&self) -> &RealtimeReference<'a> {
+        &self.reference
+    }
+
+    /// Get the ordering of the query
+    pub fn order_by(&self) -> &OrderBy {
+        &self.order_by
+    }
+
+    /// Only include children ordered at or after the given value
+    pub fn start_at(mut self, value: impl Into<Value>) -> Query<'a> {
+        self.start_at = Some(value.into());
+        self
+    }
+
+    /// Only include children ordered at or before the given value
+    pub fn end_at(mut self, value: impl Into<Value>) -> Query<'a> {
+        self.end_at = Some(value.into());
+        self
+    }
+
+    /// Only include children ordered at the given value
+    pub fn equal_to(mut self, value: impl Into<Value>) -> Query<'a> {
+        self.equal_to = Some(value.into());
+        self
+    }
+
+    /// Only include the first `limit` children
+    pub fn limit_to_first(mut self, limit: u32) -> Query<'a> {
+        self.limit_to_first = Some(limit);
+        self
+    }
+
+    /// Only include the last `limit` children
+    pub fn limit_to_last(mut self, limit: u32) -> Query<'a> {
+        self.limit_to_last = Some(limit);
+        self
+    }
+
+    /// Get the query string sent to the server, including the leading `?`
+    pub fn params(&self) -> String {
+        let mut params = vec![("orderBy", Value::String(self.order_by.param()).to_string())];
+
+        if let Some(value) = &self.start_at { params.push(("startAt", value.to_string())); }
+        if let Some(value) = &self.end_at { params.push(("endAt", value.to_string())); }
+        if let Some(value) = &self.equal_to { params.push(("equalTo", value.to_string())); }
+        if let Some(limit) = self.limit_to_first { params.push(("limitToFirst", limit.to_string())); }
+        if let Some(limit) = self.limit_to_last { params.push(("limitToLast", limit.to_string())); }
+
+        let params = params.iter()
+            .map(|(name, value)| format!("{}={}", name, byte_serialize(value.as_bytes()).collect::<String>()))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        format!("?{}", params)
+    }
+
+    /// Get the value of the query
+    ///
+    /// The order of the children is lost when deserializing into maps, use [`Query::get_snapshot`] to keep it
+    ///
+    /// # Errors
+    /// Returns an error if the value is not a valid Response
+    pub async fn get<T>(&self) -> Result<T, FirebaseError> where T: DeserializeOwned {
+        Ok(serde_json::from_value(self.fetch(&self.params()).await?)?)
+    }
+
+    /// Get the data snapshot of the query, with children in query order
+    ///
+    /// Queries ordered by priority are fetched in export format so the priorities can be used locally
+    ///
+    /// # Errors
+    /// Returns an error if the value is not a valid Response
+    pub async fn get_snapshot(&self) -> Result<DataSnapshot, FirebaseError> {
+        let mut params = self.params();
+        if self.order_by == OrderBy::Priority {
+            params.push_str("&format=export");
+        }
+
+        let value = self.fetch(&params).await?;
+        Ok(DataSnapshot::new(self.reference.key().map(|k| k.to_string()), value).with_order(self.order_by.clone()))
+    }
+
+    /// Listen to the query, getting its ordered results and the child events of every change
+    ///
+    /// The results are kept locally, children that fall out of the range or the limit of the
+    /// query are evicted even if the server does not remove them
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// # use firerust::{FirebaseClient, FirebaseError, ChildEvent};
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), FirebaseError> {
+    ///     let client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+    ///     let listener = client.reference("/scores").order_by_value().limit_to_last(10).on_snapshot(|top, events| {
+    ///         for event in events {
+    ///             if let ChildEvent::Added { snapshot, .. } = event {
+    ///                 println!("{:?} joined the top 10", snapshot.key());
+    ///             }
+    ///         }
+    ///         println!("{} scores", top.size());
+    ///         Ok(())
+    ///     }, |_| {}).await?;
+    ///
+    ///     listener.closed().await;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn on_snapshot<F, E>(&self, callback: F, on_error: E) -> Result<ListenerHandle, FirebaseError> where 
+        F: Send + Sync + 'static,
+        E: Send + Sync + 'static,
+        F: Fn(DataSnapshot, Vec<ChildEvent>) -> Result<(), FirebaseError>,
+        E: Fn(FirebaseError)
+    {
+        let mut params = self.params();
+        if self.order_by == OrderBy::Priority {
+            params.push_str("&format=export");
+        }
+
+        let window = Window::new(self);
+        let order = window.ordering.order_by().clone();
+        let mut delivered = Delivered::new(self.reference.key().map(|k| k.to_string()), order.clone());
+
+        self.reference.listen(Some(&params), Some(Box::new(move |cache| window.evict(cache))), move |snapshot, changes| {
+            Some((snapshot.with_order(order.clone()), changes.is_initial()))
+        }, |older, newer| (newer.0, older.1 || newer.1), move |(snapshot, initial): (DataSnapshot, bool)| {
+            let events = delivered.diff(&snapshot);
+
+            std::future::ready(match events.is_empty() && !initial {
+                true => Ok(()),
+                false => callback(snapshot, events),
+            })
+        }, on_error).await
+    }
+
+    /// Walk the children of the query in pages of `page_size` children
+    ///
+    /// Each page continues after the last child of the previous one, children sharing the same
+    /// ordering value are neither skipped nor repeated. Any limit set on the query is ignored.
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// # use firerust::{FirebaseClient, FirebaseError};
+    /// use futures_util::StreamExt;
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), FirebaseError> {
+    ///     let client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+    ///     let mut pages = client.reference("/users").order_by_key().paginate(100);
+    ///
+    ///     while let Some(page) = pages.next().await {
+    ///         for user in page? {
+    ///             println!("{:?}", user.key());
+    ///         }
+    ///     }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn paginate(&self, page_size: u32) -> Pages<'a> {
+        let mut query = self.clone();
+        query.limit_to_last = None;
+
+        let state = Cursor {
+            query,
+            page_size: page_size.max(1),
+            position: Position::default(),
+        };
+
+        Pages {
+            inner: stream::unfold(state, |mut cursor| async move {
+                if cursor.position.done {
+                    return None;
+                }
+
+                let page = cursor.next_page().await;
+                if page.is_err() {
+                    cursor.position.done = true;
+                }
+
+                match page {
+                    Ok(page) if page.is_empty() => None,
+                    page => Some((page, cursor)),
+                }
+            }).boxed()
+        }
+    }
+
+    async fn fetch(&self, params: &str) -> Result<Value, FirebaseError> {
+        let client = self.reference.client;
+        let response = client.connector.request(Method::Get, &self.reference.path, Some(params), None, client.api_key.as_deref()).await?;
+
+        if response.status().code() != 200 {
+            return Err(FirebaseError::new(format!("{} {}", response.status().code(), response.status().message())));
+        }
+
+        Ok(serde_json::from_str(response.body())?)
+    }
+}
+
+
+/// The range and limit of a query, applied to local results
+struct Window {
+    ordering: FirebaseOrdering,
+    start_at: Option<Value>,
+    end_at: Option<Value>,
+    equal_to: Option<Value>,
+    limit_to_first: Option<u32>,
+    limit_to_last: Option<u32>,
+}
+
+impl Window {
+
+    fn new(query: &Query<'_>) -> Window {
+        Window {
+            ordering: FirebaseOrdering::new(query.order_by.clone()),
+            start_at: query.start_at.clone(),
+            end_at: query.end_at.clone(),
+            equal_to: query.equal_to.clone(),
+            limit_to_first: query.limit_to_first,
+            limit_to_last: query.limit_to_last,
+        }
+    }
+
+    /// Compare a child with a bound of the range
+    fn compare_bound(&self, key: &str, value: &Value, bound: &Value) -> std::cmp::Ordering {
+        match (self.ordering.order_by(), self.ordering.index_value(value)) {
+            (OrderBy::Priority, Some(priority)) => FirebaseOrdering::compare_priorities(priority, bound),
+            (_, Some(index)) => FirebaseOrdering::compare_values(index, bound),
+            (_, None) => match bound {
+                Value::String(bound) => FirebaseOrdering::compare_keys(key, bound),
+                bound => FirebaseOrdering::compare_keys(key, &bound.to_string()),
+            },
+        }
+    }
+
+    fn contains(&self, key: &str, value: &Value) -> bool {
+        let bound = |bound: &Option<Value>| bound.as_ref().map(|b| self.compare_bound(key, value, b));
+
+        bound(&self.start_at).is_none_or(|o| o.is_ge())
+            && bound(&self.end_at).is_none_or(|o| o.is_le())
+            && bound(&self.equal_to).is_none_or(|o| o.is_eq())
+    }
+
+    /// Remove the children outside of the window from the cache
+    fn evict(&self, cache: &mut SnapshotCache) {
+        let snapshot = DataSnapshot::new(None, cache.shared()).with_order(self.ordering.order_by().clone());
+        let (mut inside, mut outside): (Vec<DataSnapshot>, Vec<DataSnapshot>) = snapshot.children()
+            .partition(|child| self.contains(child.key().unwrap_or_default(), child.value()));
+
+        if let Some(limit) = self.limit_to_first {
+            outside.extend(inside.drain((limit as usize).min(inside.len())..));
+        }
+
+        if let Some(limit) = self.limit_to_last {
+            let excess = inside.len().saturating_sub(limit as usize);
+            outside.extend(inside.drain(..excess));
+        }
+
+        let evicted: Vec<String> = outside.iter().filter_map(|child| child.key().map(|k| k.to_string())).collect();
+
+        // Release the shared tree so evicting does not copy it
+        drop((snapshot, inside, outside));
+
+        for key in evicted {
+            cache.apply_put(&key, Value::Null);
+        }
+    }
+}
+
+
+/// The children a query listener delivered last
+///
+/// They are kept apart from the cache of the listener so applying events never copies the cache,
+/// only the children that changed are copied again
+struct Delivered {
+    key: Option<String>,
+    order: OrderBy,
+    children: Arc<Value>,
+}
+
+impl Delivered {
+
+    fn new(key: Option<String>, order: OrderBy) -> Delivered {
+        Delivered {
+            key,
+            order,
+            children: Arc::new(Value::Object(Map::new())),
+        }
+    }
+
+    /// Get the child events that turn the children delivered last into the snapshot, and remember its children
+    fn diff(&mut self, snapshot: &DataSnapshot) -> Vec<ChildEvent> {
+        let previous = DataSnapshot::new(self.key.clone(), self.children.clone()).with_order(self.order.clone());
+        let mut events = snapshot.diff_children(&previous);
+        drop(previous);
+
+        // Removed children get their own copy so the delivered children are not shared with the callback
+        for event in &mut events {
+            if let ChildEvent::Removed { snapshot } = event {
+                let key = snapshot.key().map(|k| k.to_string());
+                *snapshot = DataSnapshot::new(key, snapshot.value().clone()).with_order(self.order.clone());
+            }
+        }
+
+        if let Value::Object(children) = Arc::make_mut(&mut self.children) {
+            for event in &events {
+                let key = event.snapshot().key().unwrap_or_default().to_string();
+                match event {
+                    ChildEvent::Removed { .. } => children.remove(&key),
+                    _ => children.insert(key, event.snapshot().value().clone()),
+                };
+            }
+        }
+
+        events
+    }
+}
+
+
+/// Stream of pages of a query, see [`Query::paginate`]
+pub struct Pages<'a> {
+    inner: BoxStream<'a, Result<Vec<DataSnapshot>, FirebaseError>>,
+}
+
+impl<'a> Pages<'a> {
+
+    /// Flatten the pages into a stream of children
+    pub fn items(self) -> impl Stream<Item = Result<DataSnapshot, FirebaseError>> + 'a {
+        self.inner.flat_map(|page| match page {
+            Ok(page) => stream::iter(page.into_iter().map(Ok).collect::<Vec<_>>()),
+            Err(e) => stream::iter(vec![Err(e)]),
+        })
+    }
+}
+
+impl Stream for Pages<'_> {
+    type Item = Result<Vec<DataSnapshot>, FirebaseError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+}
+
+
+struct Cursor<'a> {
+    query: Query<'a>,
+    page_size: u32,
+    position: Position,
+}
+
+impl Cursor<'_> {
+
+    async fn next_page(&mut self) -> Result<Vec<DataSnapshot>, FirebaseError> {
+        let ordering = FirebaseOrdering::new(self.query.order_by.clone());
+        let (start, limit) = self.position.next(&ordering, self.page_size)?;
+
+        let mut query = self.query.clone();
+        query.limit_to_first = Some(limit);
+        if start.is_some() {
+            query.start_at = start;
+        }
+
+        let snapshot = query.get_snapshot().await?;
+        Ok(self.position.advance(&ordering, &snapshot, limit))
+    }
+}
+
+
+/// Where a pagination stands, updated from the children the server returns for each page
+#[derive(Default)]
+struct Position {
+    last: Option<DataSnapshot>,
+    /// Children of the previous pages tied with the last one, the server returns them again
+    ties: u32,
+    done: bool,
+}
+
+impl Position {
+
+    /// Get the `startAt` and `limitToFirst` of the next page
+    fn next(&self, ordering: &FirebaseOrdering, page_size: u32) -> Result<(Option<Value>, u32), FirebaseError> {
+        // Children tied with the last one come back again, ask for enough to fill the page past them
+        let limit = page_size + self.ties;
+
+        let start = match &self.last {
+            Some(last) => Some(match ordering.index_value(last.value()) {
+                Some(Value::Array(_) | Value::Object(_)) => return Err(FirebaseError::new("Can not paginate past children ordered by an object")),
+                Some(value) => value.clone(),
+                None => Value::String(last.key().unwrap_or_default().to_string()),
+            }),
+            None => None,
+        };
+
+        Ok((start, limit))
+    }
+
+    /// Get the page out of the children returned for it, skipping the children of previous pages
+    fn advance(&mut self, ordering: &FirebaseOrdering, snapshot: &DataSnapshot, limit: u32) -> Vec<DataSnapshot> {
+        let returned = snapshot.size() as u32;
+
+        let page: Vec<DataSnapshot> = snapshot.children()
+            .filter(|child| match &self.last {
+                Some(last) => ordering.compare((child.key().unwrap_or_default(), child.value()), (last.key().unwrap_or_default(), last.value())).is_gt(),
+                None => true,
+            })
+            .collect();
+
+        self.done = returned < limit || page.is_empty();
+
+        if let Some(last) = page.last() {
+            let tied = |child: &DataSnapshot| ordering.compare_index(child.value(), last.value()).is_eq();
+            let page_ties = page.iter().rev().take_while(|child| tied(child)).count() as u32;
+
+            // Starting at a key only returns that key again, starting at a value returns every child tied on it
+            self.ties = match (ordering.order_by(), &self.last) {
+                (OrderBy::Key, _) => 1,
+                (_, Some(previous)) if page_ties as usize == page.len() && tied(previous) => self.ties + page_ties,
+                _ => page_ties,
+            };
+            self.last = Some(last.clone());
+        }
+
+        page
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_params() {
+        let client = crate::FirebaseClient::new("https://docs-examples.firebaseio.com/").unwrap();
+        let query = client.reference("/users").order_by_priority().start_at("a").limit_to_first(2);
+
+        assert_eq!(query.params(), "?orderBy=%22%24priority%22&startAt=%22a%22&limitToFirst=2");
+    }
+
+    #[test]
+    fn test_window_evicts_outside_limit() {
+        let client = crate::FirebaseClient::new("https://docs-examples.firebaseio.com/").unwrap();
+        let query = client.reference("/scores").order_by_value().start_at(2).limit_to_last(2);
+        let mut cache = SnapshotCache::new(json!({"a": 1, "b": 5, "c": 3, "d": 4}));
+
+        Window::new(&query).evict(&mut cache);
+        assert_eq!(cache.value(), &json!({"b": 5, "d": 4}));
+
+        cache.apply_put("e", json!(10));
+        Window::new(&query).evict(&mut cache);
+        assert_eq!(cache.value(), &json!({"b": 5, "e": 10}));
+    }
+
+    #[test]
+    fn test_window_key_range() {
+        let client = crate::FirebaseClient::new("https://docs-examples.firebaseio.com/").unwrap();
+        let query = client.reference("/items").order_by_key().start_at("b").end_at("c");
+        let mut cache = SnapshotCache::new(json!({"a": 1, "b": 2, "c": 3, "d": 4}));
+
+        Window::new(&query).evict(&mut cache);
+        assert_eq!(cache.value(), &json!({"b": 2, "c": 3}));
+    }
+
+    #[test]
+    fn test_order_by_priority() {
+        let snapshot = DataSnapshot::new(None, json!({
+            "a": {".value": 1, ".priority": "x"},
+            "b": {".value": 2, ".priority": 5},
+            "c": {".value": 3},
+            "d": {".value": 4, ".priority": 1},
+        })).with_order(OrderBy::Priority);
+
+        let keys: Vec<String> = snapshot.children().map(|c| c.key().unwrap().to_string()).collect();
+        assert_eq!(keys, vec!["c", "d", "b", "a"]);
+    }
+
+    #[test]
+    fn test_order_by_child() {
+        let snapshot = DataSnapshot::new(None, json!({
+            "a": {"age": 30},
+            "b": {"age": 20},
+            "c": {},
+        })).with_order(OrderBy::Child("age".to_string()));
+
+        let keys: Vec<String> = snapshot.children().map(|c| c.key().unwrap().to_string()).collect();
+        assert_eq!(keys, vec!["c", "b", "a"]);
+    }
+
+    /// Walk `data` in pages the way the server answers `startAt` and `limitToFirst`
+    fn paginate_locally(data: Value, order_by: OrderBy, page_size: u32) -> Vec<Vec<String>> {
+        let ordering = FirebaseOrdering::new(order_by.clone());
+        let mut position = Position::default();
+        let mut pages = Vec::new();
+
+        while !position.done {
+            let (start_at, limit) = position.next(&ordering, page_size).unwrap();
+            let window = Window {
+                ordering: ordering.clone(),
+                start_at,
+                end_at: None,
+                equal_to: None,
+                limit_to_first: Some(limit),
+                limit_to_last: None,
+            };
+
+            let mut cache = SnapshotCache::new(data.clone());
+            window.evict(&mut cache);
+            let snapshot = DataSnapshot::new(None, cache.value().clone()).with_order(order_by.clone());
+
+            let page = position.advance(&ordering, &snapshot, limit);
+            if !page.is_empty() {
+                pages.push(page.iter().map(|child| child.key().unwrap().to_string()).collect());
+            }
+        }
+
+        pages
+    }
+
+    #[test]
+    fn test_paginate_by_key() {
+        let data = json!({"a": 1, "b": 1, "c": 1, "d": 1, "e": 1});
+
+        assert_eq!(paginate_locally(data, OrderBy::Key, 2), vec![vec!["a", "b"], vec!["c", "d"], vec!["e"]]);
+    }
+
+    #[test]
+    fn test_paginate_by_value_with_ties() {
+        // The run of ties on 1 spans three pages
+        let data = json!({"a": 0, "b": 1, "c": 1, "d": 1, "e": 1, "f": 1, "g": 2, "h": 2});
+
+        assert_eq!(paginate_locally(data.clone(), OrderBy::Value, 2), vec![
+            vec!["a", "b"], vec!["c", "d"], vec!["e", "f"], vec!["g", "h"],
+        ]);
+        assert_eq!(paginate_locally(data, OrderBy::Value, 3), vec![
+            vec!["a", "b", "c"], vec!["d", "e", "f"], vec!["g", "h"],
+        ]);
+    }
+
+    #[test]
+    fn test_paginate_by_child_with_ties() {
+        let data = json!({
+            "a": {"n": 2}, "b": {"n": 1}, "c": {"n": 2}, "d": {"n": 2}, "e": {"n": 1}, "f": {"n": 3},
+        });
+
+        assert_eq!(paginate_locally(data, OrderBy::Child("n".into()), 2), vec![
+            vec!["b", "e"], vec!["a", "c"], vec!["d", "f"],
+        ]);
+    }
+
+    #[test]
+    fn test_delivered_copies_only_changes() {
+        let mut delivered = Delivered::new(Some("scores".into()), OrderBy::Value);
+        let mut cache = SnapshotCache::new(json!({"a": 1, "b": 2}));
+
+        let events = delivered.diff(&DataSnapshot::new(None, cache.shared()).with_order(OrderBy::Value));
+        assert_eq!(events.len(), 2);
+        drop(events);
+
+        cache.apply_put("a", json!(3));
+        cache.apply_put("b", Value::Null);
+        cache.apply_put("c", json!(0));
+        let events = delivered.diff(&DataSnapshot::new(None, cache.shared()).with_order(OrderBy::Value));
+
+        let names: Vec<String> = events.iter().map(|e| match e {
+            ChildEvent::Added { snapshot, .. } => format!("added {}", snapshot.key().unwrap()),
+            ChildEvent::Removed { snapshot } => format!("removed {} {}", snapshot.key().unwrap(), snapshot.value()),
+            ChildEvent::Changed { snapshot, .. } => format!("changed {}", snapshot.key().unwrap()),
+            ChildEvent::Moved { snapshot, .. } => format!("moved {}", snapshot.key().unwrap()),
+        }).collect();
+        assert_eq!(names, vec!["removed b 2", "added c", "moved a", "changed a"]);
+
+        // Once the callback is done with the events, only the cache holds its tree
+        drop(events);
+        assert_eq!(Arc::strong_count(&cache.shared()), 2);
+        assert_eq!(*delivered.children, json!({"a": 3, "c": 0}));
+        assert!(delivered.diff(&DataSnapshot::new(None, cache.shared()).with_order(OrderBy::Value)).is_empty());
+    }
+}
@@ -0,0 +1,1074 @@
+use crate::connector::{ Connector, SseDecoder, EventType };
+use crate::listener::{ SnapshotCache, ChangeSet, ListenerStatus };
+use tokio::time::{ Duration, Instant };
+use crate::{ tree, DataSnapshot, FirebaseClient, FirebaseError };
+use crate::persist::{ hex, SnapshotStore };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use std::collections::HashMap;
+use std::sync::{ Arc, Mutex };
+use tokio::sync::{ watch, Notify };
+use sha2::{ Digest, Sha256 };
+use serde_json::Value;
+
+
+pub(crate) type Deliver = Box<dyn FnMut(DataSnapshot, &ChangeSet) -> Option<BoxFuture<'static, ()>> + Send>;
+pub(crate) type Prepare = Box<dyn FnMut(&mut SnapshotCache) + Send>;
+pub(crate) type OnError = Arc<dyn Fn(FirebaseError) + Send + Sync>;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+
+/// Identifies an upstream stream, listeners with the same key can share it
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct StreamKey {
+    path: String,
+    params: Option<String>,
+    auth: Option<String>,
+}
+
+impl StreamKey {
+
+    /// Returns true if a stream with this key carries every event of `other`
+    fn covers(&self, other: &StreamKey) -> bool {
+        if self.auth != other.auth {
+            return false;
+        }
+
+        match (&self.params, &other.params) {
+            (None, None) => relative(&self.path, &other.path).is_some(),
+            (a, b) => a == b && self.path == other.path,
+        }
+    }
+}
+
+
+/// A listener fed by an upstream stream
+pub(crate) struct Subscriber {
+    id: u64,
+    path: String,
+    deliver: Arc<Mutex<Deliver>>,
+    close: Box<dyn Fn() + Send>,
+    on_error: OnError,
+    /// Update waiting for room in the queue of the listener, with the updates that arrived meanwhile
+    pending: Arc<Mutex<Pending>>,
+}
+
+/// Updates of a subscriber that could not be delivered right away
+#[derive(Default)]
+struct Pending {
+    /// An update is being delivered in the background
+    delivering: bool,
+    /// The updates that arrived during that delivery, merged into the latest snapshot
+    latest: Option<(DataSnapshot, ChangeSet)>,
+}
+
+impl Subscriber {
+
+    /// Create a subscriber, `deliver` hands each update to the listener and `close` tells it that
+    /// no update will follow
+    pub(crate) fn new(deliver: Deliver, close: Box<dyn Fn() + Send>, on_error: OnError) -> Subscriber {
+        Subscriber {
+            id: 0,
+            path: String::new(),
+            deliver: Arc::new(Mutex::new(deliver)),
+            close,
+            on_error,
+            pending: Arc::new(Mutex::new(Pending::default())),
+        }
+    }
+
+    fn send(&mut self, snapshots: &mut Snapshots<'_>, changes: &ChangeSet) -> Option<BoxFuture<'static, ()>> {
+        let changes = changes.relative_to(&self.path);
+        if changes.is_empty() {
+            return None;
+        }
+
+        let snapshot = snapshots.get(&self.path);
+        (self.deliver.lock().unwrap_or_else(|e| e.into_inner()))(snapshot, &changes)
+    }
+
+    /// Hand an update to the listener without waiting for room in its queue
+    ///
+    /// An update the queue can not take yet is delivered in the background. The updates arriving
+    /// meanwhile are merged into a single pending one, so a slow listener holds at most one update
+    /// outside of its queue and never holds back the other listeners of the stream.
+    fn forward(&mut self, snapshots: &mut Snapshots<'_>, changes: &ChangeSet) {
+        let changes = changes.relative_to(&self.path);
+        if changes.is_empty() {
+            return;
+        }
+
+        let snapshot = snapshots.get(&self.path);
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+
+        if pending.delivering {
+            let changes = match pending.latest.take() {
+                Some((_, older)) => older.merge(changes),
+                None => changes,
+            };
+            pending.latest = Some((snapshot, changes));
+            return;
+        }
+
+        let mut update = match (self.deliver.lock().unwrap_or_else(|e| e.into_inner()))(snapshot, &changes) {
+            Some(update) => update,
+            None => return,
+        };
+
+        if (&mut update).now_or_never().is_some() {
+            return;
+        }
+
+        pending.delivering = true;
+        let (deliver, pending) = (self.deliver.clone(), self.pending.clone());
+
+        tokio::spawn(async move {
+            update.await;
+
+            loop {
+                let latest = {
+                    let mut pending = pending.lock().unwrap_or_else(|e| e.into_inner());
+                    match pending.latest.take() {
+                        Some(latest) => latest,
+                        None => {
+                            pending.delivering = false;
+                            return;
+                        },
+                    }
+                };
+
+                let update = (deliver.lock().unwrap_or_else(|e| e.into_inner()))(latest.0, &latest.1);
+                if let Some(update) = update {
+                    update.await;
+                }
+            }
+        });
+    }
+}
+
+
+/// The snapshots handed to the listeners of a stream for one update
+///
+/// Each listened location is copied out of the cache once, whatever its number of listeners, so
+/// updates waiting for a callback do not keep the whole tree of the stream alive
+struct Snapshots<'c> {
+    key: Option<&'c str>,
+    cache: &'c SnapshotCache,
+    taken: HashMap<String, DataSnapshot>,
+}
+
+impl<'c> Snapshots<'c> {
+
+    fn new(key: Option<&'c str>, cache: &'c SnapshotCache) -> Snapshots<'c> {
+        Snapshots {
+            key,
+            cache,
+            taken: HashMap::new(),
+        }
+    }
+
+    fn get(&mut self, path: &str) -> DataSnapshot {
+        let (key, cache) = (self.key, self.cache);
+        self.taken.entry(path.to_string()).or_insert_with(|| cache.snapshot(key, path)).clone()
+    }
+}
+
+
+struct Stream {
+    key: StreamKey,
+    state: Mutex<StreamState>,
+    cancel: watch::Sender<bool>,
+    status: watch::Sender<ListenerStatus>,
+    /// Asks the stream to reconnect, so the server replaces the cache
+    resync: Notify,
+}
+
+#[derive(Default)]
+struct StreamState {
+    cache: Option<SnapshotCache>,
+    subscribers: Vec<Subscriber>,
+    closed: bool,
+    /// The cache was saved by a previous run and the server did not send its first put yet
+    stale: bool,
+}
+
+impl Stream {
+
+    fn state(&self) -> std::sync::MutexGuard<'_, StreamState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn name(&self) -> Option<&str> {
+        self.key.path.rsplit('/').find(|s| !s.is_empty())
+    }
+}
+
+
+/// Keeps one upstream stream per listened location and fans its events out to every listener
+/// of the location or of its descendants
+#[derive(Default)]
+pub(crate) struct ListenerRegistry {
+    streams: Mutex<HashMap<StreamKey, Arc<Stream>>>,
+    next_id: AtomicU64,
+}
+
+impl ListenerRegistry {
+
+    /// Get the number of upstream streams currently open
+    pub(crate) fn len(&self) -> usize {
+        self.streams().len()
+    }
+
+    /// Add a listener to the stream covering the location, opening a new stream if there is none
+    ///
+    /// `prepare` runs on the cache of a new stream after every event, before the listeners see it
+    pub(crate) async fn subscribe(self: &Arc<Self>, client: &FirebaseClient, path: &str, params: Option<&str>, prepare: Option<Prepare>, mut subscriber: Subscriber) -> Result<Subscription, FirebaseError> {
+        let key = StreamKey {
+            path: normalize(path),
+            params: params.map(|p| p.to_string()),
+            auth: client.api_key.clone(),
+        };
+        subscriber.id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+        let subscriber = match self.join(&key, subscriber) {
+            Ok(subscription) => return Ok(subscription),
+            Err(subscriber) => subscriber,
+        };
+
+        let connection = Connection {
+            connector: client.connector.clone(),
+            path: path.to_string(),
+            params: params.map(|p| p.to_string()),
+            api_key: client.api_key.clone(),
+            idle_timeout: client.idle_timeout,
+        };
+
+        // A saved cache lets the stream start without the server, it reconnects in the background
+        let saver = Saver::new(client.persistence.clone(), client.connector.base_url(), &key);
+        let saved = saver.load();
+        let res = match (connection.connect().await, &saved) {
+            (Ok(res), _) => Some(res),
+            (Err(_), Some(_)) => None,
+            (Err(e), None) => return Err(e),
+        };
+
+        // Another listener may have opened the same stream while connecting
+        let mut subscriber = match self.join(&key, subscriber) {
+            Ok(subscription) => return Ok(subscription),
+            Err(subscriber) => subscriber,
+        };
+        subscriber.path = String::new();
+
+        let id = subscriber.id;
+        let stale = saved.is_some();
+        let stream = Arc::new(Stream {
+            key: key.clone(),
+            state: Mutex::new(StreamState { cache: saved.map(SnapshotCache::new), stale, ..StreamState::default() }),
+            cancel: watch::channel(false).0,
+            status: watch::channel(if stale { ListenerStatus::Stale } else { ListenerStatus::Live }).0,
+            resync: Notify::new(),
+        });
+
+        {
+            let mut state = stream.state();
+            if let Some(initial) = state.cache.as_ref().and_then(|cache| subscriber.send(&mut Snapshots::new(stream.name(), cache), &ChangeSet::initial())) {
+                initial.now_or_never();
+            }
+            state.subscribers.push(subscriber);
+        }
+        self.streams().insert(key, stream.clone());
+
+        let registry = self.clone();
+        let (mut cancel, mut shutdown) = (stream.cancel.subscribe(), client.shutdown.subscribe());
+        let upstream = stream.clone();
+
+        tokio::spawn(async move {
+            registry.run(&upstream, connection, res, prepare, saver, &mut cancel, &mut shutdown).await;
+            upstream.status.send_replace(ListenerStatus::Closed);
+
+            let subscribers = {
+                let mut state = upstream.state();
+                state.closed = true;
+                std::mem::take(&mut state.subscribers)
+            };
+
+            for subscriber in subscribers {
+                (subscriber.close)();
+            }
+
+            registry.remove(&upstream);
+        });
+
+        Ok(Subscription {
+            registry: self.clone(),
+            stream,
+            id,
+        })
+    }
+
+    fn streams(&self) -> std::sync::MutexGuard<'_, HashMap<StreamKey, Arc<Stream>>> {
+        self.streams.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Get the running stream covering the location, the shallowest one if several do
+    fn covering(&self, key: &StreamKey) -> Option<Arc<Stream>> {
+        self.streams().values()
+            .filter(|stream| stream.key.covers(key))
+            .min_by_key(|stream| stream.key.path.len())
+            .cloned()
+    }
+
+    /// Read the value at the given path from the cache of a connected stream covering it
+    pub(crate) fn lookup(&self, path: &str, auth: Option<&str>) -> Option<Value> {
+        let key = StreamKey {
+            path: normalize(path),
+            params: None,
+            auth: auth.map(|a| a.to_string()),
+        };
+
+        let stream = self.covering(&key)?;
+        if *stream.status.borrow() != ListenerStatus::Live {
+            return None;
+        }
+
+        let state = stream.state();
+        let cache = state.cache.as_ref().filter(|_| !state.closed)?;
+
+        relative(&stream.key.path, &key.path).map(|below| descend(cache.value(), below))
+    }
+
+    /// Add the listener to a running stream covering the location, the shallowest one if several do
+    fn join(self: &Arc<Self>, key: &StreamKey, mut subscriber: Subscriber) -> Result<Subscription, Subscriber> {
+        let stream = match self.covering(key) {
+            Some(stream) => stream,
+            None => return Err(subscriber),
+        };
+
+        let mut state = stream.state();
+        if state.closed {
+            return Err(subscriber);
+        }
+
+        subscriber.path = relative(&stream.key.path, &key.path).unwrap_or_default().to_string();
+
+        // The queue of a new listener is empty, the initial snapshot is queued without waiting
+        if let Some(cache) = &state.cache {
+            if let Some(initial) = subscriber.send(&mut Snapshots::new(stream.name(), cache), &ChangeSet::initial()) {
+                initial.now_or_never();
+            }
+        }
+
+        let id = subscriber.id;
+        state.subscribers.push(subscriber);
+        drop(state);
+
+        Ok(Subscription {
+            registry: self.clone(),
+            stream,
+            id,
+        })
+    }
+
+    /// Apply a local write to the caches of the streams it touches, before the server confirms it
+    ///
+    /// `merge` applies each child of `data` like a `PATCH`
+    pub(crate) fn apply_local(&self, path: &str, data: &Value, merge: bool) {
+        let writes: Vec<(String, &Value)> = match (merge, data) {
+            (true, Value::Object(map)) => map.iter().map(|(key, value)| (normalize(&format!("{}/{}", path, key)), value)).collect(),
+            _ => vec![(normalize(path), data)],
+        };
+
+        // Query streams only hold a window of their location, they are left to the server
+        let streams: Vec<Arc<Stream>> = self.streams().values().filter(|stream| stream.key.params.is_none()).cloned().collect();
+
+        for stream in streams {
+            let mut state = stream.state();
+            let state = &mut *state;
+
+            let cache = match state.cache.as_mut() {
+                Some(cache) => cache,
+                None => continue,
+            };
+
+            let mut changes = ChangeSet::default();
+            for (write, value) in &writes {
+                if let Some(relative) = relative(&stream.key.path, write) {
+                    changes = changes.merge(cache.apply_put(relative, (*value).clone()));
+                } else if let Some(below) = relative(write, &stream.key.path) {
+                    changes = changes.merge(cache.apply_put("", descend(value, below)));
+                }
+            }
+
+            if changes.is_empty() {
+                continue;
+            }
+
+            let mut snapshots = Snapshots::new(stream.name(), cache);
+            for subscriber in &mut state.subscribers {
+                subscriber.forward(&mut snapshots, &changes);
+            }
+        }
+    }
+
+    /// Open a live stream serving the given value without a server
+    #[cfg(test)]
+    pub(crate) fn open_local(&self, path: &str, value: Value) {
+        let key = StreamKey {
+            path: normalize(path),
+            params: None,
+            auth: None,
+        };
+
+        let stream = Arc::new(Stream {
+            key: key.clone(),
+            state: Mutex::new(StreamState { cache: Some(SnapshotCache::new(value)), ..StreamState::default() }),
+            cancel: watch::channel(false).0,
+            status: watch::channel(ListenerStatus::Live).0,
+            resync: Notify::new(),
+        });
+        self.streams().insert(key, stream);
+    }
+
+    /// Reload the streams a write was applied to from the server, after the server rejected it
+    pub(crate) fn resync(&self, path: &str) {
+        let path = normalize(path);
+
+        for stream in self.streams().values().filter(|stream| stream.key.params.is_none()) {
+            if relative(&stream.key.path, &path).is_some() || relative(&path, &stream.key.path).is_some() {
+                stream.resync.notify_waiters();
+            }
+        }
+    }
+
+    fn remove(&self, stream: &Arc<Stream>) {
+        let mut streams = self.streams();
+
+        if streams.get(&stream.key).is_some_and(|current| Arc::ptr_eq(current, stream)) {
+            streams.remove(&stream.key);
+        }
+    }
+
+    /// Keep the stream connected, reconnecting when the connection is lost or stays idle for too long
+    #[allow(clippy::too_many_arguments)]
+    async fn run(&self, stream: &Stream, connection: Connection, mut res: Option<reqwest::Response>, mut prepare: Option<Prepare>, mut saver: Saver, cancel: &mut watch::Receiver<bool>, shutdown: &mut watch::Receiver<bool>) {
+        let mut retry = None;
+
+        loop {
+            if let Some(res) = res.take() {
+                let (error, requested) = match self.read(stream, res, &mut prepare, &mut saver, connection.idle_timeout, cancel, shutdown).await {
+                    Some(lost) => lost,
+                    None => break,
+                };
+
+                report(stream, error);
+                retry = requested;
+            }
+
+            set_status(stream, ListenerStatus::Reconnecting);
+
+            let mut delay = retry.unwrap_or(RECONNECT_DELAY);
+            res = loop {
+                tokio::select! {
+                    _ = cancel.wait_for(|cancelled| *cancelled) => break None,
+                    _ = shutdown.wait_for(|shutdown| *shutdown) => break None,
+                    _ = tokio::time::sleep(delay) => {},
+                }
+
+                match connection.connect().await {
+                    Ok(res) => break Some(res),
+                    Err(e) => report(stream, e),
+                }
+
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+            };
+
+            if res.is_none() {
+                break;
+            }
+
+            set_status(stream, ListenerStatus::Live);
+        }
+
+        if let Some(save) = saver.flush(stream) {
+            let _ = save.await;
+        }
+    }
+
+    /// Read a connection, applying its events to the shared cache and handing them to the listeners
+    ///
+    /// Returns the reason and the retry delay requested by the server when the connection is lost,
+    /// `None` when the stream is over
+    #[allow(clippy::too_many_arguments)]
+    async fn read(&self, stream: &Stream, res: reqwest::Response, prepare: &mut Option<Prepare>, saver: &mut Saver, idle_timeout: Duration, cancel: &mut watch::Receiver<bool>, shutdown: &mut watch::Receiver<bool>) -> Option<(FirebaseError, Option<Duration>)> {
+        use futures_util::StreamExt;
+        let mut body = res.bytes_stream();
+        let mut decoder = SseDecoder::new();
+        let mut last_event = Instant::now();
+
+        // Created once so a resync asked while an event is being applied is not missed
+        let resync = stream.resync.notified();
+        tokio::pin!(resync);
+
+        loop {
+            let chunk_res = tokio::select! {
+                _ = cancel.wait_for(|cancelled| *cancelled) => return None,
+                _ = shutdown.wait_for(|shutdown| *shutdown) => return None,
+                _ = &mut resync => {
+                    let error = FirebaseError::new("A local write was rejected by the server, reloading");
+                    return Some((error, Some(Duration::ZERO)));
+                },
+                _ = tokio::time::sleep_until(last_event + idle_timeout) => {
+                    let error = FirebaseError::new(format!("No event received for {:?}, reconnecting", idle_timeout));
+                    return Some((error, decoder.retry()));
+                },
+                _ = tokio::time::sleep_until(saver.next), if saver.dirty => {
+                    saver.flush(stream);
+                    continue;
+                },
+                chunk_res = body.next() => match chunk_res {
+                    Some(chunk_res) => chunk_res,
+                    None => return None,
+                },
+            };
+
+            let chunk = match chunk_res {
+                Ok(c) => c,
+                Err(e) => return Some((FirebaseError::new(e.to_string()), decoder.retry())),
+            };
+            last_event = Instant::now();
+
+            for event_stream in decoder.feed(&chunk) {
+                match event_stream.event() {
+                    EventType::Cancel | EventType::AuthRevoked => {
+                        // The listeners are closed, they learn why before it happens
+                        if let Some(error) = ended(event_stream.event(), event_stream.data()) {
+                            report(stream, error);
+                        }
+                        return None;
+                    },
+                    EventType::KeepAlive | EventType::Unknown(_) => continue,
+                    EventType::Put | EventType::Patch => {}
+                }
+
+                let mut data = match serde_json::from_str::<Value>(event_stream.data()) {
+                    Ok(data) => data,
+                    Err(e) => { report(stream, FirebaseError::new(e.to_string())); continue; }
+                };
+
+                let path = match data["path"].as_str() {
+                    Some(path) => path.to_string(),
+                    None => continue
+                };
+
+                let snapshot = match data.get_mut("data") {
+                    Some(s) => s.take(),
+                    None => continue
+                };
+
+                {
+                    let mut state = stream.state();
+                    let state = &mut *state;
+
+                    let changes = match (event_stream.event(), state.cache.as_mut()) {
+                        (EventType::Put, None) => {
+                            state.cache = Some(SnapshotCache::new(snapshot));
+                            ChangeSet::initial()
+                        },
+                        // The first put of the server replaces the saved cache, listeners only hear of it if it differs
+                        (EventType::Put, Some(current)) if state.stale => {
+                            state.stale = false;
+                            stream.status.send_replace(ListenerStatus::Live);
+
+                            match path.trim_matches('/').is_empty() && tree::normalize(snapshot.clone()) == *current.value() {
+                                true => ChangeSet::default(),
+                                false => current.apply_put(&path, snapshot),
+                            }
+                        },
+                        (EventType::Put, Some(current)) => current.apply_put(&path, snapshot),
+                        (EventType::Patch, Some(current)) => current.apply_patch(&path, snapshot),
+                        _ => continue,
+                    };
+
+                    let cache = match state.cache.as_mut() {
+                        Some(cache) if !changes.is_empty() => cache,
+                        _ => continue,
+                    };
+                    saver.mark();
+
+                    if let Some(prepare) = prepare.as_mut() {
+                        prepare(cache);
+                    }
+
+                    let mut snapshots = Snapshots::new(stream.name(), cache);
+                    for subscriber in &mut state.subscribers {
+                        subscriber.forward(&mut snapshots, &changes);
+                    }
+                }
+
+                if saver.is_due() {
+                    saver.flush(stream);
+                }
+            }
+        }
+    }
+}
+
+
+/// Saves the cache of a stream to the snapshot store, at most once per interval of the store
+struct Saver {
+    store: Option<Arc<SnapshotStore>>,
+    name: String,
+    dirty: bool,
+    next: Instant,
+}
+
+impl Saver {
+
+    /// Create a saver for the stream with the given key on the given database
+    ///
+    /// The name holds the database and a digest of the credentials, a cache saved by another user or for
+    /// another database is never loaded
+    fn new(store: Option<Arc<SnapshotStore>>, base_url: &str, key: &StreamKey) -> Saver {
+        let auth = key.auth.as_ref().map(|auth| hex(&Sha256::digest(auth.as_bytes())));
+
+        Saver {
+            store,
+            name: format!("{} {}?{} {}", base_url, key.path, key.params.as_deref().unwrap_or_default(), auth.unwrap_or_default()),
+            dirty: false,
+            next: Instant::now(),
+        }
+    }
+
+    /// Load the cache saved by a previous run
+    fn load(&self) -> Option<Value> {
+        self.store.as_ref().and_then(|store| store.load(&self.name))
+    }
+
+    /// Remember that the cache changed since the last save
+    fn mark(&mut self) {
+        self.dirty = self.store.is_some();
+    }
+
+    fn is_due(&self) -> bool {
+        self.dirty && Instant::now() >= self.next
+    }
+
+    /// Save the cache in the background
+    fn flush(&mut self, stream: &Stream) -> Option<tokio::task::JoinHandle<()>> {
+        let store = self.store.clone().filter(|_| self.dirty)?;
+        let value = stream.state().cache.as_ref()?.shared();
+        let name = self.name.clone();
+
+        self.dirty = false;
+        self.next = Instant::now() + store.interval();
+
+        Some(tokio::task::spawn_blocking(move || { let _ = store.save(&name, &value); }))
+    }
+}
+
+
+/// What is needed to open the stream again
+struct Connection {
+    connector: Connector,
+    path: String,
+    params: Option<String>,
+    api_key: Option<String>,
+    idle_timeout: Duration,
+}
+
+impl Connection {
+
+    async fn connect(&self) -> Result<reqwest::Response, FirebaseError> {
+        let res = self.connector.event_stream(&self.path, self.params.as_deref(), self.api_key.as_deref()).await?;
+
+        match res.status().as_u16() {
+            200 => Ok(res),
+            status => Err(FirebaseError::new(format!("{} {}", status, res.status().canonical_reason().unwrap_or("Unknown")))),
+        }
+    }
+}
+
+
+/// Membership of a listener in a stream, the listener leaves the stream when it is dropped and
+/// the stream is closed when its last listener leaves
+pub(crate) struct Subscription {
+    registry: Arc<ListenerRegistry>,
+    stream: Arc<Stream>,
+    id: u64,
+}
+
+impl Subscription {
+
+    /// Watch the connection status of the stream
+    pub(crate) fn status(&self) -> watch::Receiver<ListenerStatus> {
+        self.stream.status.subscribe()
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut state = self.stream.state();
+
+        if let Some(i) = state.subscribers.iter().position(|s| s.id == self.id) {
+            let subscriber = state.subscribers.remove(i);
+            (subscriber.close)();
+        }
+
+        if state.subscribers.is_empty() && !state.closed {
+            state.closed = true;
+            self.stream.cancel.send_replace(true);
+            drop(state);
+            self.registry.remove(&self.stream);
+        }
+    }
+}
+
+
+/// Change the status of a stream, a stream serving a saved cache stays stale until the server's
+/// first put whatever happens to the connection
+fn set_status(stream: &Stream, status: ListenerStatus) {
+    stream.status.send_if_modified(|current| {
+        let changed = *current != status && *current != ListenerStatus::Stale;
+        if changed {
+            *current = status;
+        }
+        changed
+    });
+}
+
+fn report(stream: &Stream, error: FirebaseError) {
+    let handlers: Vec<OnError> = stream.state().subscribers.iter().map(|s| s.on_error.clone()).collect();
+
+    for on_error in handlers {
+        on_error(error.clone());
+    }
+}
+
+/// Get the error telling the listeners the server ended their stream with the given event
+fn ended(event: &EventType, data: &str) -> Option<FirebaseError> {
+    let name = match event {
+        EventType::Cancel => "cancel",
+        EventType::AuthRevoked => "auth_revoked",
+        _ => return None,
+    };
+
+    let reason = match serde_json::from_str::<Value>(data) {
+        Ok(Value::String(reason)) => reason,
+        _ => data.trim().to_string(),
+    };
+
+    Some(match reason.is_empty() || reason == "null" {
+        true => FirebaseError::new(format!("The server closed the stream with the {} event", name)),
+        false => FirebaseError::new(format!("The server closed the stream with the {} event: {}", name, reason)),
+    })
+}
+
+/// Remove the empty segments of a path and its leading and trailing slashes
+pub(crate) fn normalize(path: &str) -> String {
+    path.split('/').filter(|s| !s.is_empty()).collect::<Vec<_>>().join("/")
+}
+
+/// Get the part of `value` at the given relative path
+pub(crate) fn descend(value: &Value, path: &str) -> Value {
+    let mut current = value;
+
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        current = match current {
+            Value::Object(map) => map.get(segment).unwrap_or(&Value::Null),
+            Value::Array(items) => segment.parse::<usize>().ok().and_then(|i| items.get(i)).unwrap_or(&Value::Null),
+            _ => &Value::Null,
+        };
+    }
+
+    current.clone()
+}
+
+/// Get `path` relative to `ancestor`, `None` if it is not `ancestor` or one of its descendants
+pub(crate) fn relative<'p>(ancestor: &str, path: &'p str) -> Option<&'p str> {
+    match path.strip_prefix(ancestor) {
+        Some("") => Some(""),
+        Some(rest) if ancestor.is_empty() => Some(rest),
+        Some(rest) => rest.strip_prefix('/'),
+        None => None,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(path: &str, params: Option<&str>) -> StreamKey {
+        StreamKey {
+            path: path.to_string(),
+            params: params.map(|p| p.to_string()),
+            auth: None,
+        }
+    }
+
+    #[test]
+    fn test_relative() {
+        assert_eq!(relative("", "users/alice"), Some("users/alice"));
+        assert_eq!(relative("users", "users/alice"), Some("alice"));
+        assert_eq!(relative("users", "users"), Some(""));
+        assert_eq!(relative("users", "usersx/alice"), None);
+        assert_eq!(relative("users/alice", "users"), None);
+    }
+
+    #[test]
+    fn test_apply_local() {
+        let registry = ListenerRegistry::default();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+
+        let mut subscriber = Subscriber::new(Box::new({
+            let seen = seen.clone();
+            move |snapshot, changes| {
+                seen.lock().unwrap().push((snapshot.value().clone(), changes.paths().to_vec()));
+                None
+            }
+        }), Box::new(|| {}), Arc::new(|_| {}));
+        subscriber.path = "alice".to_string();
+
+        let stream = Arc::new(Stream {
+            key: key("users", None),
+            state: Mutex::new(StreamState {
+                cache: Some(SnapshotCache::new(serde_json::json!({"alice": {"age": 30}}))),
+                subscribers: vec![subscriber],
+                closed: false,
+                stale: false,
+            }),
+            cancel: watch::channel(false).0,
+            status: watch::channel(ListenerStatus::Live).0,
+            resync: Notify::new(),
+        });
+        registry.streams().insert(stream.key.clone(), stream.clone());
+
+        registry.apply_local("/users/alice", &serde_json::json!({"age": 31}), true);
+        registry.apply_local("/", &serde_json::json!({"users": {"alice": {"name": "Alice"}}}), false);
+        registry.apply_local("/posts", &serde_json::json!(1), false);
+
+        assert_eq!(*seen.lock().unwrap(), vec![
+            (serde_json::json!({"age": 31}), vec!["age".to_string()]),
+            (serde_json::json!({"name": "Alice"}), vec![String::new()]),
+        ]);
+    }
+
+    #[test]
+    fn test_descend() {
+        let value = serde_json::json!({"a": {"b": [1, 2]}});
+
+        assert_eq!(descend(&value, "a/b/1"), serde_json::json!(2));
+        assert_eq!(descend(&value, "a/c"), Value::Null);
+        assert_eq!(descend(&value, ""), value);
+    }
+
+    #[tokio::test]
+    async fn test_stale_stream_saves_cache() {
+        let directory = std::env::temp_dir().join(format!("firerust-saver-{}", std::process::id()));
+        let store = Arc::new(SnapshotStore::new(crate::persist::PersistenceOptions::new(&directory)).unwrap());
+
+        let stream = Stream {
+            key: key("users", None),
+            state: Mutex::new(StreamState {
+                cache: Some(SnapshotCache::new(serde_json::json!({"alice": 1}))),
+                stale: true,
+                ..StreamState::default()
+            }),
+            cancel: watch::channel(false).0,
+            status: watch::channel(ListenerStatus::Stale).0,
+            resync: Notify::new(),
+        };
+
+        set_status(&stream, ListenerStatus::Reconnecting);
+        assert_eq!(*stream.status.borrow(), ListenerStatus::Stale);
+
+        let mut saver = Saver::new(Some(store.clone()), "https://a.firebaseio.com:443", &stream.key);
+        assert!(saver.flush(&stream).is_none());
+
+        saver.mark();
+        saver.flush(&stream).unwrap().await.unwrap();
+        assert!(!saver.is_due());
+        assert_eq!(saver.load(), Some(serde_json::json!({"alice": 1})));
+
+        // Another database or other credentials never see the saved cache
+        let signed = StreamKey { auth: Some("token".to_string()), ..key("users", None) };
+        assert_eq!(Saver::new(Some(store.clone()), "https://b.firebaseio.com:443", &stream.key).load(), None);
+        assert_eq!(Saver::new(Some(store.clone()), "https://a.firebaseio.com:443", &signed).load(), None);
+
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn test_covers() {
+        assert!(key("users", None).covers(&key("users/alice/age", None)));
+        assert!(key("", None).covers(&key("users", None)));
+        assert!(!key("users/alice", None).covers(&key("users", None)));
+        assert!(!key("users", None).covers(&key("users/alice", Some("?orderBy=%22%24key%22"))));
+        assert!(key("users", Some("?limitToFirst=1")).covers(&key("users", Some("?limitToFirst=1"))));
+        assert!(!key("users", Some("?limitToFirst=1")).covers(&key("users/alice", Some("?limitToFirst=1"))));
+
+        let mut other = key("users", None);
+        other.auth = Some("token".to_string());
+        assert!(!key("users", None).covers(&other));
+    }
+
+    /// A subscriber recording the values it receives
+    fn recorder(seen: &Arc<Mutex<Vec<Value>>>, closed: &Arc<Mutex<bool>>) -> Subscriber {
+        let (seen, closed) = (seen.clone(), closed.clone());
+
+        Subscriber::new(Box::new(move |snapshot, _| {
+            seen.lock().unwrap().push(snapshot.value().clone());
+            None
+        }), Box::new(move || *closed.lock().unwrap() = true), Arc::new(|_| {}))
+    }
+
+    #[test]
+    fn test_ended_names_the_event() {
+        assert_eq!(ended(&EventType::Cancel, "\"Permission denied\"").unwrap().to_string(), "The server closed the stream with the cancel event: Permission denied");
+        assert_eq!(ended(&EventType::AuthRevoked, "null").unwrap().to_string(), "The server closed the stream with the auth_revoked event");
+        assert!(ended(&EventType::Put, "{}").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_join_existing_cache() {
+        let client = crate::FirebaseClient::new("https://firerust-test.firebaseio.com/").unwrap();
+        client.registry.open_local("users", serde_json::json!({"alice": {"age": 30}}));
+
+        let (seen, closed) = (Arc::new(Mutex::new(Vec::new())), Arc::new(Mutex::new(false)));
+        let _subscription = client.registry.subscribe(&client, "/users/alice/", None, None, recorder(&seen, &closed)).await.unwrap();
+
+        assert_eq!(client.open_streams(), 1);
+        assert_eq!(*seen.lock().unwrap(), vec![serde_json::json!({"age": 30})]);
+    }
+
+    #[tokio::test]
+    async fn test_fan_out_skips_blocked_listener() {
+        let client = crate::FirebaseClient::new("https://firerust-test.firebaseio.com/").unwrap();
+        client.registry.open_local("users", serde_json::json!({"alice": 1}));
+
+        // Never has room for an update after the initial one
+        let blocked = Subscriber::new(Box::new(|_, changes| match changes.is_initial() {
+            true => None,
+            false => Some(futures_util::future::pending().boxed()),
+        }), Box::new(|| {}), Arc::new(|_| {}));
+        let _blocked = client.registry.subscribe(&client, "users", None, None, blocked).await.unwrap();
+
+        let (seen, closed) = (Arc::new(Mutex::new(Vec::new())), Arc::new(Mutex::new(false)));
+        let _subscription = client.registry.subscribe(&client, "users/alice", None, None, recorder(&seen, &closed)).await.unwrap();
+
+        for value in 2..=3 {
+            client.registry.apply_local("users/alice", &serde_json::json!(value), false);
+        }
+
+        assert_eq!(*seen.lock().unwrap(), vec![serde_json::json!(1), serde_json::json!(2), serde_json::json!(3)]);
+    }
+
+    #[tokio::test]
+    async fn test_stuck_listener_stays_bounded() {
+        let client = crate::FirebaseClient::new("https://firerust-test.firebaseio.com/").unwrap();
+        client.registry.open_local("users", serde_json::json!({"alice": 0}));
+
+        // The first update after the initial one never finds room, the others wait behind it
+        let calls = Arc::new(AtomicU64::new(0));
+        let counter = calls.clone();
+        let stuck = Subscriber::new(Box::new(move |_, changes| match changes.is_initial() {
+            true => None,
+            false => {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Some(futures_util::future::pending().boxed())
+            },
+        }), Box::new(|| {}), Arc::new(|_| {}));
+        let subscription = client.registry.subscribe(&client, "users", None, None, stuck).await.unwrap();
+
+        client.registry.apply_local("users/alice", &serde_json::json!(1), false);
+        let tasks = tokio::runtime::Handle::current().metrics().num_alive_tasks();
+
+        for value in 2..1000 {
+            client.registry.apply_local(&format!("users/user{}", value % 10), &serde_json::json!(value), false);
+        }
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(tokio::runtime::Handle::current().metrics().num_alive_tasks(), tasks);
+
+        let state = subscription.stream.state();
+        let pending = state.subscribers[0].pending.lock().unwrap();
+        let (snapshot, changes) = pending.latest.as_ref().unwrap();
+        assert_eq!(snapshot.value()["user9"], serde_json::json!(999));
+        assert_eq!(changes.paths().len(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_pending_updates_merge() {
+        let client = crate::FirebaseClient::new("https://firerust-test.firebaseio.com/").unwrap();
+        client.registry.open_local("users", serde_json::json!({"alice": 0}));
+
+        // Each update waits for room until the next permit
+        let (seen, room) = (Arc::new(Mutex::new(Vec::new())), Arc::new(tokio::sync::Semaphore::new(0)));
+        let (recorded, permits) = (seen.clone(), room.clone());
+        let slow = Subscriber::new(Box::new(move |snapshot, changes| {
+            if changes.is_initial() {
+                return None;
+            }
+            recorded.lock().unwrap().push((snapshot.value()["alice"].clone(), changes.paths().to_vec()));
+            let permits = permits.clone();
+            Some(async move { permits.acquire().await.unwrap().forget() }.boxed())
+        }), Box::new(|| {}), Arc::new(|_| {}));
+        let _subscription = client.registry.subscribe(&client, "users", None, None, slow).await.unwrap();
+
+        client.registry.apply_local("users/alice", &serde_json::json!(1), false);
+        client.registry.apply_local("users/bob", &serde_json::json!(1), false);
+        client.registry.apply_local("users/alice", &serde_json::json!(3), false);
+
+        room.add_permits(2);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while seen.lock().unwrap().len() < 2 {
+                tokio::task::yield_now().await;
+            }
+        }).await.unwrap();
+
+        assert_eq!(*seen.lock().unwrap(), vec![
+            (serde_json::json!(1), vec!["alice".to_string()]),
+            (serde_json::json!(3), vec!["bob".to_string(), "alice".to_string()]),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_teardown_on_last_subscription() {
+        let client = crate::FirebaseClient::new("https://firerust-test.firebaseio.com/").unwrap();
+        client.registry.open_local("users", serde_json::json!({"alice": 1}));
+
+        let (seen, first_closed, second_closed) = (Arc::new(Mutex::new(Vec::new())), Arc::new(Mutex::new(false)), Arc::new(Mutex::new(false)));
+        let first = client.registry.subscribe(&client, "users", None, None, recorder(&seen, &first_closed)).await.unwrap();
+        let second = client.registry.subscribe(&client, "users/alice", None, None, recorder(&seen, &second_closed)).await.unwrap();
+        let stream = first.stream.clone();
+
+        drop(first);
+        assert!(*first_closed.lock().unwrap());
+        assert!(!*stream.cancel.borrow());
+        assert_eq!(client.open_streams(), 1);
+
+        drop(second);
+        assert!(*second_closed.lock().unwrap());
+        assert!(*stream.cancel.borrow());
+        assert!(stream.state().closed);
+        assert_eq!(client.open_streams(), 0);
+    }
+
+    #[test]
+    fn test_resync_touched_streams() {
+        let registry = ListenerRegistry::default();
+        registry.open_local("users/alice", serde_json::json!(1));
+        registry.open_local("posts", serde_json::json!(2));
+
+        let alice = registry.streams()[&key("users/alice", None)].clone();
+        let posts = registry.streams()[&key("posts", None)].clone();
+        let (alice_resync, posts_resync) = (alice.resync.notified(), posts.resync.notified());
+
+        registry.resync("/users");
+        assert!(alice_resync.now_or_never().is_some());
+        assert!(posts_resync.now_or_never().is_none());
+    }
+}
@@ -0,0 +1,366 @@
+//! # Example
+//!
+//! ```rust,no_run
+//! use firerust::{FirebaseClient, FirebaseError};
+//! use serde_json::Value;
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), FirebaseError> {
+//!     let client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+//!     let snapshot = client.reference("/users").get_snapshot().await?;
+//!
+//!     for child in snapshot.children() {
+//!         println!("{:?} => {:?}", child.key(), child.val::<Value>()?);
+//!     }
+//!
+//!     Ok(())
+//! }
+//! ```
+
+
+use serde::de::DeserializeOwned;
+use crate::ordering::FirebaseOrdering;
+use crate::query::OrderBy;
+use std::collections::HashMap;
+use serde_json::Value;
+use std::sync::Arc;
+use crate::FirebaseError;
+
+
+/// An immutable copy of the data at a database location
+///
+/// Cloning a snapshot and taking child snapshots is cheap, the underlying tree is shared
+#[derive(Clone, Debug)]
+pub struct DataSnapshot {
+    key: Option<String>,
+    root: Arc<Value>,
+    pointer: String,
+    order: OrderBy,
+}
+
+impl DataSnapshot {
+
+    /// Create a new snapshot with the given key and value
+    pub fn new(key: Option<String>, value: impl Into<Arc<Value>>) -> DataSnapshot {
+        DataSnapshot {
+            key,
+            root: value.into(),
+            pointer: String::new(),
+            order: OrderBy::Key,
+        }
+    }
+
+    /// Set the order used when iterating the children of the snapshot
+    pub fn with_order(mut self, order: OrderBy) -> DataSnapshot {
+        self.order = order;
+        self
+    }
+
+    /// Get the key of the location, `None` for the root of the database
+    pub fn key(&self) -> Option<&str> {
+        self.key.as_deref()
+    }
+
+    /// Get the raw value of the location, including priority metadata if present
+    pub fn value(&self) -> &Value {
+        static NULL: Value = Value::Null;
+        self.root.pointer(&self.pointer).unwrap_or(&NULL)
+    }
+
+    /// Returns true if the location contains any data
+    pub fn exists(&self) -> bool {
+        match strip_value(self.value()).as_ref() {
+            Value::Null => false,
+            Value::Object(map) => !map.is_empty(),
+            Value::Array(items) => !items.is_empty(),
+            _ => true,
+        }
+    }
+
+    /// Get a snapshot of the location at the given relative path
+    ///
+    /// The returned snapshot does not exist if there is no data at the path
+    pub fn child(&self, path: &str) -> DataSnapshot {
+        let mut key = self.key.clone();
+        let mut pointer = self.pointer.clone();
+
+        for segment in path.split('/').filter(|s| !s.is_empty()) {
+            pointer.push('/');
+            pointer.push_str(&segment.replace('~', "~0"));
+            key = Some(segment.to_string());
+        }
+
+        DataSnapshot {
+            key,
+            root: self.root.clone(),
+            pointer,
+            order: OrderBy::Key,
+        }
+    }
+
+    /// Returns true if there is data at the given relative path
+    pub fn has_child(&self, path: &str) -> bool {
+        self.child(path).exists()
+    }
+
+    /// Returns true if the location has any children
+    pub fn has_children(&self) -> bool {
+        self.size() > 0
+    }
+
+    /// Get the number of children of the location
+    pub fn size(&self) -> usize {
+        child_keys(self.value()).len()
+    }
+
+    /// Get the children of the location in Firebase order
+    ///
+    /// Children are ordered by key unless the snapshot comes from a query with another ordering
+    pub fn children(&self) -> impl Iterator<Item = DataSnapshot> + '_ {
+        let value = self.value();
+        let ordering = FirebaseOrdering::new(self.order.clone());
+        let mut keys = child_keys(value);
+        keys.sort_by(|a, b| ordering.compare((a, child_value(value, a)), (b, child_value(value, b))));
+
+        keys.into_iter().map(move |key| self.child(&key))
+    }
+
+    /// Get the priority of the location, if any
+    pub fn priority(&self) -> Option<&Value> {
+        match self.value() {
+            Value::Object(map) => map.get(".priority").filter(|p| !p.is_null()),
+            _ => None,
+        }
+    }
+
+    /// Deserialize the value of the location, priority metadata is not included
+    ///
+    /// # Errors
+    /// Returns an error if the value can not be deserialized into `T`
+    pub fn val<T>(&self) -> Result<T, FirebaseError> where T: DeserializeOwned {
+        let value = self.value();
+
+        if let Some(value) = stripped(value) {
+            return Ok(serde_json::from_value(value)?);
+        }
+
+        Ok(T::deserialize(value)?)
+    }
+
+    /// Get the child events that turn `previous` into this snapshot, in the order Firebase raises them
+    ///
+    /// Children are compared using the ordering of this snapshot
+    ///
+    /// # Example
+    /// ```rust
+    /// use firerust::{DataSnapshot, ChildEvent};
+    /// use serde_json::json;
+    ///
+    /// let previous = DataSnapshot::new(None, json!({"a": 1, "b": 2}));
+    /// let current = DataSnapshot::new(None, json!({"b": 3, "c": 4}));
+    ///
+    /// let events = current.diff_children(&previous);
+    /// assert!(matches!(&events[0], ChildEvent::Removed { snapshot } if snapshot.key() == Some("a")));
+    /// ```
+    pub fn diff_children(&self, previous: &DataSnapshot) -> Vec<ChildEvent> {
+        let ordering = FirebaseOrdering::new(self.order.clone());
+        let old: HashMap<String, DataSnapshot> = previous.children()
+            .filter_map(|child| child.key().map(|k| (k.to_string(), child.clone())))
+            .collect();
+
+        let current: Vec<DataSnapshot> = self.children().collect();
+        let mut events: Vec<ChildEvent> = previous.children()
+            .filter(|child| !child.key().map(|k| self.value_has_child(k)).unwrap_or(false))
+            .map(|snapshot| ChildEvent::Removed { snapshot })
+            .collect();
+
+        let mut moved = Vec::new();
+        let mut changed = Vec::new();
+        let mut previous_key: Option<String> = None;
+
+        for child in current {
+            let key = child.key().unwrap_or_default().to_string();
+
+            match old.get(&key) {
+                None => events.push(ChildEvent::Added { snapshot: child, previous_key: previous_key.clone() }),
+                Some(before) if before.value() != child.value() => {
+                    if ordering.compare_index(before.value(), child.value()).is_ne() {
+                        moved.push(ChildEvent::Moved { snapshot: child.clone(), previous_key: previous_key.clone() });
+                    }
+                    changed.push(ChildEvent::Changed { snapshot: child, previous_key: previous_key.clone() });
+                },
+                Some(_) => {}
+            }
+
+            previous_key = Some(key);
+        }
+
+        events.extend(moved);
+        events.extend(changed);
+        events
+    }
+
+    fn value_has_child(&self, key: &str) -> bool {
+        !child_value(self.value(), key).is_null()
+    }
+}
+
+
+/// A change to a child of a location, with the key of the child ordered before it
+#[derive(Clone, Debug)]
+pub enum ChildEvent {
+    Added { snapshot: DataSnapshot, previous_key: Option<String> },
+    Removed { snapshot: DataSnapshot },
+    Changed { snapshot: DataSnapshot, previous_key: Option<String> },
+    Moved { snapshot: DataSnapshot, previous_key: Option<String> },
+}
+
+impl ChildEvent {
+
+    /// Get the snapshot of the child, the previous value for removed children
+    pub fn snapshot(&self) -> &DataSnapshot {
+        match self {
+            ChildEvent::Added { snapshot, .. } => snapshot,
+            ChildEvent::Removed { snapshot } => snapshot,
+            ChildEvent::Changed { snapshot, .. } => snapshot,
+            ChildEvent::Moved { snapshot, .. } => snapshot,
+        }
+    }
+}
+
+
+fn is_metadata(key: &str) -> bool {
+    key == ".priority" || key == ".value"
+}
+
+fn strip_value(value: &Value) -> std::borrow::Cow<'_, Value> {
+    use std::borrow::Cow;
+
+    match stripped(value) {
+        Some(value) => Cow::Owned(value),
+        None => Cow::Borrowed(value),
+    }
+}
+
+/// Remove the priority metadata of a value in a single pass, `None` if it holds none
+fn stripped(value: &Value) -> Option<Value> {
+    match value {
+        Value::Object(map) => {
+            if let Some(inner) = map.get(".value") {
+                return Some(stripped(inner).unwrap_or_else(|| inner.clone()));
+            }
+
+            let mut changed = map.contains_key(".priority");
+            let children = map.iter()
+                .filter(|(k, _)| !is_metadata(k))
+                .map(|(k, v)| {
+                    let child = stripped(v);
+                    changed |= child.is_some();
+                    (k, v, child)
+                })
+                .collect::<Vec<_>>();
+
+            changed.then(|| Value::Object(children.into_iter()
+                .map(|(k, v, child)| (k.clone(), child.unwrap_or_else(|| v.clone())))
+                .collect()))
+        },
+        Value::Array(items) => {
+            let children = items.iter().map(stripped).collect::<Vec<_>>();
+            if children.iter().all(Option::is_none) {
+                return None;
+            }
+
+            Some(Value::Array(children.into_iter().zip(items)
+                .map(|(child, v)| child.unwrap_or_else(|| v.clone()))
+                .collect()))
+        },
+        _ => None,
+    }
+}
+
+fn child_keys(value: &Value) -> Vec<String> {
+    match value {
+        Value::Object(map) if !map.contains_key(".value") => map.iter()
+            .filter(|(k, v)| !is_metadata(k) && !v.is_null())
+            .map(|(k, _)| k.clone())
+            .collect(),
+        Value::Array(items) => items.iter()
+            .enumerate()
+            .filter(|(_, v)| !v.is_null())
+            .map(|(i, _)| i.to_string())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn child_value<'a>(value: &'a Value, key: &str) -> &'a Value {
+    match value {
+        Value::Object(map) => map.get(key).unwrap_or(&Value::Null),
+        Value::Array(items) => key.parse::<usize>().ok().and_then(|i| items.get(i)).unwrap_or(&Value::Null),
+        _ => &Value::Null,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_children_key_order() {
+        let snapshot = DataSnapshot::new(None, json!({"b": 1, "10": 2, "a": 3, "2": 4, "-1": 5}));
+        let keys: Vec<String> = snapshot.children().map(|c| c.key().unwrap().to_string()).collect();
+
+        assert_eq!(keys, vec!["-1", "2", "10", "a", "b"]);
+    }
+
+    #[test]
+    fn test_child_path() {
+        let snapshot = DataSnapshot::new(Some("users".into()), json!({"alice": {"age": 30}}));
+
+        assert_eq!(snapshot.child("alice/age").key(), Some("age"));
+        assert_eq!(snapshot.child("alice/age").val::<u32>().unwrap(), 30);
+        assert!(snapshot.has_child("alice"));
+        assert!(!snapshot.child("bob/age").exists());
+    }
+
+    #[test]
+    fn test_diff_children() {
+        let previous = DataSnapshot::new(None, json!({"a": {"n": 1}, "b": {"n": 2}, "c": {"n": 3}})).with_order(OrderBy::Child("n".into()));
+        let current = DataSnapshot::new(None, json!({"b": {"n": 2}, "c": {"n": 0}, "d": {"n": 4}})).with_order(OrderBy::Child("n".into()));
+
+        let events: Vec<String> = current.diff_children(&previous).iter().map(|e| match e {
+            ChildEvent::Added { snapshot, previous_key } => format!("added {} after {:?}", snapshot.key().unwrap(), previous_key),
+            ChildEvent::Removed { snapshot } => format!("removed {}", snapshot.key().unwrap()),
+            ChildEvent::Changed { snapshot, previous_key } => format!("changed {} after {:?}", snapshot.key().unwrap(), previous_key),
+            ChildEvent::Moved { snapshot, previous_key } => format!("moved {} after {:?}", snapshot.key().unwrap(), previous_key),
+        }).collect();
+
+        assert_eq!(events, vec![
+            "removed a",
+            "added d after Some(\"b\")",
+            "moved c after None",
+            "changed c after None",
+        ]);
+    }
+
+    #[test]
+    fn test_priority_metadata() {
+        let snapshot = DataSnapshot::new(None, json!({".priority": 2, "a": {".value": 1, ".priority": "x"}}));
+
+        assert_eq!(snapshot.priority(), Some(&json!(2)));
+        assert_eq!(snapshot.child("a").priority(), Some(&json!("x")));
+        assert_eq!(snapshot.size(), 1);
+        assert_eq!(snapshot.val::<Value>().unwrap(), json!({"a": 1}));
+    }
+
+    #[test]
+    fn test_strip_value_single_pass() {
+        let plain = json!({"a": [1, {"b": 2}], "c": "x"});
+        assert!(matches!(strip_value(&plain), std::borrow::Cow::Borrowed(_)));
+
+        let nested = json!({"a": [1, {"b": {".value": 2, ".priority": 1}}], "c": "x"});
+        assert_eq!(strip_value(&nested).into_owned(), json!({"a": [1, {"b": 2}], "c": "x"}));
+    }
+}
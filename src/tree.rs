@@ -0,0 +1,400 @@
+//! # Example
+//!
+//! ```rust
+//! use firerust::tree;
+//! use serde_json::json;
+//!
+//! let mut root = json!({"count": 1});
+//! tree::set(&mut root, "/count/value", json!(2));
+//! tree::update(&mut root, "/list", json!({"0": "a", "1": "b"}));
+//!
+//! assert_eq!(root, json!({"count": {"value": 2}, "list": ["a", "b"]}));
+//!
+//! tree::set(&mut root, "/count/value", json!(null));
+//! assert_eq!(root, json!({"list": ["a", "b"]}));
+//! ```
+
+
+use serde_json::{ Map, Value };
+
+
+/// Replace the value at the given path of a tree, the way the Firebase server does
+///
+/// Missing parents are created, primitives on the way are replaced by objects, writing `null`
+/// removes the value and the parents left empty, and objects with integer keys are collapsed into
+/// arrays the same way the server returns them
+pub fn set(root: &mut Value, path: &str, data: Value) {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    set_segments(root, &segments, normalize(data));
+}
+
+/// Replace each child of `data` at the given path of a tree, the way the Firebase server applies a `PATCH`
+///
+/// Keys of `data` can be slash-separated paths, `data` that is not an object replaces the value
+pub fn update(root: &mut Value, path: &str, data: Value) {
+    match data {
+        Value::Object(map) => {
+            for (key, value) in map {
+                set(root, &format!("{}/{}", path, key), value);
+            }
+        },
+        data => set(root, path, data),
+    }
+}
+
+/// Normalize a value the way the Firebase server stores it
+///
+/// `null` children and empty objects are removed and objects with integer keys are collapsed into arrays
+pub fn normalize(value: Value) -> Value {
+    let mut value = match value {
+        Value::Object(map) => Value::Object(map.into_iter()
+            .map(|(k, v)| (k, normalize(v)))
+            .filter(|(_, v)| !v.is_null())
+            .collect()),
+        Value::Array(items) => Value::Array(items.into_iter().map(normalize).collect()),
+        value => value,
+    };
+
+    collapse(&mut value);
+    value
+}
+
+
+fn set_segments(node: &mut Value, segments: &[&str], data: Value) {
+    let (first, rest) = match segments.split_first() {
+        Some(split) => split,
+        None => {
+            *node = data;
+            return;
+        }
+    };
+
+    if data.is_null() && !matches!(node, Value::Object(_) | Value::Array(_)) {
+        return;
+    }
+
+    // A key past twice the number of children makes an object, checked before any slot is allocated
+    if let Value::Array(items) = node {
+        let count = items.iter().filter(|v| !v.is_null()).count();
+        if index(first).is_none_or(|i| i >= 2 * (count + 1)) {
+            *node = to_object(node.take());
+        }
+    }
+
+    match node {
+        Value::Array(items) => {
+            let i = index(first).unwrap_or_default();
+
+            if i >= items.len() {
+                if data.is_null() {
+                    return;
+                }
+                items.resize(i + 1, Value::Null);
+            }
+
+            set_segments(&mut items[i], rest, data);
+        },
+        Value::Object(map) => {
+            let child = map.entry(first.to_string()).or_insert(Value::Null);
+            set_segments(child, rest, data);
+
+            if child.is_null() {
+                map.remove(*first);
+            }
+        },
+        _ => {
+            let mut child = Value::Null;
+            set_segments(&mut child, rest, data);
+
+            let mut map = Map::new();
+            map.insert(first.to_string(), child);
+            *node = Value::Object(map);
+        }
+    }
+
+    collapse(node);
+}
+
+/// Turn empty containers into `null` and switch between objects and arrays like the server does,
+/// a node is an array when every key is a non-negative integer and the largest one is less than
+/// twice the number of children
+fn collapse(node: &mut Value) {
+    match node {
+        Value::Array(items) => {
+            while items.last().is_some_and(Value::is_null) {
+                items.pop();
+            }
+
+            let count = items.iter().filter(|v| !v.is_null()).count();
+            if count == 0 {
+                *node = Value::Null;
+            } else if items.len() > 2 * count {
+                *node = to_object(node.take());
+            }
+        },
+        Value::Object(map) => {
+            if map.is_empty() {
+                *node = Value::Null;
+                return;
+            }
+
+            let max = map.keys().map(|k| index(k)).try_fold(0, |max, i| i.map(|i| max.max(i)));
+            if let Some(max) = max {
+                if max < 2 * map.len() {
+                    let mut items = vec![Value::Null; max + 1];
+                    for (k, v) in std::mem::take(map) {
+                        items[index(&k).unwrap_or_default()] = v;
+                    }
+                    *node = Value::Array(items);
+                }
+            }
+        },
+        _ => {}
+    }
+}
+
+fn to_object(value: Value) -> Value {
+    match value {
+        Value::Array(items) => Value::Object(items.into_iter()
+            .enumerate()
+            .filter(|(_, v)| !v.is_null())
+            .map(|(i, v)| (i.to_string(), v))
+            .collect()),
+        value => value,
+    }
+}
+
+/// Parse a key used as an array index, only canonical non-negative integers are indexes
+fn index(key: &str) -> Option<usize> {
+    match key.parse::<u32>() {
+        Ok(i) if i.to_string() == key => Some(i as usize),
+        _ => None,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_set_creates_parents() {
+        let mut root = Value::Null;
+        set(&mut root, "/a/b/c", json!(1));
+        assert_eq!(root, json!({"a": {"b": {"c": 1}}}));
+    }
+
+    #[test]
+    fn test_set_replaces_primitive() {
+        let mut root = json!({"a": "text"});
+        set(&mut root, "/a/b", json!(1));
+        assert_eq!(root, json!({"a": {"b": 1}}));
+    }
+
+    #[test]
+    fn test_set_null_prunes_parents() {
+        let mut root = json!({"a": {"b": {"c": 1}}, "d": 2});
+        set(&mut root, "/a/b/c", Value::Null);
+        assert_eq!(root, json!({"d": 2}));
+
+        set(&mut root, "/d", Value::Null);
+        assert_eq!(root, Value::Null);
+
+        set(&mut root, "/x/y", Value::Null);
+        assert_eq!(root, Value::Null);
+    }
+
+    #[test]
+    fn test_update_replaces_children() {
+        let mut root = json!({"a": {"x": 1, "y": 2}, "b": 3});
+        update(&mut root, "/", json!({"a": {"x": 5}, "b": null, "c/d": 4}));
+        assert_eq!(root, json!({"a": {"x": 5}, "c": {"d": 4}}));
+    }
+
+    #[test]
+    fn test_arrays_collapse() {
+        let mut root = json!(["a", "b", "c"]);
+
+        set(&mut root, "/5", json!("f"));
+        assert_eq!(root, json!(["a", "b", "c", null, null, "f"]));
+
+        set(&mut root, "/1", Value::Null);
+        set(&mut root, "/2", Value::Null);
+        assert_eq!(root, json!({"0": "a", "5": "f"}));
+
+        set(&mut root, "/5", Value::Null);
+        assert_eq!(root, json!(["a"]));
+
+        set(&mut root, "/name", json!("x"));
+        assert_eq!(root, json!({"0": "a", "name": "x"}));
+    }
+
+    #[test]
+    fn test_huge_index_makes_an_object() {
+        let mut root = json!({"list": ["a", "b"]});
+
+        set(&mut root, "/list/4000000000", json!("z"));
+        assert_eq!(root, json!({"list": {"0": "a", "1": "b", "4000000000": "z"}}));
+
+        update(&mut root, "/list", json!({"4000000000": null, "9": "j"}));
+        assert_eq!(root, json!({"list": {"0": "a", "1": "b", "9": "j"}}));
+
+        set(&mut root, "/list/9", Value::Null);
+        assert_eq!(root, json!({"list": ["a", "b"]}));
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize(json!({"a": {}, "b": null, "c": {"d": {}}})), Value::Null);
+        assert_eq!(normalize(json!({"0": 1, "2": 3})), json!([1, null, 3]));
+        assert_eq!(normalize(json!([null, null, null, 4])), json!({"3": 4}));
+    }
+
+
+    const KEYS: &[&str] = &["0", "1", "2", "3", "a", "b"];
+
+    fn arb_path() -> impl Strategy<Value = Vec<&'static str>> {
+        prop::collection::vec(prop::sample::select(KEYS), 0..4)
+    }
+
+    fn arb_value() -> impl Strategy<Value = Value> {
+        let leaf = prop_oneof![
+            Just(Value::Null),
+            any::<bool>().prop_map(Value::from),
+            (-5i64..5).prop_map(Value::from),
+            "[a-c]{1,2}".prop_map(Value::from),
+        ];
+
+        leaf.prop_recursive(3, 16, 4, |inner| {
+            prop::collection::btree_map(prop::sample::select(KEYS), inner, 0..4)
+                .prop_map(|map| Value::Object(map.into_iter().map(|(k, v)| (k.to_string(), v)).collect()))
+        })
+    }
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        Set(Vec<&'static str>, Value),
+        Update(Vec<&'static str>, Vec<(Vec<&'static str>, Value)>),
+    }
+
+    fn arb_op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            (arb_path(), arb_value()).prop_map(|(p, v)| Op::Set(p, v)),
+            (arb_path(), prop::collection::vec((prop::collection::vec(prop::sample::select(KEYS), 1..3), arb_value()), 1..4))
+                .prop_map(|(p, children)| Op::Update(p, children)),
+        ]
+    }
+
+    /// Reference model, the tree as the set of its leaves
+    #[derive(Default)]
+    struct Model(BTreeMap<Vec<String>, Value>);
+
+    impl Model {
+        fn set(&mut self, path: &[String], data: &Value) {
+            let mut written = Model::default();
+            written.insert(path.to_vec(), data);
+
+            // Deleting below a primitive leaves it alone, writing below it replaces it
+            self.0.retain(|leaf, _| !leaf.starts_with(path) && (written.0.is_empty() || !path.starts_with(leaf)));
+            self.0.extend(written.0);
+        }
+
+        fn insert(&mut self, path: Vec<String>, data: &Value) {
+            match data {
+                Value::Null => {},
+                Value::Object(map) => for (k, v) in map {
+                    let mut child = path.clone();
+                    child.push(k.clone());
+                    self.insert(child, v);
+                },
+                leaf => { self.0.insert(path, leaf.clone()); },
+            }
+        }
+
+        fn render(&self) -> Value {
+            fn build(leaves: &[(&[String], &Value)]) -> Value {
+                if let [(path, value)] = leaves {
+                    if path.is_empty() {
+                        return (*value).clone();
+                    }
+                }
+
+                let mut groups: BTreeMap<&str, Vec<(&[String], &Value)>> = BTreeMap::new();
+                for (path, value) in leaves {
+                    groups.entry(path[0].as_str()).or_default().push((&path[1..], value));
+                }
+
+                let children: BTreeMap<&str, Value> = groups.into_iter().map(|(k, group)| (k, build(&group))).collect();
+                let indexes: Option<Vec<usize>> = children.keys().map(|k| index(k)).collect();
+
+                match indexes {
+                    Some(indexes) if indexes.iter().max().is_some_and(|max| *max < 2 * children.len()) => {
+                        let mut items = vec![Value::Null; indexes.iter().max().unwrap() + 1];
+                        for (i, v) in indexes.into_iter().zip(children.into_values()) {
+                            items[i] = v;
+                        }
+                        Value::Array(items)
+                    },
+                    _ => Value::Object(children.into_iter().map(|(k, v)| (k.to_string(), v)).collect()),
+                }
+            }
+
+            let leaves: Vec<(&[String], &Value)> = self.0.iter().map(|(p, v)| (p.as_slice(), v)).collect();
+            match leaves.is_empty() {
+                true => Value::Null,
+                false => build(&leaves),
+            }
+        }
+    }
+
+    fn owned(path: &[&str]) -> Vec<String> {
+        path.iter().map(|s| s.to_string()).collect()
+    }
+
+    proptest! {
+        #[test]
+        fn prop_tree_matches_model(ops in prop::collection::vec(arb_op(), 1..20)) {
+            let mut root = Value::Null;
+            let mut model = Model::default();
+
+            for op in ops {
+                match op {
+                    Op::Set(path, data) => {
+                        set(&mut root, &path.join("/"), data.clone());
+                        model.set(&owned(&path), &data);
+                    },
+                    Op::Update(path, children) => {
+                        let data: Map<String, Value> = children.iter().map(|(k, v)| (k.join("/"), v.clone())).collect();
+                        update(&mut root, &path.join("/"), Value::Object(data.clone()));
+
+                        for (key, value) in data {
+                            let mut child = owned(&path);
+                            child.extend(key.split('/').map(|s| s.to_string()));
+                            model.set(&child, &value);
+                        }
+                    },
+                }
+
+                prop_assert_eq!(&root, &model.render());
+            }
+        }
+
+        #[test]
+        fn prop_normalize_is_idempotent(value in arb_value()) {
+            let once = normalize(value);
+            prop_assert_eq!(normalize(once.clone()), once);
+        }
+
+        #[test]
+        fn prop_set_then_read(value in arb_value(), path in prop::collection::vec(prop::sample::select(&["a", "b"][..]), 1..4)) {
+            let mut root = json!({"a": 1});
+            set(&mut root, &path.join("/"), value.clone());
+
+            let pointer: String = path.iter().map(|s| format!("/{}", s)).collect();
+            prop_assert_eq!(root.pointer(&pointer).cloned().unwrap_or(Value::Null), normalize(value));
+        }
+    }
+}
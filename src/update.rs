@@ -0,0 +1,206 @@
+//! # Example
+//!
+//! ```rust,no_run
+//! use firerust::{FirebaseClient, FirebaseError, MultiPathUpdate};
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), FirebaseError> {
+//!     let client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+//!
+//!     MultiPathUpdate::new()
+//!         .set("users/alice/name", "Alice")
+//!         .set("names/Alice", "alice")
+//!         .delete("names/Alicia")
+//!         .increment("stats/renames", 1)
+//!         .apply(&client.reference("/"))
+//!         .await?;
+//!
+//!     Ok(())
+//! }
+//! ```
+
+
+use crate::{ FirebaseError, RealtimeReference };
+use crate::connector::Method;
+use serde_json::{ Map, Value };
+use serde::Serialize;
+
+
+/// Builder for atomic writes to several locations at once
+///
+/// All the writes are sent as a single `PATCH` at the deepest common ancestor of the paths,
+/// so either every write is applied or none is
+#[derive(Clone, Debug, Default)]
+pub struct MultiPathUpdate {
+    writes: Vec<(String, Value)>,
+    error: Option<String>,
+}
+
+impl MultiPathUpdate {
+
+    /// Create a new empty update
+    pub fn new() -> MultiPathUpdate {
+        MultiPathUpdate::default()
+    }
+
+    /// Set the value at the given path
+    pub fn set<T>(mut self, path: &str, value: T) -> MultiPathUpdate where T: Serialize {
+        match serde_json::to_value(value) {
+            Ok(value) => self.writes.push((path.to_string(), value)),
+            Err(e) => { self.error.get_or_insert(e.to_string()); },
+        }
+        self
+    }
+
+    /// Delete the value at the given path
+    pub fn delete(mut self, path: &str) -> MultiPathUpdate {
+        self.writes.push((path.to_string(), Value::Null));
+        self
+    }
+
+    /// Atomically add `delta` to the number at the given path on the server
+    pub fn increment(mut self, path: &str, delta: impl Into<Value>) -> MultiPathUpdate {
+        self.writes.push((path.to_string(), serde_json::json!({ ".sv": { "increment": delta.into() } })));
+        self
+    }
+
+    /// Set the value at the given path to the server time in milliseconds
+    pub fn server_timestamp(mut self, path: &str) -> MultiPathUpdate {
+        self.writes.push((path.to_string(), serde_json::json!({ ".sv": "timestamp" })));
+        self
+    }
+
+    /// Returns true if there are no writes in the update
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    /// Get the number of writes in the update
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    /// Validate the writes and build the path, relative to the reference, and the body of the `PATCH`
+    ///
+    /// # Errors
+    /// Returns an error if a path is invalid, if two paths overlap, or if a value could not be serialized
+    pub fn build(&self) -> Result<(String, Value), FirebaseError> {
+        if let Some(e) = &self.error {
+            return Err(FirebaseError::new(e));
+        }
+
+        let mut writes = Vec::with_capacity(self.writes.len());
+        for (path, value) in &self.writes {
+            writes.push((split_path(path)?, value));
+        }
+
+        writes.sort_by(|a, b| a.0.cmp(&b.0));
+        for pair in writes.windows(2) {
+            if pair[1].0.starts_with(&pair[0].0) {
+                return Err(FirebaseError::new(format!("Overlapping paths in update: {} and {}", pair[0].0.join("/"), pair[1].0.join("/"))));
+            }
+        }
+
+        let depth = match (writes.first(), writes.last()) {
+            (Some(first), Some(last)) => {
+                let common = first.0.iter().zip(last.0.iter()).take_while(|(a, b)| a == b).count();
+                common.min(writes.iter().map(|(p, _)| p.len()).min().unwrap_or(1) - 1)
+            },
+            _ => return Ok((String::new(), Value::Object(Map::new()))),
+        };
+
+        let ancestor = writes[0].0[..depth].join("/");
+        let body = writes.into_iter()
+            .map(|(path, value)| (path[depth..].join("/"), value.clone()))
+            .collect::<Map<String, Value>>();
+
+        Ok((ancestor, Value::Object(body)))
+    }
+
+    /// Apply every write atomically relative to the given reference
+    ///
+    /// # Errors
+    /// Returns an error if the update is invalid or the server rejects it
+    pub async fn apply(&self, reference: &RealtimeReference<'_>) -> Result<(), FirebaseError> {
+        let (ancestor, body) = self.build()?;
+        if self.is_empty() {
+            return Ok(());
+        }
+
+        let reference = match ancestor.is_empty() {
+            true => reference.clone(),
+            false => reference.child(&ancestor),
+        };
+
+        reference.write_request(Method::Patch, Some(&body.to_string())).await?;
+        Ok(())
+    }
+}
+
+
+pub(crate) fn split_path(path: &str) -> Result<Vec<&str>, FirebaseError> {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    if segments.is_empty() {
+        return Err(FirebaseError::new("Empty path in update"));
+    }
+
+    if let Some(segment) = segments.iter().find(|s| s.contains(['.', '$', '#', '[', ']'])) {
+        return Err(FirebaseError::new(format!("Invalid key in update path {}: {}", path, segment)));
+    }
+
+    Ok(segments)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_build_common_ancestor() {
+        let (ancestor, body) = MultiPathUpdate::new()
+            .set("/users/1/name", "Alice")
+            .delete("users/1/old")
+            .increment("users/1/count", 1)
+            .build()
+            .unwrap();
+
+        assert_eq!(ancestor, "users/1");
+        assert_eq!(body, json!({"name": "Alice", "old": null, "count": {".sv": {"increment": 1}}}));
+    }
+
+    #[test]
+    fn test_build_single_path() {
+        let (ancestor, body) = MultiPathUpdate::new().set("users/1/name", "Alice").build().unwrap();
+
+        assert_eq!(ancestor, "users/1");
+        assert_eq!(body, json!({"name": "Alice"}));
+    }
+
+    #[test]
+    fn test_build_root_ancestor() {
+        let (ancestor, body) = MultiPathUpdate::new().set("users/1", 1).set("index/1", true).build().unwrap();
+
+        assert_eq!(ancestor, "");
+        assert_eq!(body, json!({"users/1": 1, "index/1": true}));
+    }
+
+    #[test]
+    fn test_build_overlapping_paths() {
+        assert!(MultiPathUpdate::new().set("users/1", 1).set("users/1/name", "Alice").build().is_err());
+        assert!(MultiPathUpdate::new().set("users/1", 1).delete("/users/1/").build().is_err());
+        assert!(MultiPathUpdate::new().set("users/1", 1).set("users/10", 1).build().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_apply_reports_serialization_error() {
+        let client = crate::FirebaseClient::new("https://firerust-test.firebaseio.com/").unwrap();
+        let invalid = std::collections::HashMap::from([((1, 2), 3)]);
+        let update = MultiPathUpdate::new().set("users/1", invalid);
+
+        assert!(update.is_empty());
+        assert!(update.apply(&client.reference("/")).await.is_err());
+    }
+}
@@ -0,0 +1,442 @@
+//! # Example
+//!
+//! ```rust,no_run
+//! use firerust::{FirebaseClient, FirebaseError};
+//! use firerust::usage::{ UsageAnalyzer, UsageOptions };
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), FirebaseError> {
+//!     let client = FirebaseClient::new("https://docs-examples.firebaseio.com/")?;
+//!
+//!     let report = UsageAnalyzer::new(client.reference("/"), UsageOptions::new().max_depth(3)).analyze().await?;
+//!     for path in report.largest() {
+//!         println!("{:>12} bytes  {}", path.bytes(), path.path());
+//!     }
+//!
+//!     std::fs::write("usage.json", report.to_json()?).unwrap();
+//!     Ok(())
+//! }
+//! ```
+
+
+use crate::{ FirebaseError, RealtimeReference };
+use futures_util::future::BoxFuture;
+use futures_util::{ FutureExt, StreamExt };
+use crate::registry::normalize;
+use crate::connector::Method;
+use tokio::sync::Semaphore;
+use serde::Serialize;
+use std::sync::Arc;
+use serde_json::Value;
+
+
+/// How deep and how thoroughly a location is analyzed
+#[derive(Clone, Debug, PartialEq)]
+pub struct UsageOptions {
+    max_depth: usize,
+    sample: usize,
+    top: usize,
+    concurrency: usize,
+}
+
+impl Default for UsageOptions {
+    fn default() -> UsageOptions {
+        UsageOptions {
+            max_depth: 2,
+            sample: 20,
+            top: 10,
+            concurrency: 4,
+        }
+    }
+}
+
+impl UsageOptions {
+
+    /// Report two levels below the location, sampling 20 children per location at the deepest level,
+    /// sending 4 reads at a time and listing the 10 largest
+    pub fn new() -> UsageOptions {
+        UsageOptions::default()
+    }
+
+    /// Set the number of levels below the location that get their own report
+    pub fn max_depth(mut self, levels: usize) -> UsageOptions {
+        self.max_depth = levels;
+        self
+    }
+
+    /// Set the number of children downloaded per location at the deepest level of the report, the
+    /// others are estimated from them
+    pub fn sample(mut self, children: usize) -> UsageOptions {
+        self.sample = children.max(1);
+        self
+    }
+
+    /// Set the number of largest locations listed
+    pub fn top(mut self, locations: usize) -> UsageOptions {
+        self.top = locations;
+        self
+    }
+
+    /// Set the number of reads sent at the same time, over all the locations being analyzed
+    pub fn concurrency(mut self, reads: usize) -> UsageOptions {
+        self.concurrency = reads.max(1);
+        self
+    }
+}
+
+
+/// Size of a location
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PathUsage {
+    path: String,
+    bytes: u64,
+    children: u64,
+    depth: usize,
+    estimated: bool,
+}
+
+impl PathUsage {
+
+    /// Get the location
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Get the size of the value as JSON
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Get the number of children
+    pub fn children(&self) -> u64 {
+        self.children
+    }
+
+    /// Get the number of nested levels below the location
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Returns true if the size and depth are extrapolated from a sample of the children
+    pub fn estimated(&self) -> bool {
+        self.estimated
+    }
+}
+
+
+/// Sizes of a location and of the locations below it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct UsageReport {
+    root: PathUsage,
+    largest: Vec<PathUsage>,
+    paths: Vec<PathUsage>,
+}
+
+impl UsageReport {
+
+    fn new(root: PathUsage, mut paths: Vec<PathUsage>, top: usize) -> UsageReport {
+        paths.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let mut largest = paths.clone();
+        largest.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.path.cmp(&b.path)));
+        largest.truncate(top);
+
+        UsageReport {
+            root,
+            largest,
+            paths,
+        }
+    }
+
+    /// Get the size of the analyzed location
+    pub fn root(&self) -> &PathUsage {
+        &self.root
+    }
+
+    /// Get the largest locations below the analyzed one, the largest first
+    pub fn largest(&self) -> &[PathUsage] {
+        &self.largest
+    }
+
+    /// Get every location analyzed below the analyzed one, sorted by path
+    pub fn paths(&self) -> &[PathUsage] {
+        &self.paths
+    }
+
+    /// Export the report as JSON
+    ///
+    /// # Errors
+    /// Returns an error if the report can not be serialized
+    pub fn to_json(&self) -> Result<String, FirebaseError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+
+/// Measures how much data the locations below a reference hold, like `du` does for a disk
+///
+/// Locations are listed with shallow reads, and every child is listed down to the deepest level
+/// of the report. Values are only downloaded at that level, where locations with more children
+/// than the sample of the options only have a sample of their children measured and the size of
+/// the others is estimated from it. Values are measured as they arrive instead of being held in
+/// memory.
+#[derive(Clone)]
+pub struct UsageAnalyzer<'a> {
+    reference: RealtimeReference<'a>,
+    options: UsageOptions,
+    /// Reads that may be sent, shared by the clones of the analyzer
+    reads: Arc<Semaphore>,
+}
+
+impl<'a> UsageAnalyzer<'a> {
+
+    /// Create an analyzer of the given reference
+    pub fn new(reference: RealtimeReference<'a>, options: UsageOptions) -> UsageAnalyzer<'a> {
+        UsageAnalyzer {
+            reference,
+            reads: Arc::new(Semaphore::new(options.concurrency)),
+            options,
+        }
+    }
+
+    /// Crawl the reference and report the size of every location analyzed
+    ///
+    /// # Errors
+    /// Returns an error if a read fails
+    pub async fn analyze(&self) -> Result<UsageReport, FirebaseError> {
+        let mut paths = Vec::new();
+        let root = self.visit(normalize(&self.reference.path), 0, &mut paths).await?;
+
+        Ok(UsageReport::new(root, paths, self.options.top))
+    }
+
+    /// Measure a location, adding the locations measured below it to `paths`
+    fn visit<'b>(&'b self, path: String, level: usize, paths: &'b mut Vec<PathUsage>) -> BoxFuture<'b, Result<PathUsage, FirebaseError>> {
+        async move {
+            let keys = match self.read(&path).await? {
+                Value::Object(children) => children.keys().cloned().collect::<Vec<_>>(),
+                Value::Null => return Ok(usage(path, 0, 0, 0, false)),
+                value => return Ok(usage(path, value.to_string().len() as u64, 0, 0, false)),
+            };
+
+            let count = keys.len() as u64;
+
+            // As deep as the report goes, the value or a sample of its children is downloaded
+            if level >= self.options.max_depth {
+                if keys.len() <= self.options.sample {
+                    let (bytes, depth) = self.measure(&path).await?;
+                    return Ok(usage(path, bytes, count, depth, false));
+                }
+
+                let parent = &path;
+                let sampled = futures_util::stream::iter(sample(&keys, self.options.sample))
+                    .map(|key| async move {
+                        let (bytes, depth) = self.measure(&format!("{}/{}", parent, key)).await?;
+                        Ok::<_, FirebaseError>((key.len(), usage(key.clone(), bytes, 0, depth, false)))
+                    })
+                    .buffered(self.options.concurrency)
+                    .collect::<Vec<_>>().await
+                    .into_iter().collect::<Result<Vec<_>, _>>()?;
+
+                let (bytes, depth) = aggregate(&sampled, count);
+                return Ok(usage(path, bytes, count, depth, true));
+            }
+
+            let mut children = Vec::with_capacity(keys.len());
+
+            // Each child gets its own list of locations, the order of the report is restored by sorting
+            let visited = futures_util::stream::iter(keys)
+                .map(|key| {
+                    let child = normalize(&format!("{}/{}", path, key));
+                    async move {
+                        let mut below = Vec::new();
+                        let usage = self.visit(child, level + 1, &mut below).await?;
+                        Ok::<_, FirebaseError>((key.len(), usage, below))
+                    }
+                })
+                .buffered(self.options.concurrency)
+                .collect::<Vec<_>>().await;
+
+            for result in visited {
+                let (key, child, below) = result?;
+                paths.extend(below);
+                paths.push(child.clone());
+                children.push((key, child));
+            }
+
+            let (bytes, depth) = aggregate(&children, count);
+            let estimated = children.iter().any(|(_, child)| child.estimated);
+            Ok(usage(path, bytes, count, depth, estimated))
+        }.boxed()
+    }
+
+    /// List the children of a location
+    async fn read(&self, path: &str) -> Result<Value, FirebaseError> {
+        let _read = self.permit().await?;
+        let client = self.reference.client;
+        let response = client.connector.request(Method::Get, path, Some("?shallow=true"), None, client.api_key.as_deref()).await?;
+
+        if response.status().code() != 200 {
+            return Err(FirebaseError::new(format!("{} {}", response.status().code(), response.status().message())));
+        }
+
+        Ok(serde_json::from_str(response.body())?)
+    }
+
+    /// Download the value of a location as it arrives, returning its size and depth
+    async fn measure(&self, path: &str) -> Result<(u64, usize), FirebaseError> {
+        let _read = self.permit().await?;
+        let mut body = self.reference.client.reference(path).get_stream().await?;
+        let mut measure = Measure::default();
+
+        while let Some(chunk) = body.next().await {
+            measure.feed(&chunk?);
+        }
+
+        Ok((measure.bytes, measure.depth))
+    }
+
+    /// Wait until fewer reads than the concurrency of the options are running
+    async fn permit(&self) -> Result<tokio::sync::SemaphorePermit<'_>, FirebaseError> {
+        self.reads.acquire().await.map_err(|e| FirebaseError::new(format!("Usage: {}", e)))
+    }
+}
+
+
+/// Size and depth of a JSON document read in chunks, without parsing it
+#[derive(Debug, Default)]
+struct Measure {
+    bytes: u64,
+    depth: usize,
+    nesting: usize,
+    string: bool,
+    escape: bool,
+}
+
+impl Measure {
+
+    fn feed(&mut self, chunk: &[u8]) {
+        self.bytes += chunk.len() as u64;
+
+        for &byte in chunk {
+            if self.string {
+                match byte {
+                    _ if self.escape => self.escape = false,
+                    b'\\' => self.escape = true,
+                    b'"' => self.string = false,
+                    _ => {},
+                }
+                continue;
+            }
+
+            match byte {
+                b'"' => self.string = true,
+                b'{' | b'[' => {
+                    self.nesting += 1;
+                    self.depth = self.depth.max(self.nesting);
+                },
+                b'}' | b']' => self.nesting = self.nesting.saturating_sub(1),
+                _ => {},
+            }
+        }
+    }
+}
+
+
+fn usage(path: String, bytes: u64, children: u64, depth: usize, estimated: bool) -> PathUsage {
+    PathUsage {
+        path,
+        bytes,
+        children,
+        depth,
+        estimated,
+    }
+}
+
+/// Pick `size` keys spread evenly over the sorted keys
+fn sample(keys: &[String], size: usize) -> Vec<String> {
+    if keys.len() <= size {
+        return keys.to_vec();
+    }
+
+    (0..size).map(|i| keys[i * keys.len() / size].clone()).collect()
+}
+
+/// Get the size and depth of an object from some of its children, given as the length of their
+/// key and their usage, and its number of children
+fn aggregate(children: &[(usize, PathUsage)], count: u64) -> (u64, usize) {
+    let depth = children.iter().map(|(_, child)| child.depth + 1).max().unwrap_or(0);
+
+    // Each child also takes its quoted key, a colon and a comma, the object takes its braces
+    let measured: u64 = children.iter().map(|(key, child)| child.bytes + *key as u64 + 4).sum();
+    let bytes = match children.len() as u64 {
+        0 => 0,
+        sampled => measured * count / sampled + 1,
+    };
+
+    (bytes, depth)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import::depth;
+
+    fn keys(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("{:03}", i)).collect()
+    }
+
+    #[test]
+    fn test_sample() {
+        assert_eq!(sample(&keys(3), 5), keys(3));
+        assert_eq!(sample(&keys(10), 4), vec!["000", "002", "005", "007"]);
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let value = serde_json::json!({"a": {"x": 1}, "bb": "hello"});
+        let children = vec![
+            (1, usage("a".to_string(), 7, 1, 1, false)),
+            (2, usage("bb".to_string(), 7, 0, 0, false)),
+        ];
+
+        assert_eq!(aggregate(&children, 2), (value.to_string().len() as u64, depth(&value)));
+        assert_eq!(aggregate(&children, 20), (251, 2));
+        assert_eq!(aggregate(&[], 0), (0, 0));
+    }
+
+    #[test]
+    fn test_measure() {
+        let document = r#"{"a":{"tags":["x","}]\\\"{"]},"b":[[1,{"c":2}]],"d":"[[[["}"#;
+        let value: Value = serde_json::from_str(document).unwrap();
+
+        for size in 1..document.len() {
+            let mut measure = Measure::default();
+            for chunk in document.as_bytes().chunks(size) {
+                measure.feed(chunk);
+            }
+            assert_eq!((measure.bytes, measure.depth), (document.len() as u64, depth(&value)), "chunk size {}", size);
+        }
+
+        let mut measure = Measure::default();
+        measure.feed(b"42");
+        assert_eq!((measure.bytes, measure.depth), (2, 0));
+    }
+
+    #[test]
+    fn test_report_largest() {
+        let paths = vec![
+            usage("users".to_string(), 500, 2, 3, false),
+            usage("users/alice".to_string(), 300, 1, 2, false),
+            usage("config".to_string(), 50, 1, 1, false),
+        ];
+        let report = UsageReport::new(usage(String::new(), 553, 2, 4, false), paths, 2);
+
+        assert_eq!(report.largest().iter().map(|p| p.path()).collect::<Vec<_>>(), vec!["users", "users/alice"]);
+        assert_eq!(report.paths().iter().map(|p| p.path()).collect::<Vec<_>>(), vec!["config", "users", "users/alice"]);
+
+        let json: Value = serde_json::from_str(&report.to_json().unwrap()).unwrap();
+        assert_eq!(json["largest"][0], serde_json::json!({"path": "users", "bytes": 500, "children": 2, "depth": 3, "estimated": false}));
+    }
+}